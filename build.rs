@@ -9,7 +9,7 @@ fn main() {
         println!("cargo:rustc-link-lib=dylib=cuda");
         println!("cargo:rustc-link-lib=dylib=cudadevrt");
     }
-    if !env::var("CARGO_FEATURE_ERASURE").is_err() {
+    if !env::var("CARGO_FEATURE_JERASURE").is_err() {
         println!("cargo:rustc-link-lib=dylib=Jerasure");
     }
 }
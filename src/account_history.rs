@@ -0,0 +1,317 @@
+//! The `account_history` module indexes the events in the ledger by the accounts
+//! they touch, so that clients can page through an account's transactions
+//! without replaying the ledger.
+
+use accountant::VOTE_FEE;
+use chrono::prelude::*;
+use entry::Entry;
+use event::Event;
+use hash::Hash;
+use plan::{Plan, Witness};
+use signature::{PublicKey, Signature};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, RwLock};
+
+/// The most items returned for a single request, so that the response fits in
+/// the thin client's receive buffer
+pub const MAX_HISTORY_ITEMS: usize = 8;
+
+/// An event that touched an account.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct HistoryItem {
+    /// The Entry the event was recorded in
+    pub entry_id: Hash,
+    /// The event's signature
+    pub sig: Signature,
+    /// Tokens the event moved into the account, or out of it if negative
+    pub change: i64,
+    /// True if the transaction's plan was waiting on a witness when it was
+    /// processed. Its payouts aren't in `change`; they're in the item of the
+    /// witness that completes the plan.
+    pub pending: bool,
+}
+
+/// The plans waiting on a witness, reduced the same way the accountant reduces
+/// them, so that the payouts can be recorded when a witness completes one.
+#[derive(Default)]
+struct PendingPlans {
+    plans: HashMap<Signature, Plan>,
+    last_time: Option<DateTime<Utc>>,
+    time_sources: HashSet<PublicKey>,
+}
+
+impl PendingPlans {
+    /// Apply `witness` to the plan of the transaction signed by `tx_sig`, and
+    /// return its payouts if that completes it.
+    fn apply_witness(&mut self, tx_sig: Signature, witness: &Witness) -> Vec<(PublicKey, i64)> {
+        let payments = match self.plans.get_mut(&tx_sig) {
+            Some(plan) => {
+                plan.apply_witness(witness);
+                plan.final_payments()
+            }
+            None => return vec![],
+        };
+        match payments {
+            Some(payments) => {
+                self.plans.remove(&tx_sig);
+                payments.into_iter().map(|x| (x.to, x.tokens)).collect()
+            }
+            None => vec![],
+        }
+    }
+
+    /// Return the balance changes `event` makes to each account it touches,
+    /// including the fee it pays `leader`, and whether it leaves a plan waiting
+    /// on a witness.
+    fn changes(
+        &mut self,
+        event: &Event,
+        leader: Option<PublicKey>,
+    ) -> (Vec<(PublicKey, i64)>, bool) {
+        let mut changes = vec![];
+        let mut pending = false;
+        match *event {
+            Event::Transaction(ref tr) => {
+                changes.push((tr.from, -tr.data.tokens));
+                let mut plan = tr.data.plan.clone();
+                if let Some(dt) = self.last_time {
+                    plan.apply_witness(&Witness::Timestamp(dt));
+                }
+                match plan.final_payments() {
+                    Some(payments) => {
+                        changes.extend(payments.into_iter().map(|x| (x.to, x.tokens)));
+                    }
+                    None => {
+                        changes.extend(plan.payees().into_iter().map(|to| (to, 0)));
+                        self.plans.insert(tr.sig, plan);
+                        pending = true;
+                    }
+                }
+            }
+            Event::Signature { from, tx_sig, .. } => {
+                changes.push((from, 0));
+                changes.extend(self.apply_witness(tx_sig, &Witness::Signature(from)));
+            }
+            Event::Preimage {
+                from,
+                tx_sig,
+                ref preimage,
+                ..
+            } => {
+                changes.push((from, 0));
+                let witness = Witness::Preimage(preimage.clone());
+                changes.extend(self.apply_witness(tx_sig, &witness));
+            }
+            Event::Timestamp { from, dt, .. } => {
+                changes.push((from, 0));
+
+                // Like the accountant, trust the first timestamp's source.
+                if self.last_time.is_none() {
+                    self.time_sources.insert(from);
+                }
+                if self.time_sources.contains(&from) {
+                    if self.last_time.map_or(true, |last_time| dt > last_time) {
+                        self.last_time = Some(dt);
+                    }
+                    let witness = Witness::Timestamp(self.last_time.unwrap());
+                    let sigs: Vec<Signature> = self.plans.keys().cloned().collect();
+                    for sig in sigs {
+                        changes.extend(self.apply_witness(sig, &witness));
+                    }
+                }
+            }
+            Event::Vote { from, .. } => changes.push((from, -VOTE_FEE)),
+        }
+        let fee = match *event {
+            Event::Transaction(ref tr) => tr.data.fee,
+            Event::Vote { .. } => VOTE_FEE,
+            _ => 0,
+        };
+        if let Some(leader) = leader {
+            if fee > 0 {
+                changes.push((leader, fee));
+            }
+        }
+
+        // One item per account, even if the event pays an account more than once.
+        let mut merged: Vec<(PublicKey, i64)> = vec![];
+        for (key, change) in changes {
+            match merged.iter().position(|x| x.0 == key) {
+                Some(i) => merged[i].1 += change,
+                None => merged.push((key, change)),
+            }
+        }
+        (merged, pending)
+    }
+}
+
+#[derive(Default)]
+pub struct AccountHistory {
+    /// Each account's items, oldest first
+    items: RwLock<HashMap<PublicKey, Vec<HistoryItem>>>,
+    pending: Mutex<PendingPlans>,
+}
+
+impl AccountHistory {
+    /// Index the events of `entry`, whose fees were paid to `leader` if anyone.
+    /// Entries must be recorded in ledger order, from the genesis entries on, for
+    /// the payouts of pending plans to be right.
+    pub fn record_entry(&self, entry: &Entry, leader: Option<PublicKey>) {
+        if entry.events.is_empty() {
+            return;
+        }
+        let mut pending = self.pending
+            .lock()
+            .expect("'pending' lock in pub fn record_entry");
+        let mut items = self.items
+            .write()
+            .expect("'items' write lock in pub fn record_entry");
+
+        // The accountant processes an entry's transactions before its other events.
+        let (trs, rest): (Vec<&Event>, Vec<&Event>) = entry
+            .events
+            .iter()
+            .partition(|event| match **event {
+                Event::Transaction(_) => true,
+                _ => false,
+            });
+        for event in trs.into_iter().chain(rest) {
+            let (changes, is_pending) = pending.changes(event, leader);
+            for (key, change) in changes {
+                let item = HistoryItem {
+                    entry_id: entry.id,
+                    sig: event.get_sig(),
+                    change,
+                    pending: is_pending,
+                };
+                items.entry(key).or_insert_with(Vec::new).push(item);
+            }
+        }
+    }
+
+    /// Return up to `limit` of the items for `key`, newest first. If `before`
+    /// is given, start with the item recorded before the one with that
+    /// signature, so that the last signature of one page fetches the next.
+    pub fn get_history(
+        &self,
+        key: &PublicKey,
+        before: Option<Signature>,
+        limit: usize,
+    ) -> Vec<HistoryItem> {
+        let items = self.items
+            .read()
+            .expect("'items' read lock in pub fn get_history");
+        let items = match items.get(key) {
+            Some(items) => items,
+            None => return vec![],
+        };
+        let end = match before {
+            Some(sig) => match items.iter().rposition(|x| x.sig == sig) {
+                Some(i) => i,
+                None => return vec![],
+            },
+            None => items.len(),
+        };
+        items[..end].iter().rev().take(limit).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use account_history::AccountHistory;
+    use accountant::VOTE_FEE;
+    use chrono::prelude::*;
+    use entry::next_entry;
+    use event::Event;
+    use hash::Hash;
+    use signature::{KeyPair, KeyPairUtil};
+    use transaction::Transaction;
+
+    #[test]
+    fn test_account_history() {
+        let history = AccountHistory::default();
+        let alice = KeyPair::new();
+        let bob = KeyPair::new().pubkey();
+        let zero = Hash::default();
+
+        let tr0 = Transaction::new(&alice, bob, 1, zero);
+        let tr1 = Transaction::new_on_date(&alice, bob, Utc::now(), 2, zero);
+        let entry0 = next_entry(&zero, 1, vec![Event::Transaction(tr0.clone())]);
+        let entry1 = next_entry(&entry0.id, 1, vec![Event::Transaction(tr1.clone())]);
+        history.record_entry(&entry0, None);
+        history.record_entry(&entry1, None);
+
+        // Newest first, with what each transaction moved.
+        let items = history.get_history(&alice.pubkey(), None, 10);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].sig, tr1.sig);
+        assert_eq!(items[0].entry_id, entry1.id);
+        assert_eq!(items[0].change, -2);
+        assert!(items[0].pending);
+        assert_eq!(items[1].sig, tr0.sig);
+        assert_eq!(items[1].change, -1);
+        assert!(!items[1].pending);
+
+        // The postdated payment hasn't paid out yet.
+        let items = history.get_history(&bob, None, 10);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].change, 0);
+        assert_eq!(items[1].change, 1);
+
+        // The timestamp that completes it pays bob.
+        let ts = Event::new_timestamp(&alice, Utc::now());
+        let entry2 = next_entry(&entry1.id, 1, vec![ts.clone()]);
+        history.record_entry(&entry2, None);
+        let items = history.get_history(&bob, None, 10);
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].sig, ts.get_sig());
+        assert_eq!(items[0].entry_id, entry2.id);
+        assert_eq!(items[0].change, 2);
+        assert!(!items[0].pending);
+        let items = history.get_history(&alice.pubkey(), None, 10);
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].change, 0);
+
+        // Page through with `before`.
+        let page = history.get_history(&alice.pubkey(), None, 1);
+        assert_eq!(page[0].sig, ts.get_sig());
+        let page = history.get_history(&alice.pubkey(), Some(page[0].sig), 1);
+        assert_eq!(page[0].sig, tr1.sig);
+        let page = history.get_history(&alice.pubkey(), Some(page[0].sig), 1);
+        assert_eq!(page[0].sig, tr0.sig);
+        let page = history.get_history(&alice.pubkey(), Some(page[0].sig), 1);
+        assert!(page.is_empty());
+
+        assert!(history.get_history(&KeyPair::new().pubkey(), None, 10).is_empty());
+    }
+
+    #[test]
+    fn test_account_history_fees() {
+        let history = AccountHistory::default();
+        let alice = KeyPair::new();
+        let bob = KeyPair::new().pubkey();
+        let leader = KeyPair::new().pubkey();
+        let zero = Hash::default();
+
+        let tr = Transaction::new_taxed(&alice, bob, 3, 1, zero);
+        let vote = Event::new_vote(&alice, zero, zero);
+        let entry = next_entry(&zero, 1, vec![Event::Transaction(tr.clone()), vote.clone()]);
+        history.record_entry(&entry, Some(leader));
+
+        // The leader is paid the fees of both.
+        let items = history.get_history(&leader, None, 10);
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].sig, vote.get_sig());
+        assert_eq!(items[0].change, VOTE_FEE);
+        assert_eq!(items[1].sig, tr.sig);
+        assert_eq!(items[1].change, 1);
+        assert_eq!(history.get_history(&bob, None, 10)[0].change, 2);
+        assert_eq!(history.get_history(&alice.pubkey(), None, 10)[0].change, -VOTE_FEE);
+
+        // Fees paid to nobody aren't recorded.
+        let tr = Transaction::new_taxed(&alice, bob, 3, 1, entry.id);
+        let entry = next_entry(&entry.id, 1, vec![Event::Transaction(tr)]);
+        history.record_entry(&entry, None);
+        assert_eq!(history.get_history(&leader, None, 10).len(), 2);
+    }
+}
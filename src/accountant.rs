@@ -8,7 +8,8 @@ extern crate libc;
 use chrono::prelude::*;
 use entry::Entry;
 use event::Event;
-use hash::Hash;
+use bincode::serialize;
+use hash::{hash, Hash};
 use mint::Mint;
 use plan::{Payment, Plan, Witness};
 use rayon::prelude::*;
@@ -22,11 +23,28 @@ use transaction::Transaction;
 
 pub const MAX_ENTRY_IDS: usize = 1024 * 4;
 
-#[derive(Debug, PartialEq, Eq)]
+/// What a validator pays the leader for each vote it gets recorded
+pub const VOTE_FEE: i64 = 1;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub enum AccountingError {
     AccountNotFound,
     InsufficientFunds,
     InvalidTransferSignature,
+    EntryIdNotFound,
+}
+
+/// What the Accountant knows about the transaction with a given signature.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum SignatureStatus {
+    /// Never seen, or its `last_id` has expired.
+    NotFound,
+    /// Debited, but its plan is waiting on a Witness.
+    Pending,
+    /// Debited and its plan has paid out.
+    Processed,
+    /// Rejected by the Accountant. The same transaction may be resubmitted.
+    Rejected(AccountingError),
 }
 
 pub type Result<T> = result::Result<T, AccountingError>;
@@ -53,12 +71,44 @@ fn apply_payment(balances: &RwLock<HashMap<PublicKey, AtomicIsize>>, payment: &P
     }
 }
 
+/// A serializable copy of the Accountant's state, taken after the Entry `last_id`
+/// was processed.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub last_id: Hash,
+    balances: HashMap<PublicKey, i64>,
+    pending: HashMap<Signature, Plan>,
+    last_ids: Vec<(Hash, HashSet<Signature>)>,
+    time_sources: HashSet<PublicKey>,
+    last_time: DateTime<Utc>,
+    collected_fees: i64,
+    votes: HashMap<PublicKey, Hash>,
+    finalized_id: Option<Hash>,
+    supply: i64,
+}
+
 pub struct Accountant {
     balances: RwLock<HashMap<PublicKey, AtomicIsize>>,
     pending: RwLock<HashMap<Signature, Plan>>,
-    last_ids: RwLock<VecDeque<(Hash, RwLock<HashSet<Signature>>)>>,
+    /// The recent Entry IDs, with the signatures of the transactions that
+    /// used each one and the ones that were rejected.
+    last_ids: RwLock<
+        VecDeque<(
+            Hash,
+            RwLock<HashSet<Signature>>,
+            RwLock<HashMap<Signature, AccountingError>>,
+        )>,
+    >,
     time_sources: RwLock<HashSet<PublicKey>>,
     last_time: RwLock<DateTime<Utc>>,
+    collected_fees: AtomicIsize,
+    leader: RwLock<Option<PublicKey>>,
+    /// The latest Entry ID each validator voted for
+    votes: RwLock<HashMap<PublicKey, Hash>>,
+    /// The latest Entry ID that a supermajority of stake voted for
+    finalized_id: RwLock<Option<Hash>>,
+    /// All the tokens there are, which is the total stake
+    supply: i64,
 }
 
 impl Accountant {
@@ -72,6 +122,11 @@ impl Accountant {
             last_ids: RwLock::new(VecDeque::new()),
             time_sources: RwLock::new(HashSet::new()),
             last_time: RwLock::new(Utc.timestamp(0, 0)),
+            collected_fees: AtomicIsize::new(0),
+            leader: RwLock::new(None),
+            votes: RwLock::new(HashMap::new()),
+            finalized_id: RwLock::new(None),
+            supply: deposit.tokens,
         }
     }
 
@@ -86,6 +141,135 @@ impl Accountant {
         accountant
     }
 
+    /// Create an Accountant from a ledger's genesis block: an Entry with zero
+    /// num_hashes whose id seeds the ledger, then one holding the mint's deposit.
+    /// Returns None if `entry1` holds no deposit.
+    pub fn new_from_genesis(entry0: &Entry, entry1: &Entry) -> Option<Self> {
+        let deposit = match entry1.events.first() {
+            Some(&Event::Transaction(ref tr)) => tr.data.plan.final_payments(),
+            _ => None,
+        }?;
+        let accountant = Self::new_from_deposit(deposit.first()?);
+        accountant.register_entry_id(&entry0.id);
+        accountant.register_entry_id(&entry1.id);
+        Some(accountant)
+    }
+
+    /// Create an Accountant from a Snapshot. Entries up to and including
+    /// `snapshot.last_id` should not be processed again.
+    pub fn new_from_snapshot(snapshot: Snapshot) -> Self {
+        let balances = snapshot
+            .balances
+            .into_iter()
+            .map(|(key, tokens)| (key, AtomicIsize::new(tokens as isize)))
+            .collect();
+        let last_ids = snapshot
+            .last_ids
+            .into_iter()
+            .map(|(id, sigs)| (id, RwLock::new(sigs), RwLock::new(HashMap::new())))
+            .collect();
+        Accountant {
+            balances: RwLock::new(balances),
+            pending: RwLock::new(snapshot.pending),
+            last_ids: RwLock::new(last_ids),
+            time_sources: RwLock::new(snapshot.time_sources),
+            last_time: RwLock::new(snapshot.last_time),
+            collected_fees: AtomicIsize::new(snapshot.collected_fees as isize),
+            leader: RwLock::new(None),
+            votes: RwLock::new(snapshot.votes),
+            finalized_id: RwLock::new(snapshot.finalized_id),
+            supply: snapshot.supply,
+        }
+    }
+
+    /// Return the balance of every account.
+    pub fn get_balances(&self) -> HashMap<PublicKey, i64> {
+        self.balances
+            .read()
+            .expect("'balances' read lock in get_balances")
+            .iter()
+            .map(|(key, tokens)| (*key, tokens.load(Ordering::Relaxed) as i64))
+            .collect()
+    }
+
+    /// Return a Snapshot of the Accountant's state. Events should not be
+    /// processed concurrently, or the snapshot may capture half an Entry.
+    pub fn snapshot(&self) -> Snapshot {
+        let last_ids: Vec<_> = self.last_ids
+            .read()
+            .expect("'last_ids' read lock in snapshot")
+            .iter()
+            .map(|&(id, ref sigs, _)| {
+                let sigs = sigs.read().expect("'signatures' read lock in snapshot");
+                (id, sigs.clone())
+            })
+            .collect();
+        Snapshot {
+            last_id: last_ids.last().expect("empty 'last_ids' list").0,
+            balances: self.get_balances(),
+            pending: self.pending
+                .read()
+                .expect("'pending' read lock in snapshot")
+                .clone(),
+            last_ids,
+            time_sources: self.time_sources
+                .read()
+                .expect("'time_sources' read lock in snapshot")
+                .clone(),
+            last_time: *self.last_time
+                .read()
+                .expect("'last_time' read lock in snapshot"),
+            collected_fees: self.collected_fees.load(Ordering::Relaxed) as i64,
+            votes: self.votes
+                .read()
+                .expect("'votes' read lock in snapshot")
+                .clone(),
+            finalized_id: self.finalized_id(),
+            supply: self.supply,
+        }
+    }
+
+    /// Return a hash of the balances and pending plans. Two accountants that
+    /// processed the same entries return the same hash, regardless of the order
+    /// their accounts were created in.
+    pub fn state_hash(&self) -> Hash {
+        let mut balances: Vec<_> = self.get_balances().into_iter().collect();
+        balances.sort();
+        let mut pending: Vec<_> = self.pending
+            .read()
+            .expect("'pending' read lock in state_hash")
+            .iter()
+            .map(|(sig, plan)| (*sig, plan.clone()))
+            .collect();
+        pending.sort_by_key(|x| x.0);
+        hash(&serialize(&(balances, pending)).expect("serialize state in state_hash"))
+    }
+
+    /// Set the identity that transaction fees are paid to.
+    pub fn set_leader(&self, leader: PublicKey) {
+        *self.leader.write().expect("'leader' write lock in set_leader") = Some(leader);
+    }
+
+    /// Return the identity that transaction fees are paid to, if any.
+    pub fn leader(&self) -> Option<PublicKey> {
+        *self.leader.read().expect("'leader' read lock in leader")
+    }
+
+    /// Credit the fees collected since the last call to the leader. This is
+    /// called once per Entry so that replaying the ledger yields the same
+    /// payouts. If no leader is set, the fees keep accumulating.
+    pub fn pay_collected_fees(&self) {
+        let leader = *self.leader
+            .read()
+            .expect("'leader' read lock in pay_collected_fees");
+        if let Some(to) = leader {
+            let tokens = self.collected_fees.swap(0, Ordering::Relaxed) as i64;
+            if tokens > 0 {
+                apply_payment(&self.balances, &Payment { tokens, to });
+            }
+        }
+    }
+
     /// Return the last entry ID registered
     pub fn last_id(&self) -> Hash {
         let last_ids = self.last_ids.read().expect("'last_ids' read lock");
@@ -141,6 +325,62 @@ impl Accountant {
         false
     }
 
+    /// Remember that the transaction was rejected, so clients can ask why. It's
+    /// forgotten along with its `last_id`.
+    fn record_rejection(&self, tr: &Transaction, err: AccountingError) -> AccountingError {
+        if let Some(entry) = self.last_ids
+            .read()
+            .expect("'last_ids' read lock in record_rejection")
+            .iter()
+            .rev()
+            .find(|x| x.0 == tr.data.last_id)
+        {
+            entry
+                .2
+                .write()
+                .expect("'rejections' write lock in record_rejection")
+                .insert(tr.sig, err.clone());
+        }
+        err
+    }
+
+    /// Return the status of the transaction signed by `sig`.
+    pub fn get_signature_status(&self, sig: &Signature) -> SignatureStatus {
+        if self.pending
+            .read()
+            .expect("'pending' read lock in get_signature_status")
+            .contains_key(sig)
+        {
+            return SignatureStatus::Pending;
+        }
+        let last_ids = self.last_ids
+            .read()
+            .expect("'last_ids' read lock in get_signature_status");
+
+        // A transaction rejected once may have been accepted later.
+        for entry in last_ids.iter() {
+            if entry
+                .1
+                .read()
+                .expect("'signatures' read lock in get_signature_status")
+                .contains(sig)
+            {
+                return SignatureStatus::Processed;
+            }
+        }
+        for entry in last_ids.iter() {
+            if let Some(err) = entry
+                .2
+                .read()
+                .expect("'rejections' read lock in get_signature_status")
+                .get(sig)
+            {
+                return SignatureStatus::Rejected(err.clone());
+            }
+        }
+        SignatureStatus::NotFound
+    }
+
     /// Tell the accountant which Entry IDs exist on the ledger. This function
     /// assumes subsequent calls correspond to later entries, and will boot
     /// the oldest ones once its internal cache is full. Once boot, the
@@ -152,7 +392,11 @@ impl Accountant {
         if last_ids.len() >= MAX_ENTRY_IDS {
             last_ids.pop_front();
         }
-        last_ids.push_back((*last_id, RwLock::new(HashSet::new())));
+        last_ids.push_back((
+            *last_id,
+            RwLock::new(HashSet::new()),
+            RwLock::new(HashMap::new()),
+        ));
     }
 
     /// Deduct tokens from the 'from' address the account has sufficient
@@ -164,7 +408,7 @@ impl Accountant {
         let option = bals.get(&tr.from);
 
         if option.is_none() {
-            return Err(AccountingError::AccountNotFound);
+            return Err(self.record_rejection(tr, AccountingError::AccountNotFound));
         }
 
         if !self.reserve_signature_with_last_id(&tr.sig, &tr.data.last_id) {
@@ -177,7 +421,7 @@ impl Accountant {
 
             if current < tr.data.tokens {
                 self.forget_signature_with_last_id(&tr.sig, &tr.data.last_id);
-                return Err(AccountingError::InsufficientFunds);
+                return Err(self.record_rejection(tr, AccountingError::InsufficientFunds));
             }
 
             let result = bal.compare_exchange(
@@ -188,7 +432,11 @@ impl Accountant {
             );
 
             match result {
-                Ok(_) => return Ok(()),
+                Ok(_) => {
+                    self.collected_fees
+                        .fetch_add(tr.data.fee as isize, Ordering::Relaxed);
+                    return Ok(());
+                }
                 Err(_) => continue,
             };
         }
@@ -200,8 +448,10 @@ impl Accountant {
             .read()
             .expect("timestamp creation in process_verified_transaction_credits")));
 
-        if let Some(ref payment) = plan.final_payment() {
-            apply_payment(&self.balances, payment);
+        if let Some(payments) = plan.final_payments() {
+            for payment in &payments {
+                apply_payment(&self.balances, payment);
+            }
         } else {
             let mut pending = self.pending
                 .write()
@@ -262,26 +512,44 @@ impl Accountant {
         results
     }
 
-    pub fn process_verified_entries(&self, entries: Vec<Entry>) -> Result<()> {
+    /// Process entries in ledger order, paying the fees of each to the leader.
+    /// Replaying a ledger this way yields the balances of the node that wrote it.
+    pub fn process_verified_entries<I>(&self, entries: I) -> Result<()>
+    where
+        I: IntoIterator<Item = Entry>,
+    {
         for entry in entries {
             self.register_entry_id(&entry.id);
             for result in self.process_verified_events(entry.events) {
                 result?;
             }
+            self.pay_collected_fees();
         }
         Ok(())
     }
 
     /// Process a Witness Signature that has already been verified.
     fn process_verified_sig(&self, from: PublicKey, tx_sig: Signature) -> Result<()> {
+        self.apply_witness_to_pending(tx_sig, &Witness::Signature(from))
+    }
+
+    /// Process a Witness Preimage that has already been verified.
+    fn process_verified_preimage(&self, tx_sig: Signature, preimage: &[u8]) -> Result<()> {
+        self.apply_witness_to_pending(tx_sig, &Witness::Preimage(preimage.to_vec()))
+    }
+
+    /// Apply a Witness to the pending plan of the transaction signed by `tx_sig`.
+    fn apply_witness_to_pending(&self, tx_sig: Signature, witness: &Witness) -> Result<()> {
         if let Occupied(mut e) = self.pending
             .write()
-            .expect("write() in process_verified_sig")
+            .expect("write() in apply_witness_to_pending")
             .entry(tx_sig)
         {
-            e.get_mut().apply_witness(&Witness::Signature(from));
-            if let Some(ref payment) = e.get().final_payment() {
-                apply_payment(&self.balances, payment);
+            e.get_mut().apply_witness(witness);
+            if let Some(payments) = e.get().final_payments() {
+                for payment in &payments {
+                    apply_payment(&self.balances, payment);
+                }
                 e.remove_entry();
             }
         };
@@ -328,8 +596,10 @@ impl Accountant {
             plan.apply_witness(&Witness::Timestamp(*self.last_time
                 .read()
                 .expect("'last_time' read lock when creating timestamp")));
-            if let Some(ref payment) = plan.final_payment() {
-                apply_payment(&self.balances, payment);
+            if let Some(payments) = plan.final_payments() {
+                for payment in &payments {
+                    apply_payment(&self.balances, payment);
+                }
                 completed.push(key.clone());
             }
         }
@@ -341,12 +611,109 @@ impl Accountant {
         Ok(())
     }
 
+    /// Process a Vote that has already been verified. The voter pays `VOTE_FEE`
+    /// for it, like a transaction that names `entry_id` as its `last_id`. A vote
+    /// for an Entry is also a vote for every Entry before it, so only a validator's
+    /// latest vote is kept. Stake is the voter's balance at the time the vote is
+    /// processed, out of the whole supply.
+    fn process_verified_vote(&self, from: PublicKey, entry_id: Hash, sig: Signature) -> Result<()> {
+        // Take the 'balances' lock before the 'last_ids' lock, like debits do.
+        let bals = self.balances
+            .read()
+            .expect("'balances' read lock in process_verified_vote");
+        let bal = bals.get(&from).ok_or(AccountingError::AccountNotFound)?;
+        let last_ids = self.last_ids
+            .read()
+            .expect("'last_ids' read lock in process_verified_vote");
+        let position = |id: &Hash| last_ids.iter().rposition(|x| x.0 == *id);
+        let height = position(&entry_id).ok_or(AccountingError::EntryIdNotFound)?;
+        if !Self::reserve_signature(&last_ids[height].1, &sig) {
+            return Err(AccountingError::InvalidTransferSignature);
+        }
+        loop {
+            let current = bal.load(Ordering::Relaxed) as i64;
+            if current < VOTE_FEE {
+                Self::forget_signature(&last_ids[height].1, &sig);
+                return Err(AccountingError::InsufficientFunds);
+            }
+            let result = bal.compare_exchange(
+                current as isize,
+                (current - VOTE_FEE) as isize,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+            if result.is_ok() {
+                break;
+            }
+        }
+        self.collected_fees
+            .fetch_add(VOTE_FEE as isize, Ordering::Relaxed);
+
+        let mut votes = self.votes
+            .write()
+            .expect("'votes' write lock in process_verified_vote");
+        if let Some(prev_id) = votes.get(&from) {
+            if position(prev_id) >= Some(height) {
+                return Ok(());
+            }
+        }
+        votes.insert(from, entry_id);
+
+        // Walk back from the latest votes until a supermajority of stake is
+        // behind the entry; that entry and everything before it is final.
+        let mut voted: Vec<_> = votes
+            .iter()
+            .filter_map(|(key, id)| {
+                let stake = bals.get(key)
+                    .map_or(0, |x| x.load(Ordering::Relaxed) as i64)
+                    .max(0);
+                position(id).map(|height| (height, stake))
+            })
+            .collect();
+        voted.sort_by(|a, b| b.cmp(a));
+        let mut stake = 0;
+        for (height, voted_stake) in voted {
+            stake += voted_stake;
+            if 3 * stake > 2 * self.supply {
+                let mut finalized_id = self.finalized_id
+                    .write()
+                    .expect("'finalized_id' write lock in process_verified_vote");
+                // An ID too old to be in 'last_ids' is behind any recent one.
+                let is_newer = finalized_id.map_or(true, |id| position(&id) < Some(height));
+                if is_newer {
+                    *finalized_id = Some(last_ids[height].0);
+                }
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the latest Entry ID that a supermajority of stake voted for,
+    /// directly or by voting for a later Entry.
+    pub fn finalized_id(&self) -> Option<Hash> {
+        *self.finalized_id
+            .read()
+            .expect("'finalized_id' read lock in finalized_id")
+    }
+
     /// Process an Transaction or Witness that has already been verified.
     pub fn process_verified_event(&self, event: Event) -> Result<Event> {
         match event {
             Event::Transaction(ref tr) => self.process_verified_transaction(tr),
             Event::Signature { from, tx_sig, .. } => self.process_verified_sig(from, tx_sig),
             Event::Timestamp { from, dt, .. } => self.process_verified_timestamp(from, dt),
+            Event::Preimage {
+                tx_sig,
+                ref preimage,
+                ..
+            } => self.process_verified_preimage(tx_sig, preimage),
+            Event::Vote {
+                from,
+                entry_id,
+                sig,
+                ..
+            } => self.process_verified_vote(from, entry_id, sig),
         }?;
         Ok(event)
     }
@@ -392,8 +759,8 @@ impl Accountant {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bincode::serialize;
-    use hash::hash;
+    use bincode::deserialize;
+    use entry::next_entry;
     use signature::KeyPairUtil;
 
     #[test]
@@ -536,6 +903,70 @@ mod tests {
         assert_ne!(accountant.get_balance(&alice.pubkey()), Some(2));
     }
 
+    #[test]
+    fn test_multisig_transfer() {
+        let alice = Mint::new(1);
+        let accountant = Accountant::new(&alice);
+        let bob_pubkey = KeyPair::new().pubkey();
+        let signers: Vec<_> = (0..3).map(|_| KeyPair::new().pubkey()).collect();
+        let plan = Plan::new_multisig_payment(2, signers.clone(), 1, bob_pubkey);
+        let tr = Transaction::new_with_plan(&alice.keypair(), plan, 1, 0, alice.last_id());
+        accountant.process_verified_transaction(&tr).unwrap();
+        assert_eq!(accountant.get_balance(&alice.pubkey()), Some(0));
+
+        // One approval isn't enough, no matter how many times it's sent.
+        accountant.process_verified_sig(signers[1], tr.sig).unwrap();
+        accountant.process_verified_sig(signers[1], tr.sig).unwrap();
+        assert_eq!(accountant.get_balance(&bob_pubkey), None);
+
+        accountant.process_verified_sig(signers[0], tr.sig).unwrap();
+        assert_eq!(accountant.get_balance(&bob_pubkey), Some(1));
+        assert!(accountant.pending.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_htlc_transfer() {
+        let alice = Mint::new(2);
+        let accountant = Accountant::new(&alice);
+        let bob_keypair = KeyPair::new();
+        let bob_pubkey = bob_keypair.pubkey();
+        let dt = Utc::now();
+        let lock = hash(b"secret");
+        let plan = Plan::new_htlc_payment(lock, dt, alice.pubkey(), 1, bob_pubkey);
+        let tr = Transaction::new_with_plan(&alice.keypair(), plan, 1, 0, alice.last_id());
+        accountant.process_verified_transaction(&tr).unwrap();
+        assert_eq!(accountant.get_balance(&alice.pubkey()), Some(1));
+
+        // A wrong guess doesn't release the funds.
+        let event = Event::new_preimage(&bob_keypair, tr.sig, b"guess".to_vec());
+        accountant.process_verified_event(event).unwrap();
+        assert_eq!(accountant.get_balance(&bob_pubkey), None);
+
+        let event = Event::new_preimage(&bob_keypair, tr.sig, b"secret".to_vec());
+        accountant.process_verified_event(event).unwrap();
+        assert_eq!(accountant.get_balance(&bob_pubkey), Some(1));
+        assert_eq!(accountant.get_balance(&alice.pubkey()), Some(1));
+    }
+
+    #[test]
+    fn test_htlc_refund() {
+        let alice = Mint::new(1);
+        let accountant = Accountant::new(&alice);
+        let bob_pubkey = KeyPair::new().pubkey();
+        let dt = Utc::now();
+        let lock = hash(b"secret");
+        let plan = Plan::new_htlc_payment(lock, dt, alice.pubkey(), 1, bob_pubkey);
+        let tr = Transaction::new_with_plan(&alice.keypair(), plan, 1, 0, alice.last_id());
+        accountant.process_verified_transaction(&tr).unwrap();
+        assert_eq!(accountant.get_balance(&alice.pubkey()), Some(0));
+
+        accountant
+            .process_verified_timestamp(alice.pubkey(), dt)
+            .unwrap();
+        assert_eq!(accountant.get_balance(&alice.pubkey()), Some(1));
+        assert_eq!(accountant.get_balance(&bob_pubkey), None);
+    }
+
     #[test]
     fn test_duplicate_event_signature() {
         let alice = Mint::new(1);
@@ -568,6 +999,324 @@ mod tests {
         assert!(!accountant.reserve_signature_with_last_id(&sig, &alice.last_id()));
     }
 
+    #[test]
+    fn test_transfer_with_fee() {
+        let mint = Mint::new(10_000);
+        let accountant = Accountant::new(&mint);
+        let leader_pubkey = KeyPair::new().pubkey();
+        let bob_pubkey = KeyPair::new().pubkey();
+        accountant.set_leader(leader_pubkey);
+        let tr = Transaction::new_taxed(&mint.keypair(), bob_pubkey, 1_000, 10, mint.last_id());
+        accountant.process_verified_transaction(&tr).unwrap();
+        assert_eq!(accountant.get_balance(&mint.pubkey()), Some(9_000));
+        assert_eq!(accountant.get_balance(&bob_pubkey), Some(990));
+
+        // Fees are held until the entry is complete.
+        assert_eq!(accountant.get_balance(&leader_pubkey), None);
+        accountant.pay_collected_fees();
+        assert_eq!(accountant.get_balance(&leader_pubkey), Some(10));
+
+        // Paying twice doesn't pay the same fees twice.
+        accountant.pay_collected_fees();
+        assert_eq!(accountant.get_balance(&leader_pubkey), Some(10));
+    }
+
+    #[test]
+    fn test_insufficient_funds_for_fee() {
+        let mint = Mint::new(1_000);
+        let accountant = Accountant::new(&mint);
+        accountant.set_leader(KeyPair::new().pubkey());
+        let bob_pubkey = KeyPair::new().pubkey();
+        let tr = Transaction::new_taxed(&mint.keypair(), bob_pubkey, 1_001, 1, mint.last_id());
+        assert_eq!(
+            accountant.process_verified_transaction(&tr),
+            Err(AccountingError::InsufficientFunds)
+        );
+        accountant.pay_collected_fees();
+        assert_eq!(accountant.get_balance(&mint.pubkey()), Some(1_000));
+    }
+
+    #[test]
+    fn test_replay_fees() {
+        let mint = Mint::new(10_000);
+        let leader_pubkey = KeyPair::new().pubkey();
+        let bob_pubkey = KeyPair::new().pubkey();
+        let tr0 = Transaction::new_taxed(&mint.keypair(), bob_pubkey, 100, 1, mint.last_id());
+        let tr1 = Transaction::new_taxed(&mint.keypair(), bob_pubkey, 200, 2, mint.last_id());
+        let entry0 = next_entry(&mint.last_id(), 1, vec![Event::Transaction(tr0)]);
+        let entry1 = next_entry(&entry0.id, 1, vec![Event::Transaction(tr1)]);
+        let entries = vec![entry0, entry1];
+
+        let genesis = mint.create_entries();
+        let replay = || {
+            let accountant = Accountant::new_from_genesis(&genesis[0], &genesis[1]).unwrap();
+            accountant.set_leader(leader_pubkey);
+            accountant.process_verified_entries(entries.clone()).unwrap();
+            accountant
+        };
+        let accountant0 = replay();
+        let accountant1 = replay();
+        assert_eq!(accountant0.get_balance(&leader_pubkey), Some(3));
+        assert_eq!(accountant1.get_balance(&leader_pubkey), Some(3));
+        assert_eq!(accountant0.get_balance(&bob_pubkey), Some(297));
+        assert_eq!(accountant0.get_balance(&mint.pubkey()), Some(9_700));
+    }
+
+    #[test]
+    fn test_multi_payment() {
+        let mint = Mint::new(10_000);
+        let accountant = Accountant::new(&mint);
+        let bob_pubkey = KeyPair::new().pubkey();
+        let carol_pubkey = KeyPair::new().pubkey();
+        let payments = vec![
+            Payment {
+                tokens: 1_000,
+                to: bob_pubkey,
+            },
+            Payment {
+                tokens: 2_000,
+                to: carol_pubkey,
+            },
+        ];
+        let tr = Transaction::new_multi_payment(&mint.keypair(), payments, 0, mint.last_id())
+            .unwrap();
+        accountant.process_verified_transaction(&tr).unwrap();
+        assert_eq!(accountant.get_balance(&mint.pubkey()), Some(7_000));
+        assert_eq!(accountant.get_balance(&bob_pubkey), Some(1_000));
+        assert_eq!(accountant.get_balance(&carol_pubkey), Some(2_000));
+    }
+
+    #[test]
+    fn test_multi_payment_all_or_nothing() {
+        let mint = Mint::new(2_000);
+        let accountant = Accountant::new(&mint);
+        let bob_pubkey = KeyPair::new().pubkey();
+        let carol_pubkey = KeyPair::new().pubkey();
+        let payments = vec![
+            Payment {
+                tokens: 1_000,
+                to: bob_pubkey,
+            },
+            Payment {
+                tokens: 1_001,
+                to: carol_pubkey,
+            },
+        ];
+        let tr = Transaction::new_multi_payment(&mint.keypair(), payments, 0, mint.last_id())
+            .unwrap();
+        assert_eq!(
+            accountant.process_verified_transaction(&tr),
+            Err(AccountingError::InsufficientFunds)
+        );
+        assert_eq!(accountant.get_balance(&mint.pubkey()), Some(2_000));
+        assert_eq!(accountant.get_balance(&bob_pubkey), None);
+        assert_eq!(accountant.get_balance(&carol_pubkey), None);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mint = Mint::new(3);
+        let accountant = Accountant::new(&mint);
+        let bob_pubkey = KeyPair::new().pubkey();
+        let dt = Utc::now();
+        let sig0 = accountant
+            .transfer(1, &mint.keypair(), bob_pubkey, mint.last_id())
+            .unwrap();
+        let sig1 = accountant
+            .transfer_on_date(1, &mint.keypair(), bob_pubkey, dt, mint.last_id())
+            .unwrap();
+        let entry_id = hash(&mint.last_id());
+        accountant.register_entry_id(&entry_id);
+
+        let snapshot = accountant.snapshot();
+        assert_eq!(snapshot.last_id, entry_id);
+        let snapshot = deserialize(&serialize(&snapshot).unwrap()).unwrap();
+        let accountant = Accountant::new_from_snapshot(snapshot);
+        assert_eq!(accountant.last_id(), entry_id);
+        assert_eq!(accountant.get_balance(&mint.pubkey()), Some(1));
+        assert_eq!(accountant.get_balance(&bob_pubkey), Some(1));
+
+        // Signatures already seen are still rejected.
+        let tr = Transaction::new(&mint.keypair(), bob_pubkey, 2, mint.last_id());
+        assert!(!accountant.reserve_signature_with_last_id(&sig0, &mint.last_id()));
+        assert!(accountant.reserve_signature_with_last_id(&tr.sig, &mint.last_id()));
+
+        // Pending plans survive, too.
+        accountant
+            .process_verified_timestamp(mint.pubkey(), dt)
+            .unwrap();
+        assert_eq!(accountant.get_balance(&bob_pubkey), Some(2));
+        assert!(!accountant.pending.read().unwrap().contains_key(&sig1));
+    }
+
+    #[test]
+    fn test_state_hash() {
+        let mint = Mint::new(3);
+        let bob_pubkey = KeyPair::new().pubkey();
+        let carol_pubkey = KeyPair::new().pubkey();
+        let dt = Utc::now();
+
+        let accountant0 = Accountant::new(&mint);
+        let accountant1 = Accountant::new(&mint);
+        assert_eq!(accountant0.state_hash(), accountant1.state_hash());
+
+        // Same transactions in a different order.
+        let tr0 = Transaction::new(&mint.keypair(), bob_pubkey, 1, mint.last_id());
+        let tr1 = Transaction::new(&mint.keypair(), carol_pubkey, 1, mint.last_id());
+        let tr2 = Transaction::new_on_date(&mint.keypair(), bob_pubkey, dt, 1, mint.last_id());
+        for tr in &[&tr0, &tr1, &tr2] {
+            accountant0.process_verified_transaction(tr).unwrap();
+        }
+        for tr in &[&tr2, &tr1, &tr0] {
+            accountant1.process_verified_transaction(tr).unwrap();
+        }
+        assert_eq!(accountant0.state_hash(), accountant1.state_hash());
+
+        // Completing a pending plan changes the state.
+        let state_hash = accountant0.state_hash();
+        accountant0
+            .process_verified_sig(mint.pubkey(), tr2.sig)
+            .unwrap();
+        assert_ne!(accountant0.state_hash(), state_hash);
+    }
+
+    #[test]
+    fn test_signature_status() {
+        let mint = Mint::new(2);
+        let accountant = Accountant::new(&mint);
+        let bob_keypair = KeyPair::new();
+        let bob_pubkey = bob_keypair.pubkey();
+        let tr = Transaction::new(&mint.keypair(), bob_pubkey, 1, mint.last_id());
+        assert_eq!(
+            accountant.get_signature_status(&tr.sig),
+            SignatureStatus::NotFound
+        );
+        accountant.process_verified_transaction(&tr).unwrap();
+        assert_eq!(
+            accountant.get_signature_status(&tr.sig),
+            SignatureStatus::Processed
+        );
+
+        let dt = Utc::now();
+        let sig = accountant
+            .transfer_on_date(1, &mint.keypair(), bob_pubkey, dt, mint.last_id())
+            .unwrap();
+        assert_eq!(
+            accountant.get_signature_status(&sig),
+            SignatureStatus::Pending
+        );
+        accountant
+            .process_verified_timestamp(mint.pubkey(), dt)
+            .unwrap();
+        assert_eq!(
+            accountant.get_signature_status(&sig),
+            SignatureStatus::Processed
+        );
+
+        let tr = Transaction::new(&mint.keypair(), bob_pubkey, 2, mint.last_id());
+        assert!(accountant.process_verified_transaction(&tr).is_err());
+        assert_eq!(
+            accountant.get_signature_status(&tr.sig),
+            SignatureStatus::Rejected(AccountingError::InsufficientFunds)
+        );
+
+        // Once the funds arrive, the same transaction can be resubmitted.
+        accountant
+            .transfer(2, &bob_keypair, mint.pubkey(), mint.last_id())
+            .unwrap();
+        accountant.process_verified_transaction(&tr).unwrap();
+        assert_eq!(
+            accountant.get_signature_status(&tr.sig),
+            SignatureStatus::Processed
+        );
+    }
+
+    #[test]
+    fn test_rejections_expire() {
+        let mint = Mint::new(1);
+        let accountant = Accountant::new(&mint);
+        let tr = Transaction::new(&mint.keypair(), mint.pubkey(), 2, mint.last_id());
+        assert!(accountant.process_verified_transaction(&tr).is_err());
+        assert_ne!(
+            accountant.get_signature_status(&tr.sig),
+            SignatureStatus::NotFound
+        );
+        for i in 0..MAX_ENTRY_IDS {
+            accountant.register_entry_id(&hash(&serialize(&i).unwrap()));
+        }
+        assert_eq!(
+            accountant.get_signature_status(&tr.sig),
+            SignatureStatus::NotFound
+        );
+    }
+
+    #[test]
+    fn test_finality() {
+        let mint = Mint::new(100);
+        let accountant = Accountant::new(&mint);
+        let alice = KeyPair::new();
+        let bob = KeyPair::new();
+        let carol = KeyPair::new();
+        accountant
+            .transfer(30, &mint.keypair(), alice.pubkey(), mint.last_id())
+            .unwrap();
+        accountant
+            .transfer(30, &mint.keypair(), bob.pubkey(), mint.last_id())
+            .unwrap();
+        accountant
+            .transfer(1, &mint.keypair(), carol.pubkey(), mint.last_id())
+            .unwrap();
+        let id0 = hash(&mint.last_id());
+        let id1 = hash(&id0);
+        accountant.register_entry_id(&id0);
+        accountant.register_entry_id(&id1);
+        let state_hash = accountant.state_hash();
+        let vote = |keypair: &KeyPair, entry_id| {
+            let event = Event::new_vote(keypair, entry_id, state_hash);
+            accountant.process_verified_event(event)
+        };
+
+        // Unknown voters and entries aren't counted.
+        assert_eq!(
+            vote(&KeyPair::new(), id1),
+            Err(AccountingError::AccountNotFound)
+        );
+        assert_eq!(
+            vote(&alice, Hash::default()),
+            Err(AccountingError::EntryIdNotFound)
+        );
+
+        // The mint holds 39 of 100 tokens; alice and bob 30 each, less a fee per vote.
+        vote(&alice, id1).unwrap();
+        vote(&bob, id0).unwrap();
+        assert_eq!(accountant.finalized_id(), None);
+
+        // The mint's vote for id0 brings in enough stake to finalize it, but not id1.
+        vote(&mint.keypair(), id0).unwrap();
+        assert_eq!(accountant.finalized_id(), Some(id0));
+        vote(&bob, id1).unwrap();
+        assert_eq!(accountant.finalized_id(), Some(id0));
+        vote(&mint.keypair(), id1).unwrap();
+        assert_eq!(accountant.finalized_id(), Some(id1));
+
+        // A vote for an earlier entry doesn't take back a later one.
+        vote(&alice, id0).unwrap();
+        assert_eq!(accountant.finalized_id(), Some(id1));
+
+        // Votes can't be replayed, and each one costs the voter a fee.
+        assert_eq!(
+            vote(&bob, id0),
+            Err(AccountingError::InvalidTransferSignature)
+        );
+        assert_eq!(accountant.get_balance(&bob.pubkey()), Some(30 - 2 * VOTE_FEE));
+        vote(&carol, id0).unwrap();
+        assert_eq!(vote(&carol, id1), Err(AccountingError::InsufficientFunds));
+
+        // Votes and finality survive a snapshot.
+        let accountant = Accountant::new_from_snapshot(accountant.snapshot());
+        assert_eq!(accountant.finalized_id(), Some(id1));
+    }
+
     #[test]
     fn test_debits_before_credits() {
         let mint = Mint::new(2);
@@ -1,12 +1,13 @@
 //! The `accounting_stage` module implements the accounting stage of the TPU.
 
-use accountant::Accountant;
+use accountant::{Accountant, MAX_ENTRY_IDS};
 use entry::Entry;
 use event::Event;
 use hash::Hash;
 use historian::Historian;
 use recorder::Signal;
 use result::Result;
+use std::collections::VecDeque;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 
@@ -16,6 +17,10 @@ pub struct AccountingStage {
     pub accountant: Arc<Accountant>,
     historian_input: Mutex<Sender<Signal>>,
     historian: Mutex<Historian>,
+    state_hashes: Mutex<VecDeque<(Hash, Hash)>>,
+    /// Ticks left in this node's slot as the leader, or `None` if the historian
+    /// keeps time on its own and there are no slots
+    ticks_left: Mutex<Option<u64>>,
 }
 
 impl AccountingStage {
@@ -30,22 +35,160 @@ impl AccountingStage {
             accountant: Arc::new(accountant),
             historian_input: Mutex::new(historian_input),
             historian: Mutex::new(historian),
+            state_hashes: Mutex::new(VecDeque::new()),
+            ticks_left: Mutex::new(None),
         }
     }
 
+    /// Start recording a slot of `num_ticks` ticks that continues the PoH sequence
+    /// from `start_hash`. The slot only advances with `tick`, and events are dropped
+    /// once its last tick is recorded, so the slot ends on a tick. The
+    /// `num_skipped_ticks` left of the slots before it, whose leaders were skipped,
+    /// are recorded right away, before any events.
+    pub fn start_slot(
+        &self,
+        start_hash: &Hash,
+        num_skipped_ticks: u64,
+        num_ticks: u64,
+    ) -> Result<()> {
+        let mut historian = self.historian.lock().unwrap();
+        let (historian_input, event_receiver) = channel();
+        *historian = Historian::new_hashing(event_receiver, start_hash);
+        *self.historian_input.lock().unwrap() = historian_input;
+        for _ in 0..num_skipped_ticks {
+            self.record_tick(&historian)?;
+        }
+        *self.ticks_left.lock().unwrap() = Some(num_ticks);
+        Ok(())
+    }
+
+    /// Drop events until the next `start_slot`, while another node leads.
+    pub fn end_slot(&self) {
+        let _historian = self.historian.lock().unwrap();
+        *self.ticks_left.lock().unwrap() = Some(0);
+    }
+
+    /// Record the next tick of the slot. Returns the number of ticks left.
+    pub fn tick(&self) -> Result<u64> {
+        let historian = self.historian.lock().unwrap();
+        let mut ticks_left = self.ticks_left.lock().unwrap();
+        let left = ticks_left.unwrap_or(0);
+        if left == 0 {
+            return Ok(0);
+        }
+        self.record_tick(&historian)?;
+        *ticks_left = Some(left - 1);
+        Ok(left - 1)
+    }
+
+    fn record_tick(&self, historian: &Historian) -> Result<()> {
+        // A tick recorded before the historian hashed again shares the id of the
+        // entry before it, and would be merged into it by the replicas. Since it
+        // doesn't move the hash forward either, drop it and ask again.
+        let entry = loop {
+            self.historian_input.lock().unwrap().send(Signal::Tick)?;
+            let entry = historian.output.lock().unwrap().recv()?;
+            if entry.num_hashes > 0 {
+                break entry;
+            }
+        };
+        self.accountant.register_entry_id(&entry.id);
+        self.entry_sender.lock().unwrap().send(entry)?;
+        Ok(())
+    }
+
     /// Process the transactions in parallel and then log the successful ones.
     pub fn process_events(&self, events: Vec<Event>) -> Result<()> {
         let historian = self.historian.lock().unwrap();
+        let ticks_left = *self.ticks_left.lock().unwrap();
+        if ticks_left == Some(0) {
+            if !events.is_empty() {
+                debug!("not recording a slot, dropping {} events", events.len());
+            }
+            return Ok(());
+        }
+        let events = self.filter_votes(events);
         let results = self.accountant.process_verified_events(events);
-        let events = results.into_iter().filter_map(|x| x.ok()).collect();
+        let events: Vec<_> = results.into_iter().filter_map(|x| x.ok()).collect();
+        let has_events = !events.is_empty();
+        // in a slot, only `tick` records entries without events
+        if !has_events && ticks_left.is_some() {
+            return Ok(());
+        }
         let sender = self.historian_input.lock().unwrap();
         sender.send(Signal::Events(events))?;
 
         // Wait for the historian to tag our Events with an ID and then register it.
-        let entry = historian.output.lock().unwrap().recv()?;
-        self.accountant.register_entry_id(&entry.id);
-        self.entry_sender.lock().unwrap().send(entry)?;
-        Ok(())
+        // Ticks recorded before our Events come out first.
+        loop {
+            let entry = historian.output.lock().unwrap().recv()?;
+            self.accountant.register_entry_id(&entry.id);
+            let is_ours = !has_events || !entry.events.is_empty();
+            if is_ours {
+                self.accountant.pay_collected_fees();
+                if has_events {
+                    self.record_state_hash(&entry.id);
+                }
+            }
+            self.entry_sender.lock().unwrap().send(entry)?;
+            if is_ours {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Drop the votes that contradict the state hash recorded for their entry,
+    /// so only validators that agree with this node are counted. Votes for
+    /// entries recorded by another leader can't be checked and are kept.
+    fn filter_votes(&self, events: Vec<Event>) -> Vec<Event> {
+        events
+            .into_iter()
+            .filter(|event| match *event {
+                Event::Vote {
+                    from,
+                    entry_id,
+                    state_hash,
+                    ..
+                } => match self.get_state_hash(&entry_id) {
+                    Some(x) if x != state_hash => {
+                        warn!(
+                            "replica {:?} disagrees on the state at entry {:?}: {:?} != {:?}",
+                            from, entry_id, state_hash, x
+                        );
+                        false
+                    }
+                    Some(_) => true,
+                    None => {
+                        debug!("no state hash for entry {:?}", entry_id);
+                        true
+                    }
+                },
+                _ => true,
+            })
+            .collect()
+    }
+
+    /// Remember the accountant's state hash as of the Entry `entry_id`, so that
+    /// replicas can be checked against it.
+    fn record_state_hash(&self, entry_id: &Hash) {
+        let state_hash = self.accountant.state_hash();
+        let mut state_hashes = self.state_hashes.lock().unwrap();
+        if state_hashes.len() >= MAX_ENTRY_IDS {
+            state_hashes.pop_front();
+        }
+        state_hashes.push_back((*entry_id, state_hash));
+    }
+
+    /// Return the state hash recorded for the Entry `entry_id`, if it's recent
+    /// enough to still be remembered. Only entries with events are recorded.
+    pub fn get_state_hash(&self, entry_id: &Hash) -> Option<Hash> {
+        self.state_hashes
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|x| x.0 == *entry_id)
+            .map(|x| x.1)
     }
 }
 
@@ -55,8 +198,12 @@ mod tests {
     use accounting_stage::AccountingStage;
     use entry::Entry;
     use event::Event;
+    use hash::Hash;
+    use ledger::Block;
     use mint::Mint;
     use signature::{KeyPair, KeyPairUtil};
+    use std::thread::sleep;
+    use std::time::Duration;
     use transaction::Transaction;
 
     #[test]
@@ -97,6 +244,118 @@ mod tests {
         }
         assert_eq!(accountant.get_balance(&alice.pubkey()), Some(1));
     }
+
+    #[test]
+    fn test_accounting_state_hash() {
+        let mint = Mint::new(2);
+        let accountant = Accountant::new(&mint);
+        let accounting_stage = AccountingStage::new(accountant, &mint.last_id(), Some(1));
+
+        // Give the historian time to record ticks ahead of our events.
+        sleep(Duration::from_millis(10));
+        let alice = KeyPair::new();
+        let tr = Transaction::new(&mint.keypair(), alice.pubkey(), 2, mint.last_id());
+        accounting_stage
+            .process_events(vec![Event::Transaction(tr)])
+            .unwrap();
+
+        // The last entry sent is the one with our events.
+        let entries: Vec<Entry> = accounting_stage.output.lock().unwrap().try_iter().collect();
+        let entry = entries.last().unwrap();
+        assert_eq!(entry.events.len(), 1);
+
+        // A replica that processed the same entries agrees on the state.
+        let accountant = Accountant::new(&mint);
+        accountant.process_verified_entries(entries.clone()).unwrap();
+        assert_eq!(
+            accounting_stage.get_state_hash(&entry.id),
+            Some(accountant.state_hash())
+        );
+    }
+
+    #[test]
+    fn test_accounting_slot() {
+        let mint = Mint::new(2);
+        let accountant = Accountant::new(&mint);
+        let accounting_stage = AccountingStage::new(accountant, &mint.last_id(), None);
+        let alice = KeyPair::new();
+
+        // nothing is recorded between slots
+        accounting_stage.end_slot();
+        let tr = Transaction::new(&mint.keypair(), alice.pubkey(), 1, mint.last_id());
+        accounting_stage
+            .process_events(vec![Event::Transaction(tr)])
+            .unwrap();
+        assert_eq!(accounting_stage.tick().unwrap(), 0);
+        assert_eq!(accounting_stage.accountant.get_balance(&alice.pubkey()), None);
+
+        // a slot of two ticks, with a transaction in between
+        accounting_stage.start_slot(&mint.last_id(), 0, 2).unwrap();
+        assert_eq!(accounting_stage.tick().unwrap(), 1);
+        let tr = Transaction::new(&mint.keypair(), alice.pubkey(), 2, mint.last_id());
+        accounting_stage
+            .process_events(vec![Event::Transaction(tr)])
+            .unwrap();
+        assert_eq!(accounting_stage.tick().unwrap(), 0);
+
+        // the slot ends on its last tick
+        let tr = Transaction::new(&alice, mint.pubkey(), 1, mint.last_id());
+        accounting_stage
+            .process_events(vec![Event::Transaction(tr)])
+            .unwrap();
+        assert_eq!(accounting_stage.accountant.get_balance(&alice.pubkey()), Some(2));
+
+        let entries: Vec<Entry> = accounting_stage.output.lock().unwrap().try_iter().collect();
+        assert_eq!(entries.len(), 3);
+        assert!(entries[0].events.is_empty());
+        assert_eq!(entries[1].events.len(), 1);
+        assert!(entries[2].events.is_empty());
+        assert!(entries[..].verify(&mint.last_id()));
+
+        // the ticks skipped before a slot are recorded as it starts
+        let last_id = entries[2].id;
+        accounting_stage.start_slot(&last_id, 2, 1).unwrap();
+        let tr = Transaction::new(&alice, mint.pubkey(), 2, mint.last_id());
+        accounting_stage
+            .process_events(vec![Event::Transaction(tr)])
+            .unwrap();
+        assert_eq!(accounting_stage.tick().unwrap(), 0);
+        let entries: Vec<Entry> = accounting_stage.output.lock().unwrap().try_iter().collect();
+        assert_eq!(entries.len(), 4);
+        assert!(entries[0].events.is_empty());
+        assert!(entries[1].events.is_empty());
+        assert_eq!(entries[2].events.len(), 1);
+        assert!(entries[3].events.is_empty());
+        assert!(entries[..].verify(&last_id));
+    }
+
+    #[test]
+    fn test_accounting_votes() {
+        let mint = Mint::new(20);
+        let accountant = Accountant::new(&mint);
+        let accounting_stage = AccountingStage::new(accountant, &mint.last_id(), None);
+        let alice = KeyPair::new();
+        let tr = Transaction::new(&mint.keypair(), alice.pubkey(), 10, mint.last_id());
+        accounting_stage
+            .process_events(vec![Event::Transaction(tr)])
+            .unwrap();
+        let entry = accounting_stage.output.lock().unwrap().recv().unwrap();
+        let state_hash = accounting_stage.get_state_hash(&entry.id).unwrap();
+
+        // A vote that disagrees with the leader's state isn't recorded.
+        let vote = Event::new_vote(&alice, entry.id, Hash::default());
+        accounting_stage.process_events(vec![vote]).unwrap();
+        let entry1 = accounting_stage.output.lock().unwrap().recv().unwrap();
+        assert!(entry1.events.is_empty());
+
+        // Alice and the mint hold all the stake, so their votes finalize the entry,
+        // less the fees they pay for them.
+        for keypair in &[alice, mint.keypair()] {
+            let vote = Event::new_vote(keypair, entry.id, state_hash);
+            accounting_stage.process_events(vec![vote]).unwrap();
+        }
+        assert_eq!(accounting_stage.accountant.finalized_id(), Some(entry.id));
+    }
 }
 
 #[cfg(all(feature = "unstable", test))]
@@ -0,0 +1,213 @@
+//! A command-line executable for verifying and inspecting a ledger.
+
+extern crate getopts;
+extern crate isatty;
+extern crate serde_json;
+extern crate solana;
+
+use getopts::Options;
+use isatty::stdin_isatty;
+use solana::accountant::Accountant;
+use solana::crdt::ReplicatedData;
+use solana::entry::Entry;
+use solana::event::Event;
+use solana::leader_schedule::{LeaderSchedule, TICKS_PER_SLOT};
+use solana::ledger::Block;
+use solana::signature::{read_keypair, KeyPairUtil, PublicKey};
+use std::env;
+use std::fs::File;
+use std::io::{stdin, BufReader, Read};
+use std::process::exit;
+
+fn print_usage(program: &str, opts: Options) {
+    let mut brief = format!("Usage: cat <transaction.log> | {} [options] COMMAND\n\n", program);
+    brief += "  Inspect the transaction log on stdin. COMMAND is one of:\n";
+    brief += "    verify    check the Proof of History and report the first bad entry\n";
+    brief += "    print     print each entry and its events\n";
+    brief += "    stats     count ticks and transactions, and estimate the hash rate\n";
+    brief += "    balances  replay the log and print the final balances";
+
+    print!("{}", opts.usage(&brief));
+}
+
+fn verify(entries: &[Entry]) {
+    // The first entry has zero num_hashes, so its id is the ledger's seed.
+    match entries.first_invalid(&entries[0].id) {
+        None => println!("ledger verified, {} entries", entries.len()),
+        Some(i) => {
+            println!("entry {} does not follow from its predecessor", i);
+            exit(1);
+        }
+    }
+}
+
+fn print(entries: &[Entry]) {
+    for (i, entry) in entries.iter().enumerate() {
+        println!(
+            "{}: id {:x} num_hashes {} events {}",
+            i,
+            entry.id,
+            entry.num_hashes,
+            entry.events.len()
+        );
+        for event in &entry.events {
+            println!("    {:?}", event);
+        }
+    }
+}
+
+fn stats(entries: &[Entry], ms_per_tick: u64) {
+    let num_ticks = entries.iter().filter(|e| e.events.is_empty()).count();
+    let num_events: usize = entries.iter().map(|e| e.events.len()).sum();
+    let num_transactions = entries
+        .iter()
+        .flat_map(|e| &e.events)
+        .filter(|e| match **e {
+            Event::Transaction(_) => true,
+            _ => false,
+        })
+        .count();
+    let num_hashes: u64 = entries.iter().map(|e| e.num_hashes).sum();
+    println!("entries: {}", entries.len());
+    println!("ticks: {}", num_ticks);
+    println!("transactions: {}", num_transactions);
+    println!("witnesses: {}", num_events - num_transactions);
+    println!("hashes: {}", num_hashes);
+
+    // The ledger doesn't record wall-clock time, so assume each tick took `ms_per_tick`.
+    if num_ticks > 0 && ms_per_tick > 0 {
+        let secs = (num_ticks as u64 * ms_per_tick) as f64 / 1000.0;
+        println!("hash rate: {:.0} hashes/s", num_hashes as f64 / secs);
+    }
+}
+
+/// Replay the log and print the balances. The fees of each entry go to the
+/// validator whose slot it is in by `schedule`, or else to `leader`.
+fn balances(entries: &[Entry], leader: Option<PublicKey>, schedule: Option<LeaderSchedule>) {
+    if entries.len() < 2 {
+        eprintln!("expected a genesis block of at least 2 entries");
+        exit(1);
+    }
+
+    // Replay the way the node does, so that fees are paid out as they were.
+    let accountant = Accountant::new_from_genesis(&entries[0], &entries[1]).unwrap_or_else(|| {
+        eprintln!("expected a deposit in entry 1");
+        exit(1);
+    });
+    if let Some(leader) = leader {
+        accountant.set_leader(leader);
+    }
+    let mut tick_height = 0;
+    for entry in &entries[2..] {
+        if let Some(ref schedule) = schedule {
+            accountant.set_leader(schedule.leader_of_next(&mut tick_height, entry));
+        }
+        if let Err(e) = accountant.process_verified_entries(Some(entry.clone())) {
+            eprintln!("failed to process event: {:?}", e);
+            exit(1);
+        }
+    }
+
+    let mut balances: Vec<_> = accountant.get_balances().into_iter().collect();
+    balances.sort();
+    for (key, tokens) in balances {
+        println!("{:x} {}", key, tokens);
+    }
+}
+
+fn main() {
+    let mut ms_per_tick = 1000u64;
+    let mut opts = Options::new();
+    opts.optopt(
+        "t",
+        "",
+        "milliseconds per tick, used by stats to estimate the hash rate",
+        "MS",
+    );
+    opts.optopt(
+        "i",
+        "",
+        "identity file of the node that wrote the log, which balances pays the fees to",
+        "FILE",
+    );
+    opts.optopt(
+        "v",
+        "",
+        "validators file of the nodes that took turns writing the log, which balances pays \
+         the fees of each slot to its leader from",
+        "FILE",
+    );
+    opts.optflag("h", "help", "print help");
+    let args: Vec<String> = env::args().collect();
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    };
+    if matches.opt_present("h") || matches.free.len() != 1 {
+        let program = args[0].clone();
+        print_usage(&program, opts);
+        return;
+    }
+    if matches.opt_present("t") {
+        ms_per_tick = matches.opt_str("t").unwrap().parse().expect("ms per tick");
+    }
+
+    if stdin_isatty() {
+        eprintln!("nothing found on stdin, expected a log file");
+        exit(1);
+    }
+
+    let mut buffer = String::new();
+    let num_bytes = stdin().read_to_string(&mut buffer).unwrap();
+    if num_bytes == 0 {
+        eprintln!("empty file on stdin, expected a log file");
+        exit(1);
+    }
+
+    let entries: Vec<Entry> = buffer
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line).unwrap_or_else(|e| {
+                eprintln!("failed to parse json on line {}: {}", i + 1, e);
+                exit(1);
+            })
+        })
+        .collect();
+
+    match matches.free[0].as_str() {
+        "verify" => verify(&entries),
+        "print" => print(&entries),
+        "stats" => stats(&entries, ms_per_tick),
+        "balances" => {
+            let leader = matches.opt_str("i").map(|path| {
+                read_keypair(&path)
+                    .unwrap_or_else(|e| {
+                        eprintln!("failed to read identity: {}", e);
+                        exit(1);
+                    })
+                    .pubkey()
+            });
+            let schedule = matches.opt_str("v").map(|path| {
+                let file = File::open(&path).unwrap_or_else(|e| {
+                    eprintln!("failed to open validators file: {}", e);
+                    exit(1);
+                });
+                let validators: Vec<ReplicatedData> =
+                    serde_json::from_reader(BufReader::new(file)).unwrap_or_else(|e| {
+                        eprintln!("failed to read validators file: {}", e);
+                        exit(1);
+                    });
+                LeaderSchedule::new(validators.iter().map(|v| v.id).collect(), TICKS_PER_SLOT)
+            });
+            balances(&entries, leader, schedule)
+        }
+        command => {
+            eprintln!("unknown command: {}", command);
+            exit(1);
+        }
+    }
+}
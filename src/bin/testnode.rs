@@ -1,39 +1,143 @@
+extern crate bincode;
 extern crate env_logger;
 extern crate getopts;
 extern crate isatty;
 extern crate serde_json;
 extern crate solana;
 
+use bincode::{deserialize_from, serialize_into};
 use getopts::Options;
 use isatty::stdin_isatty;
-use solana::accountant::Accountant;
+use solana::account_history::AccountHistory;
+use solana::accountant::{Accountant, Snapshot};
 use solana::accounting_stage::AccountingStage;
 use solana::crdt::ReplicatedData;
 use solana::entry::Entry;
-use solana::event::Event;
-use solana::signature::{KeyPair, KeyPairUtil};
+use solana::erasure::ErasureConfig;
+use solana::leader_schedule::{LeaderSchedule, TICKS_PER_SLOT};
+use solana::ledger_store::{EntryWriter, LedgerReader, LedgerWriter};
+use solana::packet::NUM_BLOBS;
+use solana::signature::{read_keypair, write_new_keypair, KeyPair, KeyPairUtil, PublicKey};
 use solana::tpu::Tpu;
 use std::env;
-use std::io::{stdin, stdout, Read};
-use std::net::UdpSocket;
+use std::fs::{rename, File};
+use std::io::{stdin, stdout, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::path::Path;
 use std::process::exit;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 
+const MS_PER_TICK: u64 = 1000;
+
 fn print_usage(program: &str, opts: Options) {
     let mut brief = format!("Usage: cat <transaction.log> | {} [options]\n\n", program);
     brief += "  Run a Solana node to handle transactions and\n";
     brief += "  write a new transaction log to stdout.\n";
-    brief += "  Takes existing transaction log from stdin.";
+    brief += "  Takes existing transaction log from stdin.\n";
+    brief += "  With -l, reads and appends to a ledger on disk instead.";
 
     print!("{}", opts.usage(&brief));
 }
 
+fn read_stdin(buffer: &mut String) {
+    if stdin_isatty() {
+        eprintln!("nothing found on stdin, expected a log file");
+        exit(1);
+    }
+
+    let num_bytes = stdin().read_to_string(buffer).unwrap();
+    if num_bytes == 0 {
+        eprintln!("empty file on stdin, expected a log file");
+        exit(1);
+    }
+}
+
+fn parse_entries<'a>(buffer: &'a str) -> Box<Iterator<Item = Entry> + 'a> {
+    Box::new(buffer.lines().map(|line| {
+        serde_json::from_str(&line).unwrap_or_else(|e| {
+            eprintln!("failed to parse json: {}", e);
+            exit(1);
+        })
+    }))
+}
+
+/// Parse the erasure sets of the `-e` option, given as `NUM_CODED,MAX_MISSING`.
+fn parse_erasure(s: &str) -> Option<ErasureConfig> {
+    let mut it = s.split(',').map(|x| x.trim().parse::<usize>());
+    match (it.next(), it.next(), it.next()) {
+        (Some(Ok(num_coded)), Some(Ok(max_missing)), None)
+            if max_missing < num_coded && num_coded <= NUM_BLOBS =>
+        {
+            Some(ErasureConfig::new(num_coded, max_missing))
+        }
+        _ => None,
+    }
+}
+
+/// The leader that recorded `entry`, the next one in the ledger. When taking turns
+/// leading by `schedule`, also move `tick_height` and `slot_entries` past it.
+fn replay_leader(
+    schedule: &Option<LeaderSchedule>,
+    me: PublicKey,
+    tick_height: &mut u64,
+    slot_entries: &mut Vec<Entry>,
+    entry: &Entry,
+) -> PublicKey {
+    match *schedule {
+        Some(ref schedule) => {
+            let leader = schedule.leader_of_next(tick_height, entry);
+            schedule.push_slot_entry(slot_entries, *tick_height, entry.clone());
+            leader
+        }
+        None => me,
+    }
+}
+
 fn main() {
     env_logger::init().unwrap();
     let mut port = 8000u16;
     let mut opts = Options::new();
     opts.optopt("p", "", "port", "port");
+    opts.optopt(
+        "s",
+        "",
+        "load the accountant from a snapshot and update it after replaying the ledger",
+        "FILE",
+    );
+    opts.optopt(
+        "l",
+        "",
+        "read and append to the ledger in this directory instead of stdin and stdout",
+        "DIR",
+    );
+    opts.optopt(
+        "i",
+        "",
+        "read the node's identity, which fees are paid to, from this file, creating it if needed",
+        "FILE",
+    );
+    opts.optopt("r", "", "serve JSON-RPC over HTTP on this port", "PORT");
+    opts.optopt(
+        "v",
+        "",
+        "take turns leading with the validators in this file, a JSON array of their \
+         signed data that includes this node's",
+        "FILE",
+    );
+    opts.optopt(
+        "d",
+        "",
+        "print this node's signed data for a validators file, reachable at HOST, and exit",
+        "HOST",
+    );
+    opts.optopt(
+        "e",
+        "",
+        "split the blobs into erasure sets of NUM_CODED blobs, MAX_MISSING of them coding \
+         blobs, the same on every node",
+        "NUM_CODED,MAX_MISSING",
+    );
     opts.optflag("h", "help", "print help");
     let args: Vec<String> = env::args().collect();
     let matches = match opts.parse(&args[1..]) {
@@ -51,94 +155,277 @@ fn main() {
     if matches.opt_present("p") {
         port = matches.opt_str("p").unwrap().parse().expect("port");
     }
+    let snapshot_path = matches.opt_str("s");
+    let ledger_path = matches.opt_str("l");
+    let identity_path = matches.opt_str("i");
+    let erasure = matches.opt_str("e").map(|x| {
+        parse_erasure(&x).unwrap_or_else(|| {
+            eprintln!(
+                "expected NUM_CODED,MAX_MISSING with MAX_MISSING < NUM_CODED <= {}",
+                NUM_BLOBS
+            );
+            exit(1);
+        })
+    });
+    let rpc_port: Option<u16> = matches
+        .opt_str("r")
+        .map(|x| x.parse().expect("rpc port"));
     let serve_addr = format!("0.0.0.0:{}", port);
     let gossip_addr = format!("0.0.0.0:{}", port + 1);
     let replicate_addr = format!("0.0.0.0:{}", port + 2);
     let events_addr = format!("0.0.0.0:{}", port + 3);
 
-    if stdin_isatty() {
-        eprintln!("nothing found on stdin, expected a log file");
+    let keypair = match identity_path {
+        Some(ref path) => read_keypair(path).or_else(|_| write_new_keypair(path)),
+        None => Ok(KeyPair::new()),
+    }.unwrap_or_else(|e| {
+        eprintln!("failed to read or create identity: {}", e);
         exit(1);
+    });
+    if identity_path.is_none() {
+        eprintln!("no identity file given, fees will be paid to a key that isn't saved");
     }
 
-    let mut buffer = String::new();
-    let num_bytes = stdin().read_to_string(&mut buffer).unwrap();
-    if num_bytes == 0 {
-        eprintln!("empty file on stdin, expected a log file");
-        exit(1);
+    if let Some(host) = matches.opt_str("d") {
+        let addr = |port: u16| -> SocketAddr {
+            format!("{}:{}", host, port).parse().unwrap_or_else(|e| {
+                eprintln!("failed to parse host {}: {}", host, e);
+                exit(1);
+            })
+        };
+        let id = keypair.pubkey();
+        let mut d = ReplicatedData::new(id, addr(port + 1), addr(port + 2), addr(port));
+        d.sign(&keypair);
+        println!("{}", serde_json::to_string(&d).unwrap());
+        return;
     }
 
-    eprintln!("Initializing...");
-    let mut entries = buffer.lines().map(|line| {
-        serde_json::from_str(&line).unwrap_or_else(|e| {
-            eprintln!("failed to parse json: {}", e);
+    let validators: Option<Vec<ReplicatedData>> = matches.opt_str("v").map(|path| {
+        let file = File::open(&path).unwrap_or_else(|e| {
+            eprintln!("failed to open validators file: {}", e);
             exit(1);
-        })
+        });
+        let validators: Vec<ReplicatedData> = serde_json::from_reader(BufReader::new(file))
+            .unwrap_or_else(|e| {
+                eprintln!("failed to read validators file: {}", e);
+                exit(1);
+            });
+        if !validators.iter().any(|v| v.id == keypair.pubkey()) {
+            eprintln!("this node's identity isn't in the validators file");
+            exit(1);
+        }
+        validators
     });
 
+    eprintln!("Initializing...");
+    let mut buffer = String::new();
+    let mut ledger_writer = None;
+    let entries = if let Some(ref path) = ledger_path {
+        let path = Path::new(path);
+        let mut writer = LedgerWriter::open(path).unwrap_or_else(|e| {
+            eprintln!("failed to open ledger: {:?}", e);
+            exit(1);
+        });
+        let mut reader = LedgerReader::open(path).expect("open ledger");
+        if reader.len().expect("ledger length") == 0 {
+            // Seed a new ledger with the log on stdin, typically the genesis block.
+            read_stdin(&mut buffer);
+            for entry in parse_entries(&buffer) {
+                writer.write_entry(&entry).expect("write entry");
+            }
+        }
+        ledger_writer = Some(writer);
+        let entries = reader.entries_from(0).expect("read ledger");
+        Box::new(entries.map(|entry| {
+            entry.unwrap_or_else(|e| {
+                eprintln!("failed to read entry: {:?}", e);
+                exit(1);
+            })
+        }))
+    } else {
+        read_stdin(&mut buffer);
+        parse_entries(&buffer)
+    };
+
     eprintln!("done parsing...");
 
-    // The first item in the ledger is required to be an entry with zero num_hashes,
-    // which implies its id can be used as the ledger's seed.
-    let entry0 = entries.next().unwrap();
+    // A missing snapshot file isn't an error; one will be written after replay.
+    let snapshot: Option<Snapshot> = snapshot_path
+        .as_ref()
+        .and_then(|path| File::open(path).ok())
+        .map(|file| {
+            deserialize_from(&mut BufReader::new(file)).unwrap_or_else(|e| {
+                eprintln!("failed to read snapshot: {}", e);
+                exit(1);
+            })
+        });
 
-    // The second item in the ledger is a special transaction where the to and from
-    // fields are the same. That entry should be treated as a deposit, not a
-    // transfer to oneself.
+    // Each entry's fees were paid to the leader that recorded it: this node, unless
+    // it takes turns leading, in which case the schedule says whose slot it was in.
+    // The genesis entries don't count towards the tick height.
+    let me = keypair.pubkey();
+    let schedule = validators.as_ref().map(|validators| {
+        LeaderSchedule::new(validators.iter().map(|v| v.id).collect(), TICKS_PER_SLOT)
+    });
+    let mut tick_height = 0;
+    let mut slot_entries = vec![];
+    let mut entries = entries;
+    let entry0: Entry = entries.next().unwrap();
     let entry1: Entry = entries.next().unwrap();
-    let deposit = if let Event::Transaction(ref tr) = entry1.events[0] {
-        tr.data.plan.final_payment()
-    } else {
-        None
-    };
 
-    eprintln!("creating accountant...");
+    // Index every entry for account history requests, including the entries a
+    // snapshot accounts for, since the index isn't part of the snapshot.
+    let account_history = AccountHistory::default();
+    account_history.record_entry(&entry0, None);
+    account_history.record_entry(&entry1, None);
 
-    let accountant = Accountant::new_from_deposit(&deposit.unwrap());
-    accountant.register_entry_id(&entry0.id);
-    accountant.register_entry_id(&entry1.id);
+    let accountant = if let Some(snapshot) = snapshot {
+        eprintln!("loading snapshot...");
+
+        // Skip the entries the snapshot already accounts for.
+        let snapshot_id = snapshot.last_id;
+        let found = entry1.id == snapshot_id || entries.by_ref().any(|entry: Entry| {
+            let leader = replay_leader(&schedule, me, &mut tick_height, &mut slot_entries, &entry);
+            account_history.record_entry(&entry, Some(leader));
+            entry.id == snapshot_id
+        });
+        if !found {
+            eprintln!("snapshot entry {:?} not found in the ledger", snapshot_id);
+            exit(1);
+        }
+        Accountant::new_from_snapshot(snapshot)
+    } else {
+        eprintln!("creating accountant...");
+
+        Accountant::new_from_genesis(&entry0, &entry1).unwrap_or_else(|| {
+            eprintln!("expected a deposit in entry 1");
+            exit(1);
+        })
+    };
 
     eprintln!("processing entries...");
 
-    let mut last_id = entry1.id;
     for entry in entries {
-        last_id = entry.id;
-        let results = accountant.process_verified_events(entry.events);
-        for result in results {
-            if let Err(e) = result {
-                eprintln!("failed to process event {:?}", e);
-                exit(1);
-            }
+        let leader = replay_leader(&schedule, me, &mut tick_height, &mut slot_entries, &entry);
+        accountant.set_leader(leader);
+        if let Err(e) = accountant.process_verified_entries(Some(entry.clone())) {
+            eprintln!("failed to process event {:?}", e);
+            exit(1);
         }
-        accountant.register_entry_id(&last_id);
+        account_history.record_entry(&entry, Some(leader));
+    }
+    accountant.set_leader(me);
+    let last_id = accountant.last_id();
+
+    if let Some(path) = snapshot_path {
+        eprintln!("writing snapshot...");
+
+        // Write to a temporary file first, and make sure it's on disk before it
+        // replaces the old snapshot, so a crash can't leave a torn snapshot behind.
+        let tmp_path = format!("{}.tmp", path);
+        let file = File::create(&tmp_path).expect("create snapshot file");
+        let mut writer = BufWriter::new(file);
+        serialize_into(&mut writer, &accountant.snapshot()).expect("write snapshot");
+        writer.flush().expect("flush snapshot file");
+        let file = writer.into_inner().expect("flush snapshot file");
+        file.sync_all().expect("sync snapshot file");
+        rename(&tmp_path, &path).expect("rename snapshot file");
     }
 
     eprintln!("creating networking stack...");
 
-    let accounting_stage = AccountingStage::new(accountant, &last_id, Some(1000));
+    // When rotating, the slots are ticked by whoever leads them.
+    let ms_per_tick = if validators.is_some() {
+        None
+    } else {
+        Some(MS_PER_TICK)
+    };
+    let accounting_stage = AccountingStage::new(accountant, &last_id, ms_per_tick);
     let exit = Arc::new(AtomicBool::new(false));
-    let tpu = Arc::new(Tpu::new(accounting_stage));
+    let tpu = Tpu::new_with_history(accounting_stage, account_history);
+    if let Some(erasure) = erasure {
+        tpu.set_erasure(erasure);
+    }
+    let tpu = Arc::new(tpu);
     let serve_sock = UdpSocket::bind(&serve_addr).unwrap();
     let gossip_sock = UdpSocket::bind(&gossip_addr).unwrap();
     let replicate_sock = UdpSocket::bind(&replicate_addr).unwrap();
     let events_sock = UdpSocket::bind(&events_addr).unwrap();
-    let pubkey = KeyPair::new().pubkey();
     let d = ReplicatedData::new(
-        pubkey,
+        keypair.pubkey(),
         gossip_sock.local_addr().unwrap(),
         replicate_sock.local_addr().unwrap(),
         serve_sock.local_addr().unwrap(),
     );
     eprintln!("starting server...");
-    let threads = Tpu::serve(
-        &tpu,
-        d,
-        serve_sock,
-        events_sock,
-        gossip_sock,
-        exit.clone(),
-        stdout(),
-    ).unwrap();
+    let mut threads = if let Some(validators) = validators {
+        let me = validators
+            .iter()
+            .find(|v| v.id == d.id)
+            .cloned()
+            .expect("this node in the validators");
+        if let Some(writer) = ledger_writer {
+            Tpu::rotate(
+                &tpu,
+                keypair,
+                me,
+                gossip_sock,
+                serve_sock,
+                replicate_sock,
+                validators,
+                TICKS_PER_SLOT,
+                MS_PER_TICK,
+                tick_height,
+                slot_entries,
+                exit.clone(),
+                writer,
+            )
+        } else {
+            Tpu::rotate(
+                &tpu,
+                keypair,
+                me,
+                gossip_sock,
+                serve_sock,
+                replicate_sock,
+                validators,
+                TICKS_PER_SLOT,
+                MS_PER_TICK,
+                tick_height,
+                slot_entries,
+                exit.clone(),
+                stdout(),
+            )
+        }
+    } else if let Some(writer) = ledger_writer {
+        Tpu::serve(
+            &tpu,
+            keypair,
+            d,
+            serve_sock,
+            events_sock,
+            gossip_sock,
+            exit.clone(),
+            writer,
+        )
+    } else {
+        Tpu::serve(
+            &tpu,
+            keypair,
+            d,
+            serve_sock,
+            events_sock,
+            gossip_sock,
+            exit.clone(),
+            stdout(),
+        )
+    }.unwrap();
+    if let Some(rpc_port) = rpc_port {
+        let rpc_addr = format!("0.0.0.0:{}", rpc_port);
+        let listener = TcpListener::bind(&rpc_addr).unwrap();
+        threads.extend(Tpu::rpc_service(&tpu, listener, exit.clone()).unwrap());
+        eprintln!("Serving JSON-RPC on {}", rpc_addr);
+    }
     eprintln!("Ready. Listening on {}", serve_addr);
     for t in threads {
         t.join().expect("join");
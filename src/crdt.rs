@@ -11,16 +11,25 @@
 //! * layer 1 - As many nodes as we can fit
 //! * layer 2 - Everyone else, if layer 1 is `2^10`, layer 2 should be able to fit `2^20` number of nodes.
 //!
-//! Accountant needs to provide an interface for us to query the stake weight
+//! Every node computes the same tree from its table: the nodes other than the leader are sorted
+//! by id, the first `fanout` of them are layer 1, and the rest are split into one layer 2
+//! neighborhood per layer 1 node. The leader stripes its blobs across layer 1, sending each blob
+//! to one layer 1 node, which passes it to the rest of layer 1. Each layer 1 node retransmits
+//! the blobs to its neighborhood.
+//!
+//! Nodes are weighted by stake when picking gossip peers and ordering the broadcast fan-out.
+//! The stakes are set with `Crdt::set_stakes`, typically from the Accountant's balances.
 
 use bincode::{deserialize, serialize};
 use byteorder::{LittleEndian, ReadBytesExt};
+use erasure::ErasureConfig;
 use hash::Hash;
-use packet::SharedBlob;
+use packet::{SharedBlob, NUM_BLOBS};
 use rayon::prelude::*;
 use result::{Error, Result};
 use ring::rand::{SecureRandom, SystemRandom};
-use signature::{PublicKey, Signature};
+use signature::{KeyPair, KeyPairUtil, PublicKey, Signature, SignatureUtil};
+use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::io::Cursor;
 use std::net::{SocketAddr, UdpSocket};
@@ -28,8 +37,19 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread::{sleep, spawn, JoinHandle};
 use std::time::Duration;
+use streamer::Window;
+use timing::timestamp;
+
+/// Default number of milliseconds a node can go unheard from before it's purged
+pub const DEFAULT_PURGE_TIMEOUT_MS: u64 = 10_000;
+
+/// Most blobs sent to repair a node's window each second, enough to refill it twice
+pub const MAX_REPAIRS_PER_SECOND: u64 = 2 * NUM_BLOBS as u64;
 
-/// Structure to be replicated by the network
+/// Default number of layer 1 nodes, and the number of layer 2 nodes in each neighborhood
+pub const DEFAULT_FANOUT: usize = 1024;
+
+/// Structure to be replicated by the network, signed by the identity `id`
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ReplicatedData {
     pub id: PublicKey,
@@ -69,6 +89,31 @@ impl ReplicatedData {
             last_verified_count: 0,
         }
     }
+
+    fn get_sign_data(&self) -> Vec<u8> {
+        let data = (
+            &self.id,
+            self.version,
+            &self.gossip_addr,
+            &self.replicate_addr,
+            &self.serve_addr,
+            &self.current_leader_id,
+            &self.last_verified_hash,
+            self.last_verified_count,
+        );
+        serialize(&data).expect("serialize ReplicatedData in fn get_sign_data")
+    }
+
+    /// Sign this version of the data with the keypair of `id`.
+    pub fn sign(&mut self, keypair: &KeyPair) {
+        let sign_data = self.get_sign_data();
+        self.sig = Signature::clone_from_slice(keypair.sign(&sign_data).as_ref());
+    }
+
+    /// Verify that this version of the data was signed by `id`.
+    pub fn verify(&self) -> bool {
+        self.sig.verify(&self.id, &self.get_sign_data())
+    }
 }
 
 /// `Crdt` structure keeps a table of `ReplicatedData` structs
@@ -77,11 +122,14 @@ impl ReplicatedData {
 /// * `local` - map of public id's to what `self.update_index` `self.table` was updated
 /// * `remote` - map of public id's to the `remote.update_index` was sent
 /// * `update_index` - my update index
+/// * `alive` - map of public id's to the last time they were heard from
+/// * `stakes` - map of public id's to their stake
 /// # Remarks
 /// This implements two services, `gossip` and `listen`.
 /// * `gossip` - asynchronously ask nodes to send updates
 /// * `listen` - listen for requests and responses
 /// No attempt to keep track of timeouts or dropped requests is made, or should be.
+/// Nodes that go silent for longer than `purge_timeout_ms` are removed from the table.
 pub struct Crdt {
     table: HashMap<PublicKey, ReplicatedData>,
     /// Value of my update index when entry in table was updated.
@@ -93,10 +141,24 @@ pub struct Crdt {
     /// This Node will ask external nodes for updates since the value in this list
     pub remote: HashMap<PublicKey, u64>,
     pub update_index: u64,
+    /// Timestamp in milliseconds of the last time each node was heard from directly,
+    /// or when it was added to the table if it hasn't been heard from since
+    alive: HashMap<PublicKey, u64>,
+    /// Nodes that are silent for longer than this are suspected dead and purged
+    pub purge_timeout_ms: u64,
+    /// Stake of each node, nodes without an entry have no stake
+    stakes: HashMap<PublicKey, u64>,
+    /// The second of the last repair sent to each node, and how many were sent in it
+    repairs: HashMap<PublicKey, (u64, u64)>,
+    /// Size of layer 1 and of each layer 2 neighborhood in the broadcast tree
+    pub fanout: usize,
+    /// How the leader's blobs are split into erasure sets, the same on every node
+    pub erasure: ErasureConfig,
     me: PublicKey,
+    keypair: Arc<KeyPair>,
     timeout: Duration,
 }
-// TODO These messages should be signed, and go through the gpu pipeline for spam filtering
+// TODO These messages should go through the gpu pipeline for spam filtering
 #[derive(Serialize, Deserialize)]
 enum Protocol {
     /// forward your own latest data structure when requesting an update
@@ -106,16 +168,74 @@ enum Protocol {
     //TODO might need a since?
     /// from id, form's last update index, ReplicatedData
     ReceiveUpdates(PublicKey, u64, Vec<ReplicatedData>),
+    /// ask for the blob at an index in the window, which is sent straight to the
+    /// `replicate_addr` the requester has in the table
+    RequestWindowIndex(PublicKey, u64),
+}
+
+impl Protocol {
+    /// The identity that sent the message
+    fn from(&self) -> &PublicKey {
+        match *self {
+            Protocol::RequestUpdates(_, ref reqdata) => &reqdata.id,
+            Protocol::ReceiveUpdates(ref from, _, _) => from,
+            Protocol::RequestWindowIndex(ref from, _) => from,
+        }
+    }
+}
+
+/// A `Protocol` message, signed by the identity that sent it
+#[derive(Serialize, Deserialize)]
+struct SignedProtocol {
+    msg: Protocol,
+    sig: Signature,
+}
+
+impl SignedProtocol {
+    fn new(msg: Protocol, keypair: &KeyPair) -> Self {
+        let sign_data = serialize(&msg).expect("serialize Protocol in fn new");
+        let sig = Signature::clone_from_slice(keypair.sign(&sign_data).as_ref());
+        SignedProtocol { msg, sig }
+    }
+
+    fn verify(&self) -> bool {
+        let sign_data = serialize(&self.msg).expect("serialize Protocol in fn verify");
+        self.sig.verify(self.msg.from(), &sign_data)
+    }
+}
+
+/// Map the random number `r` to an index into `weights`, with each index
+/// picked in proportion to its weight.
+fn weighted_choice(weights: &[u64], r: u64) -> usize {
+    let total: u64 = weights.iter().sum();
+    let mut r = r % total;
+    for (i, w) in weights.iter().enumerate() {
+        if r < *w {
+            return i;
+        }
+        r -= w;
+    }
+    unreachable!("r is less than the total weight")
 }
 
 impl Crdt {
-    pub fn new(me: ReplicatedData) -> Crdt {
+    /// Create a Crdt for the node `me`, whose identity is `keypair`.
+    pub fn new(mut me: ReplicatedData, keypair: Arc<KeyPair>) -> Crdt {
         assert_eq!(me.version, 0);
+        assert_eq!(me.id, keypair.pubkey());
+        me.sign(&keypair);
         let mut g = Crdt {
             table: HashMap::new(),
             local: HashMap::new(),
             remote: HashMap::new(),
+            alive: HashMap::new(),
+            purge_timeout_ms: DEFAULT_PURGE_TIMEOUT_MS,
+            stakes: HashMap::new(),
+            repairs: HashMap::new(),
+            fanout: DEFAULT_FANOUT,
+            erasure: ErasureConfig::default(),
             me: me.id,
+            keypair,
             update_index: 1,
             timeout: Duration::new(0, 100_000),
         };
@@ -126,6 +246,10 @@ impl Crdt {
     pub fn my_data(&self) -> &ReplicatedData {
         &self.table[&self.me]
     }
+    /// The identity that signs my gossip, and my blobs when I'm the leader
+    pub fn keypair(&self) -> Arc<KeyPair> {
+        self.keypair.clone()
+    }
     pub fn leader_data(&self) -> &ReplicatedData {
         &self.table[&self.table[&self.me].current_leader_id]
     }
@@ -134,10 +258,17 @@ impl Crdt {
         let mut me = self.my_data().clone();
         me.current_leader_id = key;
         me.version += 1;
+        me.sign(&self.keypair);
         self.insert(me);
     }
 
+    /// Insert `v` if it is newer than what's in the table. Data that wasn't
+    /// signed by its `id` is dropped.
     pub fn insert(&mut self, v: ReplicatedData) {
+        if !v.verify() {
+            warn!("dropping ReplicatedData with a bad signature, id: {:?}", v.id[0]);
+            return;
+        }
         // TODO check that last_verified types are always increasing
         if self.table.get(&v.id).is_none() || (v.version > self.table[&v.id].version) {
             //somehow we signed a message for our own identity with a higher version that
@@ -148,6 +279,9 @@ impl Crdt {
             self.update_index += 1;
             let _ = self.table.insert(v.id.clone(), v.clone());
             let _ = self.local.insert(v.id, self.update_index);
+            // A new node gets one purge timeout to be heard from directly. Newer
+            // versions relayed by other nodes don't keep it alive.
+            self.alive.entry(v.id).or_insert_with(timestamp);
         } else {
             trace!(
                 "INSERT FAILED new.version: {} me.version: {}",
@@ -157,89 +291,173 @@ impl Crdt {
         }
     }
 
+    /// Return the ids of all the nodes in the table.
+    pub fn node_ids(&self) -> Vec<PublicKey> {
+        self.table.keys().cloned().collect()
+    }
+
+    /// Replace the stake table, either from config or the Accountant's balances.
+    pub fn set_stakes(&mut self, stakes: HashMap<PublicKey, u64>) {
+        self.stakes = stakes;
+    }
+
+    /// Selection weight of the node `id`. Nodes without stake get the smallest
+    /// weight rather than none, so they are still contacted once in a while.
+    fn weight(&self, id: &PublicKey) -> u64 {
+        max(1, *self.stakes.get(id).unwrap_or(&0))
+    }
+
+    /// Note that the node `id` sent a message at `now`. Only nodes in the table are
+    /// tracked, so messages signed with made up keys can't grow `alive`.
+    fn heard_from(&mut self, id: &PublicKey, now: u64) {
+        if self.table.contains_key(id) {
+            self.alive.insert(*id, now);
+        }
+    }
+
+    /// Return true if the node `id` has been heard from within `purge_timeout_ms` of `now`.
+    /// This node is always live.
+    fn is_live(&self, id: &PublicKey, now: u64) -> bool {
+        *id == self.me || self.alive
+            .get(id)
+            .map_or(false, |t| now.saturating_sub(*t) <= self.purge_timeout_ms)
+    }
+
+    /// Remove the nodes that haven't been heard from within `purge_timeout_ms` of `now`.
+    /// The leader is never purged, since there is nothing to fail over to.
+    pub fn purge(&mut self, now: u64) {
+        let leader_id = self.table[&self.me].current_leader_id;
+        let dead: Vec<PublicKey> = self.table
+            .keys()
+            .filter(|id| **id != leader_id && !self.is_live(id, now))
+            .cloned()
+            .collect();
+        for id in dead {
+            info!("purging {:?}", id[0]);
+            self.table.remove(&id);
+            self.local.remove(&id);
+            self.remote.remove(&id);
+            self.alive.remove(&id);
+            self.repairs.remove(&id);
+        }
+    }
+
+    /// The nodes in the broadcast tree: everyone but the leader that is listening for blobs,
+    /// sorted by id. The tree is built from the table rather than from this node's view of
+    /// who is live, so that every node builds the same one.
+    fn tree_nodes(&self) -> Vec<ReplicatedData> {
+        let leader_id = self.table[&self.me].current_leader_id;
+        let daddr = "0.0.0.0:0".parse().unwrap();
+        let mut nodes: Vec<_> = self.table
+            .values()
+            .filter(|v| v.id != leader_id && v.replicate_addr != daddr)
+            .cloned()
+            .collect();
+        nodes.sort_by_key(|v| v.id);
+        nodes
+    }
+
+    /// Return the nodes this node sends the blob at `index` to. Layer 1 is the first `fanout`
+    /// tree nodes, and the leader stripes its blobs across it: the blob goes to layer 1 node
+    /// `index % n`, where `n` is the size of layer 1, which passes it to the rest of layer 1.
+    /// Layer 1 node `i` retransmits every blob to the layer 2 nodes `j` where
+    /// `j % fanout == i`. Layer 2 nodes don't retransmit. Nodes that aren't live as of `now`
+    /// are skipped, and if the blob's layer 1 node is one of them, the leader sends the blob
+    /// to the rest of layer 1 itself.
+    pub fn broadcast_peers(&self, index: u64, now: u64) -> Vec<ReplicatedData> {
+        let fanout = max(1, self.fanout);
+        let nodes = self.tree_nodes();
+        let layer1 = min(fanout, nodes.len());
+        if layer1 == 0 {
+            return vec![];
+        }
+        let owner = (index % layer1 as u64) as usize;
+        let peers = if self.me == self.table[&self.me].current_leader_id {
+            if self.is_live(&nodes[owner].id, now) {
+                vec![nodes[owner].clone()]
+            } else {
+                nodes[..layer1].to_vec()
+            }
+        } else {
+            match nodes.iter().position(|v| v.id == self.me) {
+                Some(i) if i < fanout => {
+                    let mut peers: Vec<_> = nodes
+                        .iter()
+                        .skip(fanout)
+                        .enumerate()
+                        .filter(|&(j, _)| j % fanout == i)
+                        .map(|(_, v)| v.clone())
+                        .collect();
+                    if i == owner {
+                        peers.extend(nodes[..layer1].iter().filter(|v| v.id != self.me).cloned());
+                    }
+                    peers
+                }
+                _ => vec![],
+            }
+        };
+        peers
+            .into_iter()
+            .filter(|v| self.is_live(&v.id, now))
+            .collect()
+    }
+
     /// broadcast messages from the leader to layer 1 nodes
     /// # Remarks
+    /// The blobs should already carry the leader's id and their index.
     /// We need to avoid having obj locked while doing any io, such as the `send_to`
     pub fn broadcast(
         obj: &Arc<RwLock<Self>>,
         blobs: &Vec<SharedBlob>,
         s: &UdpSocket,
-        transmit_index: &mut u64,
     ) -> Result<()> {
-        let (me, table): (ReplicatedData, Vec<ReplicatedData>) = {
+        let (me, orders) = {
             // copy to avoid locking durring IO
+            // and send to the nodes with the most stake first
             let robj = obj.read().expect("'obj' read lock in pub fn broadcast");
-            let cloned_table: Vec<ReplicatedData> = robj.table.values().cloned().collect();
-            (robj.table[&robj.me].clone(), cloned_table)
+            let now = timestamp();
+            let mut orders = vec![];
+            for b in blobs {
+                let index = b.read().expect("'b' read lock in pub fn broadcast").get_index()?;
+                let mut peers = robj.broadcast_peers(index, now);
+                peers.sort_by(|x, y| robj.weight(&y.id).cmp(&robj.weight(&x.id)));
+                orders.extend(peers.into_iter().map(|v| (b, v)));
+            }
+            (robj.table[&robj.me].clone(), orders)
         };
-        let daddr = "0.0.0.0:0".parse().unwrap();
-        let items: Vec<(usize, &ReplicatedData)> = table
-            .iter()
-            .filter(|v| {
-                if me.id == v.id {
-                    //filter myself
-                    false
-                } else if v.replicate_addr == daddr {
-                    //filter nodes that are not listening
-                    false
-                } else {
-                    true
-                }
-            })
-            .enumerate()
-            .collect();
-        let orders: Vec<_> = items.into_iter().cycle().zip(blobs.iter()).collect();
+        if orders.is_empty() {
+            trace!("no layer 1 nodes to broadcast to");
+            return Ok(());
+        }
         let errs: Vec<_> = orders
             .into_par_iter()
-            .map(|((i, v), b)| {
+            .map(|(b, v)| {
                 // only leader should be broadcasting
                 assert!(me.current_leader_id != v.id);
-                let mut blob = b.write().expect("'b' write lock in pub fn broadcast");
-                blob.set_id(me.id).expect("set_id in pub fn broadcast");
-                blob.set_index(*transmit_index + i as u64)
-                    .expect("set_index in pub fn broadcast");
+                let blob = b.read().expect("'b' read lock in pub fn broadcast");
                 //TODO profile this, may need multiple sockets for par_iter
                 s.send_to(&blob.data[..blob.meta.size], &v.replicate_addr)
             })
             .collect();
         for e in errs {
-            trace!("retransmit result {:?}", e);
+            trace!("broadcast result {:?}", e);
             match e {
                 Err(e) => return Err(Error::IO(e)),
                 _ => (),
             }
-            *transmit_index += 1;
         }
         Ok(())
     }
 
-    /// retransmit messages from the leader to layer 1 nodes
+    /// retransmit messages from the leader to this node's layer 2 neighborhood
     /// # Remarks
     /// We need to avoid having obj locked while doing any io, such as the `send_to`
     pub fn retransmit(obj: &Arc<RwLock<Self>>, blob: &SharedBlob, s: &UdpSocket) -> Result<()> {
-        let (me, table): (ReplicatedData, Vec<ReplicatedData>) = {
-            // copy to avoid locking durring IO
-            let s = obj.read().expect("'obj' read lock in pub fn retransmit");
-            (s.table[&s.me].clone(), s.table.values().cloned().collect())
-        };
+        // copy to avoid locking durring IO
         let rblob = blob.read().expect("'blob' read lock in pub fn retransmit");
-        let daddr = "0.0.0.0:0".parse().unwrap();
-        let orders: Vec<_> = table
-            .iter()
-            .filter(|v| {
-                if me.id == v.id {
-                    false
-                } else if me.current_leader_id == v.id {
-                    trace!("skip retransmit to leader {:?}", v.id);
-                    false
-                } else if v.replicate_addr == daddr {
-                    trace!("skip nodes that are not listening {:?}", v.id);
-                    false
-                } else {
-                    true
-                }
-            })
-            .collect();
+        let orders = obj.read()
+            .expect("'obj' read lock in pub fn retransmit")
+            .broadcast_peers(rblob.get_index()?, timestamp());
         let errs: Vec<_> = orders
             .par_iter()
             .map(|v| {
@@ -278,37 +496,63 @@ impl Crdt {
         (id, ups, data)
     }
 
-    /// Create a random gossip request
+    /// Create a random gossip request to a node picked with probability proportional to its stake
     /// # Returns
     /// (A,B)
     /// * A - Address to send to
-    /// * B - RequestUpdates protocol message
-    fn gossip_request(&self) -> Result<(SocketAddr, Protocol)> {
-        if self.table.len() <= 1 {
+    /// * B - signed RequestUpdates protocol message
+    fn gossip_request(&self) -> Result<(SocketAddr, SignedProtocol)> {
+        let options: Vec<&ReplicatedData> =
+            self.table.values().filter(|v| v.id != self.me).collect();
+        if options.is_empty() {
             return Err(Error::GeneralError);
         }
-        let mut n = (Self::random() as usize) % self.table.len();
-        while self.table
-            .values()
-            .nth(n)
-            .expect("'values().nth(n)' while loop in fn gossip_request")
-            .id == self.me
-        {
-            n = (Self::random() as usize) % self.table.len();
-        }
-        let v = self.table
-            .values()
-            .nth(n)
-            .expect("'values().nth(n)' in fn gossip_request")
-            .clone();
+        let weights: Vec<u64> = options.iter().map(|v| self.weight(&v.id)).collect();
+        let v = options[weighted_choice(&weights, Self::random())];
         let remote_update_index = *self.remote.get(&v.id).unwrap_or(&0);
         let req = Protocol::RequestUpdates(remote_update_index, self.table[&self.me].clone());
-        Ok((v.gossip_addr, req))
+        Ok((v.gossip_addr, SignedProtocol::new(req, &self.keypair)))
+    }
+
+    /// Create a request for the blob at index `ix` to a random node
+    /// # Returns
+    /// (A,B)
+    /// * A - Address to send to
+    /// * B - serialized and signed RequestWindowIndex protocol message
+    pub fn window_index_request(&self, ix: u64) -> Result<(SocketAddr, Vec<u8>)> {
+        let daddr = "0.0.0.0:0".parse().unwrap();
+        let options: Vec<&ReplicatedData> = self.table
+            .values()
+            .filter(|v| v.id != self.me && v.gossip_addr != daddr)
+            .collect();
+        if options.is_empty() {
+            return Err(Error::GeneralError);
+        }
+        let v = options[(Self::random() as usize) % options.len()];
+        let req = Protocol::RequestWindowIndex(self.me, ix);
+        let out = serialize(&SignedProtocol::new(req, &self.keypair))?;
+        Ok((v.gossip_addr, out))
+    }
+
+    /// Return where to send a repair requested by the node `id` at `now`. Only nodes in the
+    /// table are answered, at the address they signed there, so a forged request can't aim
+    /// blobs at someone else. Each node gets at most `MAX_REPAIRS_PER_SECOND`.
+    fn repair_addr(&mut self, id: &PublicKey, now: u64) -> Option<SocketAddr> {
+        let addr = self.table.get(id)?.replicate_addr;
+        let second = now / 1000;
+        let repairs = self.repairs.entry(*id).or_insert((second, 0));
+        if repairs.0 != second {
+            *repairs = (second, 0);
+        }
+        repairs.1 += 1;
+        if repairs.1 > MAX_REPAIRS_PER_SECOND {
+            return None;
+        }
+        Some(addr)
     }
 
     /// At random pick a node and try to get updated changes from them
     fn run_gossip(obj: &Arc<RwLock<Self>>) -> Result<()> {
-        //TODO we need to keep track of stakes and weight the selection by stake size
         //TODO cache sockets
 
         // Lock the object only to do this operation and not for any longer
@@ -332,17 +576,21 @@ impl Crdt {
     fn apply_updates(&mut self, from: PublicKey, update_index: u64, data: &[ReplicatedData]) {
         trace!("got updates {}", data.len());
         // TODO we need to punish/spam resist here
-        // sig verify the whole update and slash anyone who sends a bad update
+        // slash anyone who sends a bad update, `insert` drops the data with bad signatures
         for v in data {
             self.insert(v.clone());
         }
         *self.remote.entry(from).or_insert(update_index) = update_index;
     }
 
-    /// randomly pick a node and ask them for updates asynchronously
+    /// randomly pick a node and ask them for updates asynchronously, and purge
+    /// the nodes that have gone silent
     pub fn gossip(obj: Arc<RwLock<Self>>, exit: Arc<AtomicBool>) -> JoinHandle<()> {
         spawn(move || loop {
             let _ = Self::run_gossip(&obj);
+            obj.write()
+                .expect("'obj' write lock in pub fn gossip")
+                .purge(timestamp());
             if exit.load(Ordering::Relaxed) {
                 return;
             }
@@ -356,29 +604,39 @@ impl Crdt {
     }
 
     /// Process messages from the network
-    fn run_listen(obj: &Arc<RwLock<Self>>, sock: &UdpSocket) -> Result<()> {
+    fn run_listen(obj: &Arc<RwLock<Self>>, window: &Window, sock: &UdpSocket) -> Result<()> {
         //TODO cache connections
         let mut buf = vec![0u8; 1024 * 64];
         let (amt, src) = sock.recv_from(&mut buf)?;
         trace!("got request from {}", src);
         buf.resize(amt, 0);
-        let r = deserialize(&buf)?;
-        match r {
-            // TODO sigverify these
+        let r: SignedProtocol = deserialize(&buf)?;
+        if !r.verify() {
+            warn!("dropping gossip message with a bad signature from {}", src);
+            return Err(Error::GeneralError);
+        }
+        obj.write()
+            .expect("'obj' write lock in run_listen")
+            .heard_from(r.msg.from(), timestamp());
+        match r.msg {
             Protocol::RequestUpdates(v, reqdata) => {
                 trace!("RequestUpdates {}", v);
                 let addr = reqdata.gossip_addr;
                 // only lock for this call, dont lock durring IO `sock.send_to` or `sock.recv_from`
-                let (from, ups, data) = obj.read()
-                    .expect("'obj' read lock in RequestUpdates")
-                    .get_updates_since(v);
-                trace!("get updates since response {} {}", v, data.len());
-                let rsp = serialize(&Protocol::ReceiveUpdates(from, ups, data))?;
+                let rsp = {
+                    let robj = obj.read().expect("'obj' read lock in RequestUpdates");
+                    let (from, ups, data) = robj.get_updates_since(v);
+                    trace!("get updates since response {} {}", v, data.len());
+                    let msg = Protocol::ReceiveUpdates(from, ups, data);
+                    serialize(&SignedProtocol::new(msg, &robj.keypair))?
+                };
                 trace!("send_to {}", addr);
-                //TODO verify reqdata belongs to sender
-                obj.write()
-                    .expect("'obj' write lock in RequestUpdates")
-                    .insert(reqdata);
+                {
+                    let mut wobj = obj.write().expect("'obj' write lock in RequestUpdates");
+                    let id = reqdata.id;
+                    wobj.insert(reqdata);
+                    wobj.heard_from(&id, timestamp());
+                }
                 sock.send_to(&rsp, addr)
                     .expect("'sock.send_to' in RequestUpdates");
                 trace!("send_to done!");
@@ -389,18 +647,48 @@ impl Crdt {
                     .expect("'obj' write lock in ReceiveUpdates")
                     .apply_updates(from, ups, &data);
             }
+            Protocol::RequestWindowIndex(from, ix) => {
+                let addr = obj.write()
+                    .expect("'obj' write lock in RequestWindowIndex")
+                    .repair_addr(&from, timestamp());
+                let addr = match addr {
+                    Some(addr) => addr,
+                    None => {
+                        trace!("not repairing window index {} for {:?}", ix, from[0]);
+                        return Ok(());
+                    }
+                };
+                // hold the window lock so the blob can't be recycled while it's sent
+                let window = window.read().expect("'window' read lock in RequestWindowIndex");
+                let w = (ix as usize) % window.len();
+                if let Some(ref blob) = window[w] {
+                    let rblob = blob.read().expect("'blob' read lock in RequestWindowIndex");
+                    if rblob.is_recovered() {
+                        // it wouldn't verify, the sender has to wait for the leader's copy
+                        trace!("not repairing recovered window index {}", ix);
+                    } else if rblob.get_index()? == ix {
+                        trace!("repairing window index {} for {}", ix, addr);
+                        sock.send_to(&rblob.data[..rblob.meta.size], addr)?;
+                    } else {
+                        trace!("window index {} is no longer retained", ix);
+                    }
+                }
+            }
         }
         Ok(())
     }
+
+    /// Listen for gossip, and answer requests for blobs from `window`
     pub fn listen(
         obj: Arc<RwLock<Self>>,
+        window: Window,
         sock: UdpSocket,
         exit: Arc<AtomicBool>,
     ) -> JoinHandle<()> {
         sock.set_read_timeout(Some(Duration::new(2, 0)))
             .expect("'sock.set_read_timeout' in crdt.rs");
         spawn(move || loop {
-            let _ = Self::run_listen(&obj, &sock);
+            let _ = Self::run_listen(&obj, &window, &sock);
             if exit.load(Ordering::Relaxed) {
                 return;
             }
@@ -410,30 +698,35 @@ impl Crdt {
 
 #[cfg(test)]
 mod test {
-    use crdt::{Crdt, ReplicatedData};
+    use bincode::{deserialize, serialize};
+    use crdt::{weighted_choice, Crdt, Protocol, ReplicatedData, SignedProtocol,
+               MAX_REPAIRS_PER_SECOND};
     use logger;
     use packet::Blob;
     use rayon::iter::*;
     use signature::KeyPair;
-    use signature::KeyPairUtil;
+    use signature::{KeyPairUtil, PublicKey};
+    use std::collections::HashMap;
     use std::net::UdpSocket;
     use std::sync::atomic::{AtomicBool, Ordering};
     use std::sync::{Arc, RwLock};
     use std::thread::{sleep, JoinHandle};
     use std::time::Duration;
+    use streamer::default_window;
+    use timing::timestamp;
 
     fn test_node() -> (Crdt, UdpSocket, UdpSocket, UdpSocket) {
         let gossip = UdpSocket::bind("0.0.0.0:0").unwrap();
         let replicate = UdpSocket::bind("0.0.0.0:0").unwrap();
         let serve = UdpSocket::bind("0.0.0.0:0").unwrap();
-        let pubkey = KeyPair::new().pubkey();
+        let keypair = KeyPair::new();
         let d = ReplicatedData::new(
-            pubkey,
+            keypair.pubkey(),
             gossip.local_addr().unwrap(),
             replicate.local_addr().unwrap(),
             serve.local_addr().unwrap(),
         );
-        let crdt = Crdt::new(d);
+        let crdt = Crdt::new(d, Arc::new(keypair));
         trace!(
             "id: {} gossip: {} replicate: {} serve: {}",
             crdt.my_data().id[0],
@@ -458,7 +751,7 @@ mod test {
             .map(|_| {
                 let (crdt, gossip, _, _) = test_node();
                 let c = Arc::new(RwLock::new(crdt));
-                let l = Crdt::listen(c.clone(), gossip, exit.clone());
+                let l = Crdt::listen(c.clone(), default_window(), gossip, exit.clone());
                 (c, l)
             })
             .collect();
@@ -539,23 +832,256 @@ mod test {
     /// Test that insert drops messages that are older
     #[test]
     fn insert_test() {
+        let keypair = Arc::new(KeyPair::new());
         let mut d = ReplicatedData::new(
-            KeyPair::new().pubkey(),
+            keypair.pubkey(),
             "127.0.0.1:1234".parse().unwrap(),
             "127.0.0.1:1235".parse().unwrap(),
             "127.0.0.1:1236".parse().unwrap(),
         );
         assert_eq!(d.version, 0);
-        let mut crdt = Crdt::new(d.clone());
+        let mut crdt = Crdt::new(d.clone(), keypair.clone());
         assert_eq!(crdt.table[&d.id].version, 0);
         d.version = 2;
+        d.sign(&keypair);
         crdt.insert(d.clone());
         assert_eq!(crdt.table[&d.id].version, 2);
         d.version = 1;
+        d.sign(&keypair);
         crdt.insert(d.clone());
         assert_eq!(crdt.table[&d.id].version, 2);
     }
 
+    /// Test that insert drops data that isn't signed by its id
+    #[test]
+    fn insert_bad_sig_test() {
+        let (mut crdt, _, _, _) = test_node();
+        let keypair = KeyPair::new();
+        let mut d = ReplicatedData::new(
+            keypair.pubkey(),
+            "127.0.0.1:1234".parse().unwrap(),
+            "127.0.0.1:1235".parse().unwrap(),
+            "127.0.0.1:1236".parse().unwrap(),
+        );
+        crdt.insert(d.clone());
+        assert!(crdt.table.get(&d.id).is_none());
+
+        d.sign(&keypair);
+        crdt.insert(d.clone());
+        assert_eq!(crdt.table[&d.id].version, 0);
+
+        // Forge a newer version with someone else's key.
+        d.version = 1;
+        d.serve_addr = "127.0.0.1:6666".parse().unwrap();
+        d.sign(&KeyPair::new());
+        crdt.insert(d.clone());
+        assert_eq!(crdt.table[&d.id].version, 0);
+        assert_eq!(crdt.table[&d.id].serve_addr, "127.0.0.1:1236".parse().unwrap());
+    }
+
+    /// Test that silent nodes are purged, but never this node or the leader
+    #[test]
+    fn purge_test() {
+        let (mut crdt, _, _, _) = test_node();
+        let (leader, _, _, _) = test_node();
+        let (peer, _, _, _) = test_node();
+        let leader_data = leader.my_data().clone();
+        let peer_data = peer.my_data().clone();
+        crdt.insert(leader_data.clone());
+        crdt.insert(peer_data.clone());
+        crdt.set_leader(leader_data.id);
+        crdt.remote.insert(peer_data.id, 1);
+        let now = crdt.alive[&peer_data.id];
+        crdt.alive.insert(leader_data.id, now);
+
+        crdt.purge(now + crdt.purge_timeout_ms);
+        assert_eq!(crdt.table.len(), 3);
+        let num_live =
+            |crdt: &Crdt, now| crdt.table.keys().filter(|id| crdt.is_live(id, now)).count();
+        assert_eq!(num_live(&crdt, now + crdt.purge_timeout_ms), 3);
+
+        // The peer is heard from again, but the leader goes silent.
+        crdt.alive.insert(peer_data.id, now + 100);
+        let later = now + crdt.purge_timeout_ms + 1;
+        assert!(crdt.is_live(&peer_data.id, later));
+        assert!(!crdt.is_live(&leader_data.id, later));
+        assert!(crdt.is_live(&crdt.me, later));
+        crdt.purge(later);
+        assert_eq!(crdt.table.len(), 3);
+
+        let much_later = later + 100;
+        assert_eq!(num_live(&crdt, much_later), 1);
+        crdt.purge(much_later);
+        assert_eq!(crdt.table.len(), 2);
+        assert!(crdt.table.get(&peer_data.id).is_none());
+        assert!(crdt.local.get(&peer_data.id).is_none());
+        assert!(crdt.remote.get(&peer_data.id).is_none());
+        assert!(crdt.table.get(&leader_data.id).is_some());
+    }
+
+    /// Test that repairs only go to nodes in the table, at their own address, and
+    /// are rate limited
+    #[test]
+    fn repair_addr_test() {
+        let (mut crdt, _, _, _) = test_node();
+        let (peer, _, _, _) = test_node();
+        let peer_data = peer.my_data().clone();
+        assert_eq!(crdt.repair_addr(&peer_data.id, 0), None);
+
+        crdt.insert(peer_data.clone());
+        for _ in 0..MAX_REPAIRS_PER_SECOND {
+            assert_eq!(crdt.repair_addr(&peer_data.id, 0), Some(peer_data.replicate_addr));
+        }
+        assert_eq!(crdt.repair_addr(&peer_data.id, 999), None);
+        assert_eq!(crdt.repair_addr(&peer_data.id, 1000), Some(peer_data.replicate_addr));
+    }
+
+    /// Test that only a node's own messages keep it alive
+    #[test]
+    fn liveness_test() {
+        let (mut crdt, _, _, _) = test_node();
+        let (peer, _, _, _) = test_node();
+        let mut peer_data = peer.my_data().clone();
+        crdt.insert(peer_data.clone());
+        assert!(crdt.alive.get(&peer_data.id).is_some());
+
+        // A newer version relayed by another node doesn't refresh it.
+        crdt.alive.insert(peer_data.id, 0);
+        peer_data.version += 1;
+        peer_data.sign(&peer.keypair);
+        crdt.insert(peer_data.clone());
+        assert_eq!(crdt.table[&peer_data.id].version, 1);
+        assert_eq!(crdt.alive[&peer_data.id], 0);
+
+        crdt.heard_from(&peer_data.id, 5);
+        assert_eq!(crdt.alive[&peer_data.id], 5);
+
+        // Keys that aren't in the table aren't tracked.
+        let num_alive = crdt.alive.len();
+        crdt.heard_from(&KeyPair::new().pubkey(), 5);
+        assert_eq!(crdt.alive.len(), num_alive);
+    }
+
+    /// Send the blob at `index` from the leader at `nodes[0]` down the broadcast tree, and
+    /// return how many times each node received it.
+    fn deliver(nodes: &[Crdt], index: u64, now: u64) -> HashMap<PublicKey, usize> {
+        let mut received = HashMap::new();
+        let mut senders = vec![0];
+        while let Some(i) = senders.pop() {
+            for v in nodes[i].broadcast_peers(index, now) {
+                let count = received.entry(v.id).or_insert(0);
+                *count += 1;
+                if *count == 1 {
+                    senders.push(nodes.iter().position(|c| c.me == v.id).unwrap());
+                }
+            }
+        }
+        received
+    }
+
+    /// Test that the leader sends each blob to one layer 1 node, and every other
+    /// node gets it exactly once
+    #[test]
+    fn broadcast_tree_test() {
+        let mut nodes: Vec<_> = (0..8).map(|_| test_node().0).collect();
+        let table: Vec<_> = nodes.iter().map(|c| c.my_data().clone()).collect();
+        let leader_id = table[0].id;
+        for crdt in nodes.iter_mut() {
+            for v in &table {
+                crdt.insert(v.clone());
+            }
+            crdt.set_leader(leader_id);
+            crdt.fanout = 3;
+        }
+        let now = timestamp();
+        let mut layer1 = vec![];
+        for index in 0..6 {
+            let peers = nodes[0].broadcast_peers(index, now);
+            assert_eq!(peers.len(), 1);
+            layer1.push(peers[0].id);
+
+            let received = deliver(&nodes, index, now);
+            assert_eq!(received.len(), table.len() - 1);
+            assert!(received.values().all(|x| *x == 1));
+        }
+        // The blobs are striped across all of layer 1.
+        layer1.sort();
+        layer1.dedup();
+        assert_eq!(layer1.len(), 3);
+
+        // Every node builds the same tree, even if it thinks some nodes are dead.
+        let dead = nodes[0].broadcast_peers(0, now)[0].id;
+        nodes[0].alive.insert(dead, 0);
+        let tree: Vec<_> = nodes[1].tree_nodes().into_iter().map(|v| v.id).collect();
+        assert_eq!(
+            nodes[0].tree_nodes().into_iter().map(|v| v.id).collect::<Vec<_>>(),
+            tree
+        );
+
+        // The leader skips the dead node and sends to the rest of layer 1 itself.
+        let peers = nodes[0].broadcast_peers(0, now);
+        assert_eq!(peers.len(), 2);
+        assert!(peers.iter().all(|v| v.id != dead));
+        let received = deliver(&nodes, 0, now);
+        assert!(received.get(&dead).is_none());
+        assert!(received.values().all(|x| *x == 1));
+    }
+
+    #[test]
+    fn weighted_choice_test() {
+        let weights = [1, 0, 3];
+        let picks: Vec<_> = (0..8).map(|r| weighted_choice(&weights, r)).collect();
+        assert_eq!(picks, [0, 2, 2, 2, 0, 2, 2, 2]);
+    }
+
+    /// Test that gossip requests go to high-stake nodes more often
+    #[test]
+    fn gossip_stake_test() {
+        let (mut crdt, _, _, _) = test_node();
+        let (low, _, _, _) = test_node();
+        let (high, _, _, _) = test_node();
+        let low_data = low.my_data().clone();
+        let high_data = high.my_data().clone();
+        crdt.insert(low_data.clone());
+        crdt.insert(high_data.clone());
+        let mut stakes = HashMap::new();
+        stakes.insert(low_data.id, 1);
+        stakes.insert(high_data.id, 9);
+        crdt.set_stakes(stakes);
+
+        let mut num_high = 0;
+        let num_requests = 1000;
+        for _ in 0..num_requests {
+            let (addr, _) = crdt.gossip_request().unwrap();
+            assert_ne!(addr, crdt.my_data().gossip_addr);
+            if addr == high_data.gossip_addr {
+                num_high += 1;
+            }
+        }
+        // expect 900
+        assert!(num_high > 800);
+    }
+
+    /// Test that a spoofed ReceiveUpdates fails verification
+    #[test]
+    fn signed_protocol_test() {
+        let (crdt, _, _, _) = test_node();
+        let (from, ups, data) = crdt.get_updates_since(0);
+        let msg = SignedProtocol::new(Protocol::ReceiveUpdates(from, ups, data), &crdt.keypair);
+        assert!(msg.verify());
+
+        let mut msg: SignedProtocol = deserialize(&serialize(&msg).unwrap()).unwrap();
+        assert!(msg.verify());
+        if let Protocol::ReceiveUpdates(_, ref mut ups, _) = msg.msg {
+            *ups = 1_000_000;
+        }
+        assert!(!msg.verify());
+
+        let (from, ups, data) = crdt.get_updates_since(0);
+        let msg = SignedProtocol::new(Protocol::ReceiveUpdates(from, ups, data), &KeyPair::new());
+        assert!(!msg.verify());
+    }
+
     #[test]
     pub fn test_crdt_retransmit() {
         logger::setup();
@@ -578,13 +1104,13 @@ mod test {
 
         // Create listen threads
         let a1 = Arc::new(RwLock::new(c1));
-        let t1 = Crdt::listen(a1.clone(), s1, exit.clone());
+        let t1 = Crdt::listen(a1.clone(), default_window(), s1, exit.clone());
 
         let a2 = Arc::new(RwLock::new(c2));
-        let t2 = Crdt::listen(a2.clone(), s2, exit.clone());
+        let t2 = Crdt::listen(a2.clone(), default_window(), s2, exit.clone());
 
         let a3 = Arc::new(RwLock::new(c3));
-        let t3 = Crdt::listen(a3.clone(), s3, exit.clone());
+        let t3 = Crdt::listen(a3.clone(), default_window(), s3, exit.clone());
 
         // Create gossip threads
         let t1_gossip = Crdt::gossip(a1.clone(), exit.clone());
@@ -605,19 +1131,29 @@ mod test {
         assert!(done);
         let mut b = Blob::default();
         b.meta.size = 10;
-        Crdt::retransmit(&a1, &Arc::new(RwLock::new(b)), &e1).unwrap();
-        let res: Vec<_> = [r1, r2, r3]
-            .into_par_iter()
-            .map(|s| {
-                let mut b = Blob::default();
-                s.set_read_timeout(Some(Duration::new(1, 0))).unwrap();
-                let res = s.recv_from(&mut b.data);
-                res.is_err() //true if failed to receive the retransmit packet
-            })
-            .collect();
-        //true if failed receive the retransmit packet, r2, and r3 should succeed
+        let blob = Arc::new(RwLock::new(b));
+        //true if failed to receive the retransmit packet
+        let receive = |socks: &[&UdpSocket]| -> Vec<bool> {
+            socks
+                .par_iter()
+                .map(|s| {
+                    let mut b = Blob::default();
+                    s.set_read_timeout(Some(Duration::new(1, 0))).unwrap();
+                    s.recv_from(&mut b.data).is_err()
+                })
+                .collect()
+        };
+        //the leader sends the blob to one layer 1 node
         //r1 was the sender, so it should fail to receive the packet
-        assert_eq!(res, [true, false, false]);
+        Crdt::retransmit(&a1, &blob, &e1).unwrap();
+        let res = receive(&[&r1, &r2, &r3]);
+        assert!(res[0]);
+        assert_eq!(res.iter().filter(|x| !**x).count(), 1);
+
+        //which passes it to the rest of layer 1
+        let (owner, other) = if !res[1] { (&a2, &r3) } else { (&a3, &r2) };
+        Crdt::retransmit(owner, &blob, &e1).unwrap();
+        assert_eq!(receive(&[&r1, other]), [true, false]);
         exit.store(true, Ordering::Relaxed);
         let threads = vec![t1, t2, t3, t1_gossip, t2_gossip, t3_gossip];
         for t in threads.into_iter() {
@@ -1,5 +1,10 @@
-use packet::{Packet, SharedPackets};
+use bincode::serialize;
+use event::Event;
+use packet::{Packet, Packets, SharedBlob, SharedPackets, PACKET_DATA_SIZE};
+use rayon::prelude::*;
+use std::collections::VecDeque;
 use std::mem::size_of;
+use std::sync::RwLock;
 use transaction::{PUB_KEY_OFFSET, SIGNED_DATA_OFFSET, SIG_OFFSET};
 
 pub const TX_OFFSET: usize = 4;
@@ -53,8 +58,6 @@ fn verify_packet(packet: &Packet) -> u8 {
 
 #[cfg(not(feature = "cuda"))]
 pub fn ed25519_verify(batches: &Vec<SharedPackets>) -> Vec<Vec<u8>> {
-    use rayon::prelude::*;
-
     batches
         .into_par_iter()
         .map(|p| {
@@ -70,8 +73,6 @@ pub fn ed25519_verify(batches: &Vec<SharedPackets>) -> Vec<Vec<u8>> {
 
 #[cfg(feature = "cuda")]
 pub fn ed25519_verify(batches: &Vec<SharedPackets>) -> Vec<Vec<u8>> {
-    use packet::PACKET_DATA_SIZE;
-
     let mut out = Vec::new();
     let mut elems = Vec::new();
     let mut locks = Vec::new();
@@ -132,11 +133,59 @@ pub fn ed25519_verify(batches: &Vec<SharedPackets>) -> Vec<Vec<u8>> {
     rvs
 }
 
+/// Verify the signatures of a batch of events. Transactions are laid out in packets the
+/// way clients send them and checked together by `ed25519_verify`; the other events, and
+/// any transaction too big for a packet, are checked on their own.
+pub fn ed25519_verify_events<'a, I>(events: I) -> bool
+where
+    I: IntoIterator<Item = &'a Event>,
+{
+    let mut packets = Packets::default();
+    packets.packets.clear();
+    let mut others = vec![];
+    for event in events {
+        if let Event::Transaction(_) = *event {
+            if let Ok(data) = serialize(event) {
+                if data.len() <= PACKET_DATA_SIZE {
+                    let mut packet = Packet::default();
+                    packet.data[..data.len()].copy_from_slice(&data);
+                    packet.meta.size = data.len();
+                    packets.packets.push(packet);
+                    continue;
+                }
+            }
+        }
+        others.push(event);
+    }
+    let batches = vec![SharedPackets::new(RwLock::new(packets))];
+    ed25519_verify(&batches)
+        .iter()
+        .all(|batch| batch.iter().all(|&ok| ok == 1))
+        && others.par_iter().all(|event| event.verify())
+}
+
+/// Verify the signature on each blob against the id in its header, in parallel.
+pub fn ed25519_verify_blobs(blobs: &VecDeque<SharedBlob>) -> Vec<u8> {
+    blobs
+        .par_iter()
+        .map(|b| {
+            b.read()
+                .expect("'b' read lock in ed25519_verify_blobs")
+                .verify() as u8
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use bincode::serialize;
+    use chrono::prelude::*;
     use ecdsa;
-    use packet::{Packet, Packets, SharedPackets};
+    use event::Event;
+    use hash::Hash;
+    use packet::{Packet, Packets, SharedPackets, PACKET_DATA_SIZE};
+    use plan::{Payment, MAX_PAYMENTS};
+    use signature::{KeyPair, KeyPairUtil};
     use std::sync::RwLock;
     use thin_client_service::Request;
     use transaction::Transaction;
@@ -195,4 +244,40 @@ mod tests {
     fn test_verify_fail() {
         test_verify_n(5, true);
     }
+
+    #[test]
+    fn test_verify_multi_payment() {
+        let keypair = KeyPair::new();
+        let payments = (0..MAX_PAYMENTS as i64)
+            .map(|i| Payment {
+                tokens: i,
+                to: KeyPair::new().pubkey(),
+            })
+            .collect();
+        let tr = Transaction::new_multi_payment(&keypair, payments, 0, Hash::default()).unwrap();
+        assert!(serialize(&Request::Transaction(tr.clone())).unwrap().len() <= PACKET_DATA_SIZE);
+
+        let mut packets = Packets::default();
+        packets.packets = vec![make_packet_from_transaction(tr)];
+        let batches = vec![SharedPackets::new(RwLock::new(packets))];
+        assert_eq!(ecdsa::ed25519_verify(&batches), vec![vec![1u8]]);
+    }
+
+    #[test]
+    fn test_verify_events() {
+        let tr = Event::Transaction(test_tx());
+        let ts = Event::new_timestamp(&KeyPair::new(), Utc::now());
+        assert!(ecdsa::ed25519_verify_events(&vec![tr.clone(), ts.clone()]));
+
+        let mut bad_tr = test_tx();
+        bad_tr.data.tokens += 1;
+        let events = vec![Event::Transaction(bad_tr), ts.clone()];
+        assert!(!ecdsa::ed25519_verify_events(&events));
+
+        let mut bad_ts = ts;
+        if let Event::Timestamp { ref mut dt, .. } = bad_ts {
+            *dt = dt.with_year(2000).unwrap();
+        }
+        assert!(!ecdsa::ed25519_verify_events(&vec![tr, bad_ts]));
+    }
 }
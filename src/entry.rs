@@ -57,6 +57,15 @@ fn add_event_data(hash_data: &mut Vec<u8>, event: &Event) {
             hash_data.push(2u8);
             hash_data.extend_from_slice(sig);
         }
+        Event::Preimage { ref sig, .. } => {
+            // The signature covers the preimage, so hashing it commits to both.
+            hash_data.push(3u8);
+            hash_data.extend_from_slice(sig);
+        }
+        Event::Vote { ref sig, .. } => {
+            hash_data.push(4u8);
+            hash_data.extend_from_slice(sig);
+        }
     }
 }
 
@@ -157,13 +166,19 @@ mod tests {
         let keypair = KeyPair::new();
         let tr0 = Event::new_timestamp(&keypair, Utc::now());
         let tr1 = Event::new_signature(&keypair, Default::default());
-        let mut e0 = create_entry(&zero, 0, vec![tr0.clone(), tr1.clone()]);
+        let tr2 = Event::new_preimage(&keypair, Default::default(), vec![0]);
+        let mut e0 = create_entry(&zero, 0, vec![tr0.clone(), tr1.clone(), tr2.clone()]);
         assert!(e0.verify(&zero));
 
         // Next, swap two witness events and ensure verification fails.
         e0.events[0] = tr1; // <-- attack
         e0.events[1] = tr0;
         assert!(!e0.verify(&zero));
+
+        // Swapping a preimage for another witness fails too.
+        e0.events[0] = tr2.clone(); // <-- attack
+        e0.events[1] = tr2;
+        assert!(!e0.verify(&zero));
     }
 
     #[test]
@@ -1,12 +1,16 @@
 // Support erasure coding
+//   Reed-Solomon codes over GF(2^32) built from a Cauchy matrix, either in Rust or
+//   through the C jerasure library with the `jerasure` feature. Both produce the
+//   same coding blocks, so either can decode the other's.
 
-use packet::{BlobRecycler, SharedBlob};
+use packet::{BlobRecycler, SharedBlob, BLOB_FLAGS_END, BLOB_FLAG_IS_CODING,
+             BLOB_FLAG_IS_RECOVERED, BLOB_HEADER_SIZE, BLOB_SIZE, NUM_BLOBS};
+use std::cmp::{max, min};
 use std::result;
 
 //TODO(sakridge) pick these values
-const NUM_CODED: usize = 10;
-const MAX_MISSING: usize = 2;
-const NUM_DATA: usize = NUM_CODED - MAX_MISSING;
+pub const NUM_CODED: usize = 10;
+pub const MAX_MISSING: usize = 2;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ErasureError {
@@ -17,10 +21,57 @@ pub enum ErasureError {
 
 pub type Result<T> = result::Result<T, ErasureError>;
 
+/// How blob indexes are split into erasure sets. Each set spans `num_coded` indexes,
+/// `num_data` data blobs followed by `max_missing` coding blobs, and can be recovered
+/// as long as no more than `max_missing` of its blobs are lost.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErasureConfig {
+    num_coded: usize,
+    max_missing: usize,
+}
+
+impl Default for ErasureConfig {
+    fn default() -> Self {
+        ErasureConfig::new(NUM_CODED, MAX_MISSING)
+    }
+}
+
+impl ErasureConfig {
+    pub fn new(num_coded: usize, max_missing: usize) -> Self {
+        assert!(max_missing < num_coded);
+        // a set must fit in the window to be recovered
+        assert!(num_coded <= NUM_BLOBS);
+        ErasureConfig {
+            num_coded,
+            max_missing,
+        }
+    }
+    pub fn num_coded(&self) -> usize {
+        self.num_coded
+    }
+    pub fn max_missing(&self) -> usize {
+        self.max_missing
+    }
+    pub fn num_data(&self) -> usize {
+        self.num_coded - self.max_missing
+    }
+
+    /// Whether the blob at index `ix` carries erasure codes.
+    pub fn is_coding_index(&self, ix: u64) -> bool {
+        ix % self.num_coded as u64 >= self.num_data() as u64
+    }
+
+    /// The first index of the erasure set holding the blob at index `ix`.
+    pub fn set_start(&self, ix: u64) -> u64 {
+        ix - ix % self.num_coded as u64
+    }
+}
+
 // k = number of data devices
 // m = number of coding devices
 // w = word size
 
+#[cfg(feature = "jerasure")]
 extern "C" {
     fn jerasure_matrix_encode(
         k: i32,
@@ -45,116 +96,296 @@ extern "C" {
     fn galois_single_divide(a: i32, b: i32, w: i32) -> i32;
 }
 
-fn get_matrix(m: i32, k: i32, w: i32) -> Vec<i32> {
+pub const ERASURE_W: i32 = 32;
+
+// x^32 + x^22 + x^2 + x + 1, the polynomial jerasure uses for w = 32
+const GALOIS_PRIM_POLY: u32 = 0x0040_0007;
+
+fn galois_multiply(a: u32, b: u32) -> u32 {
+    let mut a = a;
+    let mut b = b;
+    let mut p = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            p ^= a;
+        }
+        let carry = a & 0x8000_0000 != 0;
+        a <<= 1;
+        if carry {
+            a ^= GALOIS_PRIM_POLY;
+        }
+        b >>= 1;
+    }
+    p
+}
+
+// a^(2^32 - 2) is the inverse of a
+fn galois_inverse(a: u32) -> u32 {
+    let mut r = 1;
+    let mut x = a;
+    let mut e: u32 = 0xffff_fffe;
+    while e != 0 {
+        if e & 1 != 0 {
+            r = galois_multiply(r, x);
+        }
+        x = galois_multiply(x, x);
+        e >>= 1;
+    }
+    r
+}
+
+fn galois_divide(a: u32, b: u32) -> u32 {
+    galois_multiply(a, galois_inverse(b))
+}
+
+// dst ^= c * src, a word at a time, with a table for each byte of the word
+#[cfg_attr(feature = "jerasure", allow(dead_code))]
+fn galois_region_multiply(c: u32, src: &[u8], dst: &mut [u8]) {
+    if c == 0 {
+        return;
+    }
+    let mut tables = vec![[0u32; 256]; 4];
+    for (i, table) in tables.iter_mut().enumerate() {
+        for b in 0..256 {
+            table[b] = galois_multiply(c, (b as u32) << (8 * i));
+        }
+    }
+    for (s, d) in src.chunks(4).zip(dst.chunks_mut(4)) {
+        let p = tables[0][s[0] as usize] ^ tables[1][s[1] as usize] ^ tables[2][s[2] as usize]
+            ^ tables[3][s[3] as usize];
+        for (i, x) in d.iter_mut().enumerate() {
+            *x ^= (p >> (8 * i)) as u8;
+        }
+    }
+}
+
+// Invert the k x k matrix a, None if it is singular
+#[cfg_attr(feature = "jerasure", allow(dead_code))]
+fn invert_matrix(a: &[u32], k: usize) -> Option<Vec<u32>> {
+    let mut a = a.to_vec();
+    let mut inv = vec![0; k * k];
+    for i in 0..k {
+        inv[i * k + i] = 1;
+    }
+    for col in 0..k {
+        let pivot = (col..k).find(|r| a[r * k + col] != 0)?;
+        for j in 0..k {
+            a.swap(col * k + j, pivot * k + j);
+            inv.swap(col * k + j, pivot * k + j);
+        }
+        let scale = galois_inverse(a[col * k + col]);
+        for j in 0..k {
+            a[col * k + j] = galois_multiply(a[col * k + j], scale);
+            inv[col * k + j] = galois_multiply(inv[col * k + j], scale);
+        }
+        for r in 0..k {
+            let f = a[r * k + col];
+            if r == col || f == 0 {
+                continue;
+            }
+            for j in 0..k {
+                a[r * k + j] ^= galois_multiply(f, a[col * k + j]);
+                inv[r * k + j] ^= galois_multiply(f, inv[col * k + j]);
+            }
+        }
+    }
+    Some(inv)
+}
+
+fn get_matrix(m: i32, k: i32, _w: i32) -> Vec<i32> {
     let mut matrix = vec![0; (m * k) as usize];
     for i in 0..m {
         for j in 0..k {
-            unsafe {
-                matrix[(i * k + j) as usize] = galois_single_divide(1, i ^ (m + j), w);
-            }
+            matrix[(i * k + j) as usize] = galois_divide(1, (i ^ (m + j)) as u32) as i32;
         }
     }
     matrix
 }
 
-pub const ERASURE_W: i32 = 32;
+#[cfg_attr(feature = "jerasure", allow(dead_code))]
+fn matrix_encode(k: usize, matrix: &[i32], coding: &mut [&mut [u8]], data: &[&[u8]]) {
+    for (i, block) in coding.iter_mut().enumerate() {
+        for x in block.iter_mut() {
+            *x = 0;
+        }
+        for j in 0..k {
+            galois_region_multiply(matrix[i * k + j] as u32, data[j], block);
+        }
+    }
+}
+
+#[cfg(feature = "jerasure")]
+fn jerasure_encode(k: usize, matrix: &[i32], coding: &mut [&mut [u8]], data: &[&[u8]]) {
+    let data_arg: Vec<*const u8> = data.iter().map(|x| x.as_ptr()).collect();
+    let coding_arg: Vec<*mut u8> = coding.iter_mut().map(|x| x.as_mut_ptr()).collect();
+    unsafe {
+        jerasure_matrix_encode(
+            k as i32,
+            coding.len() as i32,
+            ERASURE_W,
+            matrix.as_ptr(),
+            data_arg.as_ptr(),
+            coding_arg.as_ptr(),
+            data[0].len() as i32,
+        );
+    }
+}
+
+// Solve for the erased data blocks from the first k blocks that survived, data blocks first
+#[cfg_attr(feature = "jerasure", allow(dead_code))]
+fn matrix_decode(
+    k: usize,
+    matrix: &[i32],
+    erasures: &[i32],
+    data: &mut [&mut [u8]],
+    coding: &[&[u8]],
+) -> Result<()> {
+    let erased: Vec<bool> = (0..k + coding.len())
+        .map(|i| erasures.iter().take_while(|e| **e >= 0).any(|e| *e as usize == i))
+        .collect();
+    let rows: Vec<usize> = (0..k + coding.len()).filter(|i| !erased[*i]).take(k).collect();
+    if rows.len() < k {
+        return Err(ErasureError::NotEnoughBlocksToDecode);
+    }
+    let mut survivors = vec![0; k * k];
+    for (r, dev) in rows.iter().enumerate() {
+        if *dev < k {
+            survivors[r * k + dev] = 1;
+        } else {
+            for j in 0..k {
+                survivors[r * k + j] = matrix[(dev - k) * k + j] as u32;
+            }
+        }
+    }
+    let inv = invert_matrix(&survivors, k).ok_or(ErasureError::DecodeError)?;
+    for j in (0..k).filter(|j| erased[*j]) {
+        let mut block = vec![0; data[j].len()];
+        for (r, dev) in rows.iter().enumerate() {
+            let src: &[u8] = if *dev < k { &data[*dev] } else { coding[dev - k] };
+            galois_region_multiply(inv[j * k + r], src, &mut block);
+        }
+        data[j].copy_from_slice(&block);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "jerasure")]
+fn jerasure_decode(
+    k: usize,
+    matrix: &[i32],
+    erasures: &[i32],
+    data: &mut [&mut [u8]],
+    coding: &[&[u8]],
+) -> Result<()> {
+    let coding_arg: Vec<*const u8> = coding.iter().map(|x| x.as_ptr()).collect();
+    let data_arg: Vec<*mut u8> = data.iter_mut().map(|x| x.as_mut_ptr()).collect();
+    unsafe {
+        let ret = jerasure_matrix_decode(
+            k as i32,
+            coding.len() as i32,
+            ERASURE_W,
+            matrix.as_ptr(),
+            0,
+            erasures.as_ptr(),
+            data_arg.as_ptr(),
+            coding_arg.as_ptr(),
+            data[0].len() as i32,
+        );
+        trace!("jerasure_matrix_decode ret: {}", ret);
+        if ret < 0 {
+            return Err(ErasureError::DecodeError);
+        }
+    }
+    Ok(())
+}
 
 // Generate coding blocks into coding
-//   There are some alignment restrictions, blocks should be aligned by 16 bytes
-//   which means their size should be >= 16 bytes
+//   Blocks are processed a 32-bit word at a time, jerasure also wants them
+//   to be a multiple of sizeof(long), so their size should be a multiple of 8
 pub fn generate_coding_blocks(coding: &mut [&mut [u8]], data: &[&[u8]]) -> Result<()> {
     if data.len() == 0 {
         return Ok(());
     }
     let m = coding.len() as i32;
     let block_len = data[0].len();
+    if block_len % 8 != 0 {
+        return Err(ErasureError::InvalidBlockSize);
+    }
     let matrix: Vec<i32> = get_matrix(m, data.len() as i32, ERASURE_W);
-    let mut coding_arg = Vec::new();
-    let mut data_arg = Vec::new();
     for block in data {
         if block_len != block.len() {
             return Err(ErasureError::InvalidBlockSize);
         }
-        data_arg.push(block.as_ptr());
     }
-    for mut block in coding {
+    for block in coding.iter() {
         if block_len != block.len() {
             return Err(ErasureError::InvalidBlockSize);
         }
-        coding_arg.push(block.as_mut_ptr());
-    }
-
-    unsafe {
-        jerasure_matrix_encode(
-            data.len() as i32,
-            m,
-            ERASURE_W,
-            matrix.as_ptr(),
-            data_arg.as_ptr(),
-            coding_arg.as_ptr(),
-            data[0].len() as i32,
-        );
     }
+    #[cfg(not(feature = "jerasure"))]
+    matrix_encode(data.len(), &matrix, coding, data);
+    #[cfg(feature = "jerasure")]
+    jerasure_encode(data.len(), &matrix, coding, data);
     Ok(())
 }
 
 // Recover data + coding blocks into data blocks
 //   data: array of blocks to recover into
 //   coding: arry of coding blocks
-//   erasures: list of indices in data where blocks should be recovered
+//   erasures: list of indices in data where blocks should be recovered, ending with -1,
+//   coding blocks count from data.len(), erased coding blocks aren't recovered
 pub fn decode_blocks(data: &mut [&mut [u8]], coding: &[&[u8]], erasures: &[i32]) -> Result<()> {
     if data.len() == 0 {
         return Ok(());
     }
     let block_len = data[0].len();
+    if block_len % 8 != 0 {
+        return Err(ErasureError::InvalidBlockSize);
+    }
     let matrix: Vec<i32> = get_matrix(coding.len() as i32, data.len() as i32, ERASURE_W);
 
-    // generate coding pointers, blocks should be the same size
-    let mut coding_arg: Vec<*const u8> = Vec::new();
+    // blocks should be the same size
     for x in coding.iter() {
         if x.len() != block_len {
             return Err(ErasureError::InvalidBlockSize);
         }
-        coding_arg.push(x.as_ptr());
     }
-
-    // generate data pointers, blocks should be the same size
-    let mut data_arg: Vec<*mut u8> = Vec::new();
-    for x in data.iter_mut() {
+    for x in data.iter() {
         if x.len() != block_len {
             return Err(ErasureError::InvalidBlockSize);
         }
-        data_arg.push(x.as_mut_ptr());
     }
-    unsafe {
-        let ret = jerasure_matrix_decode(
-            data.len() as i32,
-            coding.len() as i32,
-            ERASURE_W,
-            matrix.as_ptr(),
-            0,
-            erasures.as_ptr(),
-            data_arg.as_ptr(),
-            coding_arg.as_ptr(),
-            data[0].len() as i32,
-        );
-        trace!("jerasure_matrix_decode ret: {}", ret);
-        for x in data[erasures[0] as usize][0..8].iter() {
-            trace!("{} ", x)
-        }
-        trace!("");
-        if ret < 0 {
-            return Err(ErasureError::DecodeError);
+    #[cfg(not(feature = "jerasure"))]
+    return matrix_decode(data.len(), &matrix, erasures, data, coding);
+    #[cfg(feature = "jerasure")]
+    return jerasure_decode(data.len(), &matrix, erasures, data, coding);
+}
+
+// The window slot for index i holds the blob at i, not one from an earlier pass over the window
+fn is_present(window: &Vec<Option<SharedBlob>>, i: usize) -> bool {
+    match window[i % window.len()] {
+        Some(ref b) => {
+            let p = b.read().expect("'b' read lock in fn is_present");
+            p.get_index().ok() == Some(i as u64)
         }
+        None => false,
     }
-    Ok(())
 }
 
-// Generate coding blocks in window from consumed to consumed+NUM_DATA
+// Coding covers the blob from the end of its flags, so that a recovered data blob
+// gets its size back along with its data. Jerasure needs a multiple of sizeof(long).
+fn coded_len(size: usize) -> usize {
+    let len = max(size, BLOB_HEADER_SIZE) - BLOB_FLAGS_END;
+    min((len + 7) & !7, BLOB_SIZE - BLOB_FLAGS_END)
+}
+
+// Generate coding blocks in window for the erasure set from start to start+num_coded,
+//   the data blobs from start to start+num_data should be present
 pub fn generate_coding(
+    config: &ErasureConfig,
     re: &BlobRecycler,
-    window: &mut Vec<SharedBlob>,
-    consumed: usize,
+    window: &mut Vec<Option<SharedBlob>>,
+    start: usize,
 ) -> Result<()> {
     let mut data_blobs = Vec::new();
     let mut coding_blobs = Vec::new();
@@ -162,7 +393,7 @@ pub fn generate_coding(
     let mut data_ptrs: Vec<&[u8]> = Vec::new();
     let mut coding_locks = Vec::new();
     let mut coding_ptrs: Vec<&mut [u8]> = Vec::new();
-    for i in consumed..consumed + NUM_DATA {
+    for i in start..start + config.num_data() {
         let n = i % window.len();
         data_blobs.push(
             window[n]
@@ -173,22 +404,45 @@ pub fn generate_coding(
     for b in &data_blobs {
         data_locks.push(b.write().expect("'b' write lock in pub fn generate_coding"));
     }
-    for (i, l) in data_locks.iter_mut().enumerate() {
+    let block_len = coded_len(data_locks.iter().map(|l| l.meta.size).max().unwrap_or(0));
+    let block_end = BLOB_FLAGS_END + block_len;
+    let mut header = [0u8; BLOB_FLAGS_END];
+    header.copy_from_slice(&data_locks[0].data[..BLOB_FLAGS_END]);
+    for l in data_locks.iter_mut() {
+        let size = l.meta.size;
+        l.set_data_size(size as u64)
+            .expect("set_data_size in pub fn generate_coding");
+        // the padding up to the block length is coded too
+        for x in l.data[max(size, BLOB_HEADER_SIZE)..block_end].iter_mut() {
+            *x = 0;
+        }
+    }
+    for (i, l) in data_locks.iter().enumerate() {
         trace!("i: {} data: {}", i, l.data[0]);
-        data_ptrs.push(&l.data);
+        data_ptrs.push(&l.data[BLOB_FLAGS_END..block_end]);
     }
 
     // generate coding ptr array
-    let coding_start = consumed + NUM_DATA;
-    let coding_end = consumed + NUM_CODED;
+    let coding_start = start + config.num_data();
+    let coding_end = start + config.num_coded();
     for i in coding_start..coding_end {
         let n = i % window.len();
-        window[n] = re.allocate();
-        coding_blobs.push(
-            window[n]
-                .clone()
-                .expect("'coding_blobs' arr in pub fn generate_coding"),
-        );
+        if let Some(old) = window[n].take() {
+            re.recycle(old);
+        }
+        let b = re.allocate();
+        {
+            let mut l = b.write().expect("'b' write lock in pub fn generate_coding");
+            // same id as the data blobs
+            l.data[..BLOB_FLAGS_END].copy_from_slice(&header);
+            l.set_index(i as u64)
+                .expect("set_index in pub fn generate_coding");
+            l.set_flags(BLOB_FLAG_IS_CODING)
+                .expect("set_flags in pub fn generate_coding");
+            l.meta.size = block_end;
+        }
+        window[n] = Some(b.clone());
+        coding_blobs.push(b);
     }
     for b in &coding_blobs {
         coding_locks.push(
@@ -198,31 +452,35 @@ pub fn generate_coding(
     }
     for (i, l) in coding_locks.iter_mut().enumerate() {
         trace!("i: {} data: {}", i, l.data[0]);
-        coding_ptrs.push(&mut l.data);
+        coding_ptrs.push(&mut l.data[BLOB_FLAGS_END..block_end]);
     }
 
     generate_coding_blocks(coding_ptrs.as_mut_slice(), &data_ptrs)?;
-    trace!("consumed: {}", consumed);
+    trace!("start: {}", start);
     Ok(())
 }
 
-// Recover missing blocks into window
-//   missing blocks should be None, will use re
+// Recover missing data blocks into window
+//   missing blocks are None or hold an older index, will use re
 //   to allocate new ones. Returns err if not enough
 //   coding blocks are present to restore
 pub fn recover(
+    config: &ErasureConfig,
     re: &BlobRecycler,
     window: &mut Vec<Option<SharedBlob>>,
-    consumed: usize,
+    start: usize,
 ) -> Result<()> {
     //recover with erasure coding
+    let num_data = config.num_data();
     let mut data_missing = 0;
     let mut coded_missing = 0;
-    let coding_start = consumed + NUM_DATA;
-    let coding_end = consumed + NUM_CODED;
-    for i in consumed..coding_end {
-        let n = i % window.len();
-        if window[n].is_none() {
+    let coding_start = start + num_data;
+    let coding_end = start + config.num_coded();
+    let present: Vec<bool> = (start..coding_end)
+        .map(|i| is_present(window, i))
+        .collect();
+    for i in start..coding_end {
+        if !present[i - start] {
             if i >= coding_start {
                 coded_missing += 1;
             } else {
@@ -231,50 +489,119 @@ pub fn recover(
         }
     }
     trace!("missing: data: {} coding: {}", data_missing, coded_missing);
-    if data_missing > 0 {
-        if (data_missing + coded_missing) <= MAX_MISSING {
-            let mut blobs: Vec<SharedBlob> = Vec::new();
-            let mut locks = Vec::new();
-            let mut data_ptrs: Vec<&mut [u8]> = Vec::new();
-            let mut coding_ptrs: Vec<&[u8]> = Vec::new();
-            let mut erasures: Vec<i32> = Vec::new();
-            for i in consumed..coding_end {
-                let j = i % window.len();
-                let mut b = &mut window[j];
-                if b.is_some() {
-                    blobs.push(b.clone().expect("'blobs' arr in pb fn recover"));
-                    continue;
-                }
-                let n = re.allocate();
-                *b = Some(n.clone());
-                //mark the missing memory
-                blobs.push(n);
-                erasures.push((i - consumed) as i32);
-            }
-            erasures.push(-1);
-            trace!("erasures: {:?}", erasures);
-            //lock everything
-            for b in &blobs {
-                locks.push(b.write().expect("'locks' arr in pb fn recover"));
-            }
-            for (i, l) in locks.iter_mut().enumerate() {
-                if i >= NUM_DATA {
-                    trace!("pushing coding: {}", i);
-                    coding_ptrs.push(&l.data);
-                } else {
-                    trace!("pushing data: {}", i);
-                    data_ptrs.push(&mut l.data);
-                }
+    if data_missing == 0 {
+        return Ok(());
+    }
+    if (data_missing + coded_missing) > config.max_missing() {
+        return Err(ErasureError::NotEnoughBlocksToDecode);
+    }
+    let mut blobs: Vec<SharedBlob> = Vec::new();
+    let mut erasures: Vec<i32> = Vec::new();
+    for i in start..coding_end {
+        if present[i - start] {
+            let j = i % window.len();
+            blobs.push(window[j].clone().expect("'blobs' arr in pb fn recover"));
+            continue;
+        }
+        //mark the missing memory
+        blobs.push(re.allocate());
+        erasures.push((i - start) as i32);
+    }
+    erasures.push(-1);
+    trace!("erasures: {:?}", erasures);
+    let result = decode_window_blocks(num_data, &blobs, &present, &erasures);
+    // recovered data blobs go into the window, the rest of the memory is returned
+    for (i, b) in blobs.into_iter().enumerate() {
+        if present[i] {
+            continue;
+        }
+        if result.is_err() || i >= num_data {
+            re.recycle(b);
+            continue;
+        }
+        let j = (start + i) % window.len();
+        if let Some(old) = window[j].take() {
+            re.recycle(old);
+        }
+        b.write()
+            .expect("'b' write lock in pb fn recover")
+            .set_index((start + i) as u64)
+            .expect("set_index in pb fn recover");
+        window[j] = Some(b);
+    }
+    result
+}
+
+// Decode the blobs of an erasure set, restoring the headers of the missing data blobs
+// except their index
+fn decode_window_blocks(
+    num_data: usize,
+    blobs: &[SharedBlob],
+    present: &[bool],
+    erasures: &[i32],
+) -> Result<()> {
+    //lock everything
+    let mut locks = Vec::new();
+    for b in blobs {
+        locks.push(b.write().expect("'locks' arr in fn decode_window_blocks"));
+    }
+    // a coding blob that arrived tells the block length and the id of the set
+    let coded = (num_data..blobs.len())
+        .find(|i| present[*i])
+        .expect("coding blob in fn decode_window_blocks");
+    let block_end = locks[coded].meta.size;
+    if block_end <= BLOB_FLAGS_END {
+        return Err(ErasureError::InvalidBlockSize);
+    }
+    let mut header = [0u8; BLOB_FLAGS_END];
+    header.copy_from_slice(&locks[coded].data[..BLOB_FLAGS_END]);
+    for (i, l) in locks.iter_mut().enumerate() {
+        if !present[i] {
+            continue;
+        }
+        let size = l.meta.size;
+        if size > block_end || (i >= num_data && size != block_end) {
+            return Err(ErasureError::InvalidBlockSize);
+        }
+        // the padding up to the block length was coded as zeros
+        for x in l.data[max(size, BLOB_HEADER_SIZE)..block_end].iter_mut() {
+            *x = 0;
+        }
+    }
+    {
+        let mut data_ptrs: Vec<&mut [u8]> = Vec::new();
+        let mut coding_ptrs: Vec<&[u8]> = Vec::new();
+        for (i, l) in locks.iter_mut().enumerate() {
+            if i >= num_data {
+                trace!("pushing coding: {}", i);
+                coding_ptrs.push(&l.data[BLOB_FLAGS_END..block_end]);
+            } else {
+                trace!("pushing data: {}", i);
+                data_ptrs.push(&mut l.data[BLOB_FLAGS_END..block_end]);
             }
-            trace!(
-                "coding_ptrs.len: {} data_ptrs.len {}",
-                coding_ptrs.len(),
-                data_ptrs.len()
-            );
-            decode_blocks(data_ptrs.as_mut_slice(), &coding_ptrs, &erasures)?;
-        } else {
-            return Err(ErasureError::NotEnoughBlocksToDecode);
         }
+        trace!(
+            "coding_ptrs.len: {} data_ptrs.len {}",
+            coding_ptrs.len(),
+            data_ptrs.len()
+        );
+        decode_blocks(data_ptrs.as_mut_slice(), &coding_ptrs, erasures)?;
+    }
+    for (i, l) in locks.iter_mut().enumerate().take(num_data) {
+        if present[i] {
+            continue;
+        }
+        // the leader's signature isn't coded, so a recovered blob keeps the one from the
+        // coding blob and is marked so it isn't handed out to answer a repair request
+        l.data[..BLOB_FLAGS_END].copy_from_slice(&header);
+        l.set_flags(BLOB_FLAG_IS_RECOVERED)
+            .expect("set_flags in fn decode_window_blocks");
+        let size = l.get_data_size()
+            .expect("get_data_size in fn decode_window_blocks") as usize;
+        if size > block_end {
+            return Err(ErasureError::DecodeError);
+        }
+        l.meta.size = size;
     }
     Ok(())
 }
@@ -282,7 +609,9 @@ pub fn recover(
 #[cfg(test)]
 mod test {
     use erasure;
-    use packet::{BlobRecycler, SharedBlob, PACKET_DATA_SIZE};
+    use erasure::ErasureConfig;
+    use packet::{BlobRecycler, SharedBlob, BLOB_FLAGS_END, PACKET_DATA_SIZE};
+    use signature::{KeyPair, KeyPairUtil};
 
     #[test]
     pub fn test_coding() {
@@ -334,6 +663,122 @@ mod test {
         assert_eq!(v_orig, vs[0]);
     }
 
+    #[test]
+    pub fn test_galois() {
+        let a = 0x1234_5678;
+        assert_eq!(erasure::galois_multiply(a, 1), a);
+        assert_eq!(erasure::galois_multiply(a, 0), 0);
+        // x^31 * x wraps around to the primitive polynomial
+        assert_eq!(erasure::galois_multiply(0x8000_0000, 2), 0x0040_0007);
+        for x in 1..100 {
+            assert_eq!(erasure::galois_multiply(x, erasure::galois_inverse(x)), 1);
+            assert_eq!(erasure::galois_divide(erasure::galois_multiply(a, x), x), a);
+        }
+    }
+
+    /// Known answers for jerasure's Cauchy matrix and `jerasure_matrix_encode` with
+    /// w = 32, for a set of 8 data and 2 coding blocks. They were computed apart from
+    /// this module, and whichever backend is built has to reproduce them.
+    #[test]
+    pub fn test_coding_known_answer() {
+        let (m, k) = (2, 8);
+        let matrix: Vec<u32> = vec![
+            0x8020_0003, 0xffc0_0002, 0xc030_0002, 0xaa80_0003, 0x7fe0_0001, 0x6dad_b6da,
+            0x6018_0001, 0x249b_6db6, 0xffc0_0002, 0x8020_0003, 0xaa80_0003, 0xc030_0002,
+            0x6dad_b6da, 0x7fe0_0001, 0x249b_6db6, 0x6018_0001,
+        ];
+        let got: Vec<u32> = erasure::get_matrix(m, k, erasure::ERASURE_W)
+            .into_iter()
+            .map(|x| x as u32)
+            .collect();
+        assert_eq!(got, matrix);
+
+        let data: Vec<Vec<u8>> = (0..k)
+            .map(|i| (0..16).map(|j| (i * 64 + j * 7) as u8).collect())
+            .collect();
+        let mut coding = vec![vec![0u8; 16]; m as usize];
+        {
+            let mut coding_slices: Vec<_> = coding.iter_mut().map(|x| x.as_mut_slice()).collect();
+            let data_slices: Vec<_> = data.iter().map(|x| x.as_slice()).collect();
+            assert!(erasure::generate_coding_blocks(&mut coding_slices, &data_slices).is_ok());
+        }
+        let expected: Vec<Vec<u8>> = vec![
+            vec![
+                70, 113, 248, 156, 62, 255, 233, 71, 78, 208, 113, 120, 137, 48, 160, 92,
+            ],
+            vec![
+                157, 216, 13, 53, 229, 86, 28, 238, 150, 121, 116, 89, 227, 202, 254, 166,
+            ],
+        ];
+        assert_eq!(coding, expected);
+    }
+
+    /// Any `m` of the blocks can be lost, for a few set sizes
+    #[test]
+    pub fn test_coding_params() {
+        for &(k, m) in &[(4, 2), (8, 2), (6, 4)] {
+            let data: Vec<Vec<u8>> = (0..k)
+                .map(|i| (0..64).map(|j| (i * 64 + j * 7) as u8).collect())
+                .collect();
+            let mut coding = vec![vec![0u8; 64]; m];
+            {
+                let mut coding_slices: Vec<_> =
+                    coding.iter_mut().map(|x| x.as_mut_slice()).collect();
+                let data_slices: Vec<_> = data.iter().map(|x| x.as_slice()).collect();
+                assert!(erasure::generate_coding_blocks(&mut coding_slices, &data_slices).is_ok());
+            }
+            // lose data blocks from the front and coding blocks from the back
+            for lost_data in 1..(m + 1) {
+                let mut vs = data.clone();
+                let mut cs = coding.clone();
+                let mut erasures: Vec<i32> = (0..lost_data).map(|i| i as i32).collect();
+                for i in 0..lost_data {
+                    vs[i] = vec![0; 64];
+                }
+                for i in lost_data..m {
+                    cs[m - 1 - (i - lost_data)] = vec![0; 64];
+                    erasures.push((k + m - 1 - (i - lost_data)) as i32);
+                }
+                erasures.push(-1);
+                let coding_slices: Vec<_> = cs.iter().map(|x| x.as_slice()).collect();
+                let mut v_slices: Vec<_> = vs.iter_mut().map(|x| x.as_mut_slice()).collect();
+                assert!(erasure::decode_blocks(&mut v_slices, &coding_slices, &erasures).is_ok());
+                assert_eq!(vs, data);
+            }
+        }
+    }
+
+    /// Both backends build the same matrix and the same coding blocks
+    #[cfg(feature = "jerasure")]
+    #[test]
+    pub fn test_jerasure_compat() {
+        let (m, k) = (2, 8);
+        let matrix = erasure::get_matrix(m, k, erasure::ERASURE_W);
+        for i in 0..m {
+            for j in 0..k {
+                let w = erasure::ERASURE_W;
+                let x = unsafe { erasure::galois_single_divide(1, i ^ (m + j), w) };
+                assert_eq!(matrix[(i * k + j) as usize], x);
+            }
+        }
+        let data: Vec<Vec<u8>> = (0..k)
+            .map(|i| (0..64).map(|j| (i * 64 + j * 7) as u8).collect())
+            .collect();
+        let data_slices: Vec<_> = data.iter().map(|x| x.as_slice()).collect();
+        let mut coding = vec![vec![0u8; 64]; m as usize];
+        let mut expected = coding.clone();
+        {
+            let mut coding_slices: Vec<_> = coding.iter_mut().map(|x| x.as_mut_slice()).collect();
+            erasure::jerasure_encode(k as usize, &matrix, &mut coding_slices, &data_slices);
+        }
+        {
+            let mut coding_slices: Vec<_> =
+                expected.iter_mut().map(|x| x.as_mut_slice()).collect();
+            erasure::matrix_encode(k as usize, &matrix, &mut coding_slices, &data_slices);
+        }
+        assert_eq!(coding, expected);
+    }
+
     fn print_window(window: &Vec<Option<SharedBlob>>) {
         for (i, w) in window.iter().enumerate() {
             print!("window({}): ", i);
@@ -354,77 +799,76 @@ mod test {
     pub fn test_window_recover() {
         let mut window = Vec::new();
         let blob_recycler = BlobRecycler::default();
+        let id = KeyPair::new().pubkey();
+        let config = ErasureConfig::default();
+        let num_coded = config.num_coded();
         let offset = 4;
-        for i in 0..(4 * erasure::NUM_CODED + 1) {
+        for i in 0..(4 * num_coded + 1) {
             let b = blob_recycler.allocate();
             let b_ = b.clone();
             let data_len = b.read().unwrap().data.len();
             let mut w = b.write().unwrap();
-            w.set_index(i as u64).unwrap();
-            assert_eq!(i as u64, w.get_index().unwrap());
-            w.meta.size = PACKET_DATA_SIZE;
             for k in 0..data_len {
                 w.data[k] = (k + i) as u8;
             }
+            w.set_index(i as u64).unwrap();
+            assert_eq!(i as u64, w.get_index().unwrap());
+            w.set_id(id).unwrap();
+            w.set_flags(0).unwrap();
+            w.meta.size = PACKET_DATA_SIZE;
             window.push(Some(b_));
         }
         println!("** after-gen:");
         print_window(&window);
-        assert!(erasure::generate_coding(&blob_recycler, &mut window, offset).is_ok());
-        assert!(
-            erasure::generate_coding(&blob_recycler, &mut window, offset + erasure::NUM_CODED)
-                .is_ok()
-        );
-        assert!(
-            erasure::generate_coding(
-                &blob_recycler,
-                &mut window,
-                offset + (2 * erasure::NUM_CODED)
-            ).is_ok()
-        );
-        assert!(
-            erasure::generate_coding(
-                &blob_recycler,
-                &mut window,
-                offset + (3 * erasure::NUM_CODED)
-            ).is_ok()
-        );
+        for k in 0..4 {
+            let start = offset + k * num_coded;
+            assert!(erasure::generate_coding(&config, &blob_recycler, &mut window, start).is_ok());
+        }
         println!("** after-coding:");
         print_window(&window);
         let refwindow = window[offset + 1].clone();
         window[offset + 1] = None;
         window[offset + 2] = None;
-        window[offset + erasure::NUM_CODED + 3] = None;
-        window[offset + (2 * erasure::NUM_CODED) + 0] = None;
-        window[offset + (2 * erasure::NUM_CODED) + 1] = None;
-        window[offset + (2 * erasure::NUM_CODED) + 2] = None;
-        let window_l0 = &(window[offset + (3 * erasure::NUM_CODED)]).clone().unwrap();
+        window[offset + num_coded + 3] = None;
+        window[offset + (2 * num_coded) + 0] = None;
+        window[offset + (2 * num_coded) + 1] = None;
+        window[offset + (2 * num_coded) + 2] = None;
+        let window_l0 = &(window[offset + (3 * num_coded)]).clone().unwrap();
         window_l0.write().unwrap().data[0] = 55;
         println!("** after-nulling:");
         print_window(&window);
-        assert!(erasure::recover(&blob_recycler, &mut window, offset).is_ok());
-        assert!(erasure::recover(&blob_recycler, &mut window, offset + erasure::NUM_CODED).is_ok());
+        assert!(erasure::recover(&config, &blob_recycler, &mut window, offset).is_ok());
+        assert!(erasure::recover(&config, &blob_recycler, &mut window, offset + num_coded).is_ok());
         assert!(
             erasure::recover(
+                &config,
                 &blob_recycler,
                 &mut window,
-                offset + (2 * erasure::NUM_CODED)
+                offset + (2 * num_coded)
             ).is_err()
         );
         assert!(
             erasure::recover(
+                &config,
                 &blob_recycler,
                 &mut window,
-                offset + (3 * erasure::NUM_CODED)
+                offset + (3 * num_coded)
             ).is_ok()
         );
         println!("** after-restore:");
         print_window(&window);
         let window_l = window[offset + 1].clone().unwrap();
         let ref_l = refwindow.clone().unwrap();
+        let size = ref_l.read().unwrap().meta.size;
+        assert_eq!(window_l.read().unwrap().meta.size, size);
+        assert_eq!(window_l.read().unwrap().get_index().unwrap(), offset as u64 + 1);
+        assert_eq!(window_l.read().unwrap().get_id().unwrap(), id);
+        assert!(window_l.read().unwrap().is_recovered());
+        assert!(!window_l.read().unwrap().is_coding());
+        // the leader's signature isn't coded, everything after the flags is
         assert_eq!(
-            window_l.read().unwrap().data.to_vec(),
-            ref_l.read().unwrap().data.to_vec()
+            window_l.read().unwrap().data[BLOB_FLAGS_END..size].to_vec(),
+            ref_l.read().unwrap().data[BLOB_FLAGS_END..size].to_vec()
         );
     }
 }
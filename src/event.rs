@@ -1,8 +1,9 @@
-//! The `event` module handles events, which may be a `Transaction`, or a `Witness` used to process a pending
-//! Transaction.
+//! The `event` module handles events, which may be a `Transaction`, a `Witness` used to process a pending
+//! Transaction, or a validator's `Vote`.
 
 use bincode::serialize;
 use chrono::prelude::*;
+use hash::Hash;
 use signature::{KeyPair, KeyPairUtil, PublicKey, Signature, SignatureUtil};
 use transaction::Transaction;
 
@@ -19,6 +20,20 @@ pub enum Event {
         dt: DateTime<Utc>,
         sig: Signature,
     },
+    Preimage {
+        from: PublicKey,
+        tx_sig: Signature,
+        preimage: Vec<u8>,
+        sig: Signature,
+    },
+    /// A validator's claim that its state hash was `state_hash` after it
+    /// processed the Entry `entry_id`.
+    Vote {
+        from: PublicKey,
+        entry_id: Hash,
+        state_hash: Hash,
+        sig: Signature,
+    },
 }
 
 impl Event {
@@ -43,6 +58,44 @@ impl Event {
         }
     }
 
+    /// Create and sign a new Witness Preimage that unlocks the hash lock in the
+    /// transaction signed by `tx_sig`. Used for unit-testing.
+    pub fn new_preimage(from: &KeyPair, tx_sig: Signature, preimage: Vec<u8>) -> Self {
+        let sign_data = serialize(&(&tx_sig, &preimage))
+            .expect("serialize 'preimage' in pub fn new_preimage");
+        let sig = Signature::clone_from_slice(from.sign(&sign_data).as_ref());
+        Event::Preimage {
+            from: from.pubkey(),
+            tx_sig,
+            preimage,
+            sig,
+        }
+    }
+
+    /// Create and sign a new Vote for the Entry `entry_id`.
+    pub fn new_vote(from: &KeyPair, entry_id: Hash, state_hash: Hash) -> Self {
+        let sign_data = serialize(&(&entry_id, &state_hash))
+            .expect("serialize 'state_hash' in pub fn new_vote");
+        let sig = Signature::clone_from_slice(from.sign(&sign_data).as_ref());
+        Event::Vote {
+            from: from.pubkey(),
+            entry_id,
+            state_hash,
+            sig,
+        }
+    }
+
+    /// Return the Event's signature.
+    pub fn get_sig(&self) -> Signature {
+        match *self {
+            Event::Transaction(ref tr) => tr.sig,
+            Event::Signature { sig, .. }
+            | Event::Timestamp { sig, .. }
+            | Event::Preimage { sig, .. }
+            | Event::Vote { sig, .. } => sig,
+        }
+    }
+
     /// Verify the Event's signature's are valid and if a transaction, that its
     /// spending plan is valid.
     pub fn verify(&self) -> bool {
@@ -53,6 +106,24 @@ impl Event {
                 &from,
                 &serialize(&dt).expect("serialize 'dt' in pub fn verify"),
             ),
+            Event::Preimage {
+                from,
+                tx_sig,
+                ref preimage,
+                sig,
+            } => sig.verify(
+                &from,
+                &serialize(&(&tx_sig, preimage)).expect("serialize 'preimage' in pub fn verify"),
+            ),
+            Event::Vote {
+                from,
+                entry_id,
+                state_hash,
+                sig,
+            } => sig.verify(
+                &from,
+                &serialize(&(&entry_id, &state_hash)).expect("serialize 'vote' in pub fn verify"),
+            ),
         }
     }
 }
@@ -60,11 +131,48 @@ impl Event {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use hash::hash;
     use signature::{KeyPair, KeyPairUtil};
 
     #[test]
     fn test_event_verify() {
         assert!(Event::new_timestamp(&KeyPair::new(), Utc::now()).verify());
         assert!(Event::new_signature(&KeyPair::new(), Signature::default()).verify());
+        assert!(Event::new_preimage(&KeyPair::new(), Signature::default(), vec![1, 2, 3]).verify());
+        assert!(Event::new_vote(&KeyPair::new(), Hash::default(), Hash::default()).verify());
+    }
+
+    #[test]
+    fn test_preimage_tamper() {
+        let event = Event::new_preimage(&KeyPair::new(), Signature::default(), vec![1, 2, 3]);
+        if let Event::Preimage {
+            from, tx_sig, sig, ..
+        } = event
+        {
+            let event = Event::Preimage {
+                from,
+                tx_sig,
+                preimage: vec![3, 2, 1], // <-- attack!
+                sig,
+            };
+            assert!(!event.verify());
+        }
+    }
+
+    #[test]
+    fn test_vote_tamper() {
+        let event = Event::new_vote(&KeyPair::new(), Hash::default(), Hash::default());
+        if let Event::Vote {
+            from, entry_id, sig, ..
+        } = event
+        {
+            let event = Event::Vote {
+                from,
+                entry_id,
+                state_hash: hash(&entry_id), // <-- attack!
+                sig,
+            };
+            assert!(!event.verify());
+        }
     }
 }
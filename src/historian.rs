@@ -20,9 +20,26 @@ impl Historian {
         start_hash: &Hash,
         ms_per_tick: Option<u64>,
     ) -> Self {
+        let (entry_sender, output) = channel();
+        let thread_hdl = Historian::create_recorder(
+            *start_hash,
+            ms_per_tick,
+            ms_per_tick.is_some(),
+            event_receiver,
+            entry_sender,
+        );
+        Historian {
+            output: Mutex::new(output),
+            thread_hdl,
+        }
+    }
+
+    /// Create a Historian that hashes between entries like a ticking one, but only
+    /// records a tick when it is sent `Signal::Tick`.
+    pub fn new_hashing(event_receiver: Receiver<Signal>, start_hash: &Hash) -> Self {
         let (entry_sender, output) = channel();
         let thread_hdl =
-            Historian::create_recorder(*start_hash, ms_per_tick, event_receiver, entry_sender);
+            Historian::create_recorder(*start_hash, None, true, event_receiver, entry_sender);
         Historian {
             output: Mutex::new(output),
             thread_hdl,
@@ -34,6 +51,7 @@ impl Historian {
     fn create_recorder(
         start_hash: Hash,
         ms_per_tick: Option<u64>,
+        hashing: bool,
         receiver: Receiver<Signal>,
         sender: Sender<Entry>,
     ) -> JoinHandle<ExitReason> {
@@ -44,7 +62,7 @@ impl Historian {
                 if let Err(err) = recorder.process_events(now, ms_per_tick) {
                     return err;
                 }
-                if ms_per_tick.is_some() {
+                if hashing {
                     recorder.hash();
                 }
             }
@@ -108,6 +126,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_hashing_historian() {
+        let (input, event_receiver) = channel();
+        let zero = Hash::default();
+        let hist = Historian::new_hashing(event_receiver, &zero);
+        sleep(Duration::from_millis(20));
+        input.send(Signal::Tick).unwrap();
+        drop(input);
+        let entries: Vec<Entry> = hist.output.lock().unwrap().iter().collect();
+
+        // only the tick that was asked for, after some hashing
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].num_hashes > 0);
+        assert!(entries[..].verify(&zero));
+    }
+
     #[test]
     fn test_ticking_historian() {
         let (input, event_receiver) = channel();
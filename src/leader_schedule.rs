@@ -0,0 +1,139 @@
+//! The `leader_schedule` module decides which validator leads the network at a given
+//! tick height. The ledger is split into slots of `ticks_per_slot` ticks, and the
+//! validators take turns leading them. Every node derives the same schedule from the
+//! same validator set, so no messages are needed to hand off.
+
+use entry::Entry;
+use signature::PublicKey;
+
+/// Ticks in a slot, unless configured otherwise
+pub const TICKS_PER_SLOT: u64 = 10;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeaderSchedule {
+    validators: Vec<PublicKey>,
+    ticks_per_slot: u64,
+}
+
+impl LeaderSchedule {
+    /// Create a schedule for `validators`, in whatever order they are given.
+    pub fn new(mut validators: Vec<PublicKey>, ticks_per_slot: u64) -> Self {
+        assert!(!validators.is_empty());
+        assert!(ticks_per_slot > 0);
+        validators.sort();
+        validators.dedup();
+        LeaderSchedule {
+            validators,
+            ticks_per_slot,
+        }
+    }
+
+    pub fn validators(&self) -> &[PublicKey] {
+        &self.validators
+    }
+
+    pub fn ticks_per_slot(&self) -> u64 {
+        self.ticks_per_slot
+    }
+
+    /// The slot that the tick after `tick_height` falls in.
+    pub fn slot(&self, tick_height: u64) -> u64 {
+        tick_height / self.ticks_per_slot
+    }
+
+    /// The tick height at which the slot containing `tick_height` ends.
+    pub fn slot_end(&self, tick_height: u64) -> u64 {
+        (self.slot(tick_height) + 1) * self.ticks_per_slot
+    }
+
+    /// The validator that records the tick after `tick_height`.
+    pub fn leader_at(&self, tick_height: u64) -> PublicKey {
+        let slot = self.slot(tick_height);
+        self.validators[(slot % self.validators.len() as u64) as usize]
+    }
+
+    /// The validator that recorded `entry`, the next one in the ledger at
+    /// `tick_height`, which is moved past it. Replaying a ledger this way pays each
+    /// entry's fees to the leader that was paid them when it was recorded.
+    pub fn leader_of_next(&self, tick_height: &mut u64, entry: &Entry) -> PublicKey {
+        let leader = self.leader_at(*tick_height);
+        if entry.events.is_empty() {
+            *tick_height += 1;
+        }
+        leader
+    }
+
+    /// Add `entry`, which took the ledger to `tick_height`, to `slot_entries`, the
+    /// entries since the start of the slot. The tick that ends the slot clears them.
+    pub fn push_slot_entry(&self, slot_entries: &mut Vec<Entry>, tick_height: u64, entry: Entry) {
+        if entry.events.is_empty() && tick_height % self.ticks_per_slot == 0 {
+            slot_entries.clear();
+        } else {
+            slot_entries.push(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use entry::next_entry;
+    use event::Event;
+    use hash::Hash;
+    use leader_schedule::LeaderSchedule;
+    use signature::{KeyPair, KeyPairUtil};
+    use transaction::test_tx;
+
+    #[test]
+    fn test_leader_schedule() {
+        let ids: Vec<_> = (0..3).map(|_| KeyPair::new().pubkey()).collect();
+        let schedule = LeaderSchedule::new(ids.clone(), 10);
+        assert_eq!(schedule.validators().len(), 3);
+
+        // the same set in a different order gives the same schedule
+        let reversed = ids.iter().rev().cloned().collect();
+        assert_eq!(LeaderSchedule::new(reversed, 10), schedule);
+
+        assert_eq!(schedule.slot(0), 0);
+        assert_eq!(schedule.slot(9), 0);
+        assert_eq!(schedule.slot(10), 1);
+        assert_eq!(schedule.slot_end(0), 10);
+        assert_eq!(schedule.slot_end(10), 20);
+
+        // each validator leads one slot in turn
+        let leaders: Vec<_> = (0..3).map(|s| schedule.leader_at(s * 10)).collect();
+        for id in &ids {
+            assert!(leaders.contains(id));
+        }
+        assert_eq!(schedule.leader_at(5), leaders[0]);
+        assert_eq!(schedule.leader_at(30), leaders[0]);
+        assert_eq!(schedule.leader_at(45), leaders[1]);
+    }
+
+    #[test]
+    fn test_leader_of_next() {
+        let ids: Vec<_> = (0..2).map(|_| KeyPair::new().pubkey()).collect();
+        let schedule = LeaderSchedule::new(ids, 2);
+        let zero = Hash::default();
+        let tick = next_entry(&zero, 1, vec![]);
+        let events = next_entry(&zero, 1, vec![Event::Transaction(test_tx())]);
+
+        // the entries up to and including a slot's last tick are its leader's
+        let mut tick_height = 0;
+        let leader0 = schedule.leader_at(0);
+        assert_eq!(schedule.leader_of_next(&mut tick_height, &tick), leader0);
+        assert_eq!(schedule.leader_of_next(&mut tick_height, &events), leader0);
+        assert_eq!(schedule.leader_of_next(&mut tick_height, &tick), leader0);
+        assert_eq!(tick_height, 2);
+        let leader1 = schedule.leader_at(2);
+        assert_ne!(leader1, leader0);
+        assert_eq!(schedule.leader_of_next(&mut tick_height, &events), leader1);
+        assert_eq!(tick_height, 2);
+
+        let mut slot_entries = vec![];
+        schedule.push_slot_entry(&mut slot_entries, 2, events.clone());
+        schedule.push_slot_entry(&mut slot_entries, 3, tick.clone());
+        assert_eq!(slot_entries, vec![events, tick.clone()]);
+        schedule.push_slot_entry(&mut slot_entries, 4, tick);
+        assert!(slot_entries.is_empty());
+    }
+}
@@ -6,7 +6,7 @@ use entry::{next_entry, Entry};
 use event::Event;
 use hash::Hash;
 use packet;
-use packet::{SharedBlob, BLOB_DATA_SIZE, BLOB_SIZE};
+use packet::{SharedBlob, BLOB_DATA_SIZE, BLOB_SIZE, PACKET_DATA_SIZE};
 use rayon::prelude::*;
 use std::cmp::min;
 use std::collections::VecDeque;
@@ -16,6 +16,9 @@ use std::mem::size_of;
 pub trait Block {
     /// Verifies the hashes and counts of a slice of events are all consistent.
     fn verify(&self, start_hash: &Hash) -> bool;
+
+    /// Returns the index of the first Entry that doesn't follow from the one before it.
+    fn first_invalid(&self, start_hash: &Hash) -> Option<usize>;
 }
 
 impl Block for [Entry] {
@@ -24,6 +27,12 @@ impl Block for [Entry] {
         let entry_pairs = genesis.par_iter().chain(self).zip(self);
         entry_pairs.all(|(x0, x1)| x1.verify(&x0.id))
     }
+
+    fn first_invalid(&self, start_hash: &Hash) -> Option<usize> {
+        let genesis = [Entry::new_tick(0, start_hash)];
+        let entry_pairs = genesis.par_iter().chain(self).zip(self);
+        entry_pairs.position_first(|(x0, x1)| !x1.verify(&x0.id))
+    }
 }
 
 /// Create a vector of Entries of length `event_set.len()` from `start_hash` hash, `num_hashes`, and `event_set`.
@@ -50,7 +59,8 @@ pub fn process_entry_list_into_blobs(
         let mut entries: Vec<Vec<Entry>> = Vec::new();
         let mut total = 0;
         for i in &list[start..] {
-            total += size_of::<Event>() * i.events.len();
+            // Events arrive in packets, so none serializes to more than a packet.
+            total += PACKET_DATA_SIZE * i.events.len();
             total += size_of::<Entry>();
             if total >= BLOB_DATA_SIZE {
                 break;
@@ -60,7 +70,7 @@ pub fn process_entry_list_into_blobs(
         // See if we need to split the events
         if end <= start {
             let mut event_start = 0;
-            let num_events_per_blob = BLOB_DATA_SIZE / size_of::<Event>();
+            let num_events_per_blob = BLOB_DATA_SIZE / PACKET_DATA_SIZE;
             let total_entry_chunks =
                 (list[end].events.len() + num_events_per_blob - 1) / num_events_per_blob;
             trace!(
@@ -143,6 +153,20 @@ mod tests {
         assert!(!bad_ticks.verify(&zero)); // inductive step, bad
     }
 
+    #[test]
+    fn test_first_invalid() {
+        let zero = Hash::default();
+        let one = hash(&zero);
+        let mut ticks = next_entries(&zero, 0, vec![vec![]; 4]);
+        assert_eq!(ticks.first_invalid(&zero), None);
+        assert_eq!(ticks.first_invalid(&one), Some(0));
+
+        ticks[2].id = one;
+        assert_eq!(ticks.first_invalid(&zero), Some(2));
+        ticks[1].id = one;
+        assert_eq!(ticks.first_invalid(&zero), Some(1));
+    }
+
     #[test]
     fn test_entry_to_blobs() {
         let zero = Hash::default();
@@ -0,0 +1,297 @@
+//! The `ledger_store` module persists the ledger to disk. Entries are appended
+//! bincode-encoded to a data file, and the offset of each one is appended to an
+//! index file, so that entries can be looked up by their height in the ledger.
+
+use bincode::{deserialize_from, serialize};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use entry::Entry;
+use result::{Error, Result};
+use serde_json;
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Size of each offset in the index file.
+const INDEX_ENTRY_SIZE: u64 = 8;
+
+fn data_path(path: &Path) -> PathBuf {
+    path.join("data")
+}
+
+fn index_path(path: &Path) -> PathBuf {
+    path.join("index")
+}
+
+fn read_offset(index: &mut File, height: u64) -> Result<u64> {
+    index.seek(SeekFrom::Start(height * INDEX_ENTRY_SIZE))?;
+    Ok(index.read_u64::<LittleEndian>()?)
+}
+
+/// Discard a partially written entry at the end of the ledger. The data is
+/// always written before its offset, so an entry is whole if its offset made
+/// it to the index and it can be decoded. Returns the length of the data file
+/// that remains.
+fn recover(data: &mut File, index: &mut File) -> Result<u64> {
+    let data_file_len = data.metadata()?.len();
+    let mut num_entries = index.metadata()?.len() / INDEX_ENTRY_SIZE;
+    let mut data_len = 0;
+    while num_entries > 0 {
+        let offset = read_offset(index, num_entries - 1)?;
+        if offset < data_file_len {
+            let mut buf = vec![];
+            data.seek(SeekFrom::Start(offset))?;
+            data.read_to_end(&mut buf)?;
+            let mut cursor = Cursor::new(&buf[..]);
+            if deserialize_from::<_, Entry>(&mut cursor).is_ok() {
+                data_len = offset + cursor.position();
+                break;
+            }
+        }
+        warn!("discarding torn ledger entry at height {}", num_entries - 1);
+        num_entries -= 1;
+    }
+    index.set_len(num_entries * INDEX_ENTRY_SIZE)?;
+    data.set_len(data_len)?;
+    Ok(data_len)
+}
+
+/// Something the Tpu can record its entries to.
+pub trait EntryWriter {
+    fn write_entry(&mut self, entry: &Entry) -> Result<()>;
+}
+
+/// Any `Write` records entries as JSON, one per line.
+impl<W: Write> EntryWriter for W {
+    fn write_entry(&mut self, entry: &Entry) -> Result<()> {
+        writeln!(self, "{}", serde_json::to_string(entry)?)?;
+        Ok(())
+    }
+}
+
+pub struct LedgerWriter {
+    data: BufWriter<File>,
+    index: BufWriter<File>,
+    data_len: u64,
+}
+
+impl LedgerWriter {
+    /// Open the ledger in the directory `path` for appending, creating it if it
+    /// doesn't exist.
+    pub fn open(path: &Path) -> Result<Self> {
+        create_dir_all(path)?;
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true);
+        let mut data = options.open(data_path(path))?;
+        let mut index = options.open(index_path(path))?;
+        let data_len = recover(&mut data, &mut index)?;
+        data.seek(SeekFrom::End(0))?;
+        index.seek(SeekFrom::End(0))?;
+        Ok(LedgerWriter {
+            data: BufWriter::new(data),
+            index: BufWriter::new(index),
+            data_len,
+        })
+    }
+}
+
+impl EntryWriter for LedgerWriter {
+    fn write_entry(&mut self, entry: &Entry) -> Result<()> {
+        let bytes = serialize(entry)?;
+        self.data.write_all(&bytes)?;
+        self.data.flush()?;
+        self.index.write_u64::<LittleEndian>(self.data_len)?;
+        self.index.flush()?;
+        self.data_len += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+pub struct LedgerReader {
+    path: PathBuf,
+    data: File,
+    index: File,
+}
+
+impl LedgerReader {
+    /// Open the ledger in the directory `path` for reading.
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(LedgerReader {
+            path: path.to_path_buf(),
+            data: File::open(data_path(path))?,
+            index: File::open(index_path(path))?,
+        })
+    }
+
+    /// Return the number of entries in the ledger.
+    pub fn len(&self) -> Result<u64> {
+        Ok(self.index.metadata()?.len() / INDEX_ENTRY_SIZE)
+    }
+
+    /// Return the Entry at `height`, where the first Entry is at height 0.
+    pub fn get_entry(&mut self, height: u64) -> Result<Entry> {
+        let offset = read_offset(&mut self.index, height)?;
+        self.data.seek(SeekFrom::Start(offset))?;
+        Ok(deserialize_from(&mut BufReader::new(&self.data))?)
+    }
+
+    /// Return an iterator over the entries from `height` to the end of the ledger,
+    /// as of the time of the call.
+    pub fn entries_from(&mut self, height: u64) -> Result<LedgerIter> {
+        let len = self.len()?;
+        let mut data = File::open(data_path(&self.path))?;
+        if height < len {
+            let offset = read_offset(&mut self.index, height)?;
+            data.seek(SeekFrom::Start(offset))?;
+        }
+        Ok(LedgerIter {
+            data: BufReader::new(data),
+            remaining: len.saturating_sub(height),
+        })
+    }
+}
+
+pub struct LedgerIter {
+    data: BufReader<File>,
+    remaining: u64,
+}
+
+impl Iterator for LedgerIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Result<Entry>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(deserialize_from(&mut self.data).map_err(Error::from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entry::next_entry;
+    use event::Event;
+    use hash::Hash;
+    use rand::{thread_rng, Rng};
+    use signature::{KeyPair, KeyPairUtil};
+    use std::env;
+    use std::fs::remove_dir_all;
+    use transaction::Transaction;
+
+    fn tmp_ledger_path(name: &str) -> PathBuf {
+        let id: u64 = thread_rng().gen();
+        env::temp_dir().join(format!("ledger_store-{}-{}", name, id))
+    }
+
+    fn make_entries(num: usize) -> Vec<Entry> {
+        let keypair = KeyPair::new();
+        let mut id = Hash::default();
+        (0..num)
+            .map(|i| {
+                let tr = Transaction::new(&keypair, keypair.pubkey(), i as i64, id);
+                let entry = next_entry(&id, 1, vec![Event::Transaction(tr)]);
+                id = entry.id;
+                entry
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let path = tmp_ledger_path("write_and_read");
+        let entries = make_entries(10);
+        {
+            let mut writer = LedgerWriter::open(&path).unwrap();
+            for entry in &entries[..5] {
+                writer.write_entry(entry).unwrap();
+            }
+        }
+
+        // Reopening appends.
+        let mut writer = LedgerWriter::open(&path).unwrap();
+        for entry in &entries[5..] {
+            writer.write_entry(entry).unwrap();
+        }
+
+        let mut reader = LedgerReader::open(&path).unwrap();
+        assert_eq!(reader.len().unwrap(), 10);
+        assert_eq!(reader.get_entry(7).unwrap(), entries[7]);
+        assert_eq!(reader.get_entry(0).unwrap(), entries[0]);
+        assert!(reader.get_entry(10).is_err());
+
+        let read: Vec<_> = reader.entries_from(3).unwrap().map(|x| x.unwrap()).collect();
+        assert_eq!(read, &entries[3..]);
+        assert_eq!(reader.entries_from(10).unwrap().count(), 0);
+        remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_recover_torn_data() {
+        let path = tmp_ledger_path("recover_torn_data");
+        let entries = make_entries(3);
+        {
+            let mut writer = LedgerWriter::open(&path).unwrap();
+            for entry in &entries {
+                writer.write_entry(entry).unwrap();
+            }
+        }
+
+        // Simulate a crash after writing the offset, but only half of the entry.
+        let data_len = File::open(data_path(&path)).unwrap().metadata().unwrap().len();
+        let last_len = serialize(&entries[2]).unwrap().len() as u64;
+        let data = OpenOptions::new()
+            .write(true)
+            .open(data_path(&path))
+            .unwrap();
+        data.set_len(data_len - last_len / 2).unwrap();
+
+        let mut writer = LedgerWriter::open(&path).unwrap();
+        assert_eq!(LedgerReader::open(&path).unwrap().len().unwrap(), 2);
+        writer.write_entry(&entries[2]).unwrap();
+        let mut reader = LedgerReader::open(&path).unwrap();
+        let read: Vec<_> = reader.entries_from(0).unwrap().map(|x| x.unwrap()).collect();
+        assert_eq!(read, entries);
+        remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_recover_torn_index() {
+        let path = tmp_ledger_path("recover_torn_index");
+        let entries = make_entries(3);
+        {
+            let mut writer = LedgerWriter::open(&path).unwrap();
+            for entry in &entries {
+                writer.write_entry(entry).unwrap();
+            }
+        }
+
+        // Simulate a crash halfway through writing the last offset.
+        let index = OpenOptions::new()
+            .write(true)
+            .open(index_path(&path))
+            .unwrap();
+        index.set_len(2 * INDEX_ENTRY_SIZE + 3).unwrap();
+
+        let mut writer = LedgerWriter::open(&path).unwrap();
+        writer.write_entry(&entries[2]).unwrap();
+        let mut reader = LedgerReader::open(&path).unwrap();
+        let read: Vec<_> = reader.entries_from(0).unwrap().map(|x| x.unwrap()).collect();
+        assert_eq!(read, entries);
+        remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_json_entry_writer() {
+        let entries = make_entries(2);
+        let mut buf = vec![];
+        for entry in &entries {
+            buf.write_entry(entry).unwrap();
+        }
+        let text = String::from_utf8(buf).unwrap();
+        let read: Vec<Entry> = text.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(read, entries);
+    }
+}
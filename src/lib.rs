@@ -1,21 +1,24 @@
 #![cfg_attr(feature = "unstable", feature(test))]
+pub mod account_history;
 pub mod accountant;
 pub mod accounting_stage;
 pub mod crdt;
 pub mod ecdsa;
 pub mod entry;
-#[cfg(feature = "erasure")]
 pub mod erasure;
 pub mod event;
 pub mod hash;
 pub mod historian;
+pub mod leader_schedule;
 pub mod ledger;
+pub mod ledger_store;
 pub mod logger;
 pub mod mint;
 pub mod packet;
 pub mod plan;
 pub mod recorder;
 pub mod result;
+pub mod rpc;
 pub mod signature;
 pub mod streamer;
 pub mod thin_client;
@@ -2,7 +2,7 @@
 use bincode::{deserialize, serialize};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use result::{Error, Result};
-use signature::PublicKey;
+use signature::{KeyPair, PublicKey, Signature, SignatureUtil};
 use std::collections::VecDeque;
 use std::fmt;
 use std::io;
@@ -17,8 +17,10 @@ pub type BlobRecycler = Recycler<Blob>;
 
 pub const NUM_PACKETS: usize = 1024 * 8;
 pub const BLOB_SIZE: usize = 64 * 1024;
-pub const BLOB_DATA_SIZE: usize = BLOB_SIZE - BLOB_ID_END;
-pub const PACKET_DATA_SIZE: usize = 256;
+pub const BLOB_DATA_SIZE: usize = BLOB_SIZE - BLOB_HEADER_SIZE;
+/// Big enough for a transaction making `plan::MAX_PAYMENTS` payments
+pub const PACKET_DATA_SIZE: usize = 512;
+/// The blobs in the window, 64 of them with 512 byte packets
 pub const NUM_BLOBS: usize = (NUM_PACKETS * PACKET_DATA_SIZE) / BLOB_SIZE;
 
 #[derive(Clone, Default)]
@@ -216,19 +218,32 @@ impl Packets {
     }
 }
 
-const BLOB_INDEX_END: usize = size_of::<u64>();
+/// The leader's signature of everything in the blob after it
+const BLOB_SIG_END: usize = size_of::<Signature>();
+const BLOB_INDEX_END: usize = BLOB_SIG_END + size_of::<u64>();
 const BLOB_ID_END: usize = BLOB_INDEX_END + size_of::<usize>() + size_of::<PublicKey>();
+/// End of the blob flags, and the start of the bytes covered by erasure coding.
+pub const BLOB_FLAGS_END: usize = BLOB_ID_END + size_of::<u64>();
+const BLOB_SIZE_END: usize = BLOB_FLAGS_END + size_of::<u64>();
+pub const BLOB_HEADER_SIZE: usize = BLOB_SIZE_END;
+
+/// Set on blobs that carry erasure codes rather than entries.
+/// The erasure set of either kind of blob follows from its index, see `erasure`.
+pub const BLOB_FLAG_IS_CODING: u64 = 0x1;
+/// Set on data blobs rebuilt from erasure codes. They carry the coding blob's
+/// signature, so they are kept for this node only and never sent out.
+pub const BLOB_FLAG_IS_RECOVERED: u64 = 0x2;
 
 impl Blob {
     pub fn get_index(&self) -> Result<u64> {
-        let mut rdr = io::Cursor::new(&self.data[0..BLOB_INDEX_END]);
+        let mut rdr = io::Cursor::new(&self.data[BLOB_SIG_END..BLOB_INDEX_END]);
         let r = rdr.read_u64::<LittleEndian>()?;
         Ok(r)
     }
     pub fn set_index(&mut self, ix: u64) -> Result<()> {
         let mut wtr = vec![];
         wtr.write_u64::<LittleEndian>(ix)?;
-        self.data[..BLOB_INDEX_END].clone_from_slice(&wtr);
+        self.data[BLOB_SIG_END..BLOB_INDEX_END].clone_from_slice(&wtr);
         Ok(())
     }
 
@@ -243,14 +258,71 @@ impl Blob {
         Ok(())
     }
 
+    pub fn get_flags(&self) -> Result<u64> {
+        let mut rdr = io::Cursor::new(&self.data[BLOB_ID_END..BLOB_FLAGS_END]);
+        let r = rdr.read_u64::<LittleEndian>()?;
+        Ok(r)
+    }
+    pub fn set_flags(&mut self, flags: u64) -> Result<()> {
+        let mut wtr = vec![];
+        wtr.write_u64::<LittleEndian>(flags)?;
+        self.data[BLOB_ID_END..BLOB_FLAGS_END].clone_from_slice(&wtr);
+        Ok(())
+    }
+
+    pub fn is_coding(&self) -> bool {
+        self.get_flags().map(|f| f & BLOB_FLAG_IS_CODING != 0).unwrap_or(false)
+    }
+    pub fn set_coding(&mut self) -> Result<()> {
+        let flags = self.get_flags()?;
+        self.set_flags(flags | BLOB_FLAG_IS_CODING)
+    }
+
+    pub fn is_recovered(&self) -> bool {
+        self.get_flags()
+            .map(|f| f & BLOB_FLAG_IS_RECOVERED != 0)
+            .unwrap_or(false)
+    }
+
+    /// The size of the whole blob as recorded in its header, so erasure recovery can
+    /// restore `meta.size`
+    pub fn get_data_size(&self) -> Result<u64> {
+        let mut rdr = io::Cursor::new(&self.data[BLOB_FLAGS_END..BLOB_SIZE_END]);
+        let r = rdr.read_u64::<LittleEndian>()?;
+        Ok(r)
+    }
+    pub fn set_data_size(&mut self, size: u64) -> Result<()> {
+        let mut wtr = vec![];
+        wtr.write_u64::<LittleEndian>(size)?;
+        self.data[BLOB_FLAGS_END..BLOB_SIZE_END].clone_from_slice(&wtr);
+        Ok(())
+    }
+
+    /// Sign the header and payload, so it has to be done after they are final
+    pub fn sign(&mut self, keypair: &KeyPair) {
+        let sig = keypair.sign(&self.data[BLOB_SIG_END..self.meta.size]);
+        self.data[..BLOB_SIG_END].copy_from_slice(sig.as_ref());
+    }
+    /// Check the signature against the id in the header
+    pub fn verify(&self) -> bool {
+        if self.meta.size < BLOB_HEADER_SIZE {
+            return false;
+        }
+        let sig = Signature::clone_from_slice(&self.data[..BLOB_SIG_END]);
+        match self.get_id() {
+            Ok(id) => sig.verify(&id, &self.data[BLOB_SIG_END..self.meta.size]),
+            Err(_) => false,
+        }
+    }
+
     pub fn data(&self) -> &[u8] {
-        &self.data[BLOB_ID_END..]
+        &self.data[BLOB_HEADER_SIZE..]
     }
     pub fn data_mut(&mut self) -> &mut [u8] {
-        &mut self.data[BLOB_ID_END..]
+        &mut self.data[BLOB_HEADER_SIZE..]
     }
     pub fn set_size(&mut self, size: usize) {
-        self.meta.size = size + BLOB_ID_END;
+        self.meta.size = size + BLOB_HEADER_SIZE;
     }
     pub fn recv_from(re: &BlobRecycler, socket: &UdpSocket) -> Result<VecDeque<SharedBlob>> {
         let mut v = VecDeque::new();
@@ -307,6 +379,7 @@ impl Blob {
 #[cfg(test)]
 mod test {
     use packet::{Blob, BlobRecycler, Packet, PacketRecycler, Packets};
+    use signature::{KeyPair, KeyPairUtil};
     use std::collections::VecDeque;
     use std::io;
     use std::io::Write;
@@ -410,5 +483,43 @@ mod test {
         assert_eq!(b.data()[0], 1);
         assert_eq!(b.get_index().unwrap(), <u64>::max_value());
     }
+    #[test]
+    pub fn blob_flags_test() {
+        let mut b = Blob::default();
+        b.set_index(7).unwrap();
+        b.set_flags(0).unwrap();
+        assert!(!b.is_coding());
+        b.set_coding().unwrap();
+        assert!(b.is_coding());
+        b.set_data_size(1024).unwrap();
+        assert_eq!(b.get_data_size().unwrap(), 1024);
+        assert!(b.is_coding());
+        assert_eq!(b.get_index().unwrap(), 7);
+    }
+
+    #[test]
+    pub fn blob_sign_test() {
+        let keypair = KeyPair::new();
+        let mut b = Blob::default();
+        b.set_index(7).unwrap();
+        b.set_id(keypair.pubkey()).unwrap();
+        b.set_size(10);
+        assert!(!b.verify());
+        b.sign(&keypair);
+        assert!(b.verify());
+
+        // the header and the payload are both signed
+        b.set_index(8).unwrap();
+        assert!(!b.verify());
+        b.set_index(7).unwrap();
+        b.data_mut()[0] = 1;
+        assert!(!b.verify());
+        b.data_mut()[0] = 0;
+        assert!(b.verify());
+
+        // the signature has to come from the id in the header
+        b.set_id(KeyPair::new().pubkey()).unwrap();
+        assert!(!b.verify());
+    }
 
 }
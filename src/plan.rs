@@ -4,18 +4,28 @@
 //! `Payment`, the payment is executed.
 
 use chrono::prelude::*;
+use hash::{hash, Hash};
 use signature::PublicKey;
-use std::mem;
+use std::collections::HashSet;
 
 pub enum Witness {
     Timestamp(DateTime<Utc>),
     Signature(PublicKey),
+    Preimage(Vec<u8>),
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub enum Condition {
     Timestamp(DateTime<Utc>),
     Signature(PublicKey),
+    /// Satisfied once `threshold` more of `keys` have signed. Keys are removed
+    /// as their signatures arrive, so a signer can only be counted once.
+    MultiSig {
+        threshold: u64,
+        keys: Vec<PublicKey>,
+    },
+    /// Satisfied by revealing data that hashes to the given Hash.
+    HashLock(Hash),
 }
 
 impl Condition {
@@ -24,9 +34,40 @@ impl Condition {
         match (self, witness) {
             (&Condition::Signature(ref pubkey), &Witness::Signature(ref from)) => pubkey == from,
             (&Condition::Timestamp(ref dt), &Witness::Timestamp(ref last_time)) => dt <= last_time,
+            (&Condition::MultiSig { threshold, .. }, _) => threshold == 0,
+            (&Condition::HashLock(ref h), &Witness::Preimage(ref preimage)) => {
+                hash(preimage) == *h
+            }
             _ => false,
         }
     }
+
+    /// Record partial progress toward satisfying this Condition.
+    pub fn apply_witness(&mut self, witness: &Witness) {
+        if let Condition::MultiSig {
+            ref mut threshold,
+            ref mut keys,
+        } = *self
+        {
+            if let Witness::Signature(ref from) = *witness {
+                if let Some(i) = keys.iter().position(|key| key == from) {
+                    keys.remove(i);
+                    *threshold = threshold.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    /// Return true if the Condition can ever be satisfied.
+    pub fn verify(&self) -> bool {
+        match *self {
+            Condition::MultiSig { threshold, ref keys } => {
+                let unique: HashSet<_> = keys.iter().collect();
+                threshold > 0 && threshold <= keys.len() as u64 && unique.len() == keys.len()
+            }
+            _ => true,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
@@ -35,12 +76,34 @@ pub struct Payment {
     pub to: PublicKey,
 }
 
+/// Return the sum of the payments' tokens, or None if it overflows.
+pub fn total_tokens(payments: &[Payment]) -> Option<i64> {
+    payments
+        .iter()
+        .fold(Some(0), |total: Option<i64>, x| total?.checked_add(x.tokens))
+}
+
+/// Plans nested deeper than this are rejected by `Plan::verify`.
+pub const MAX_PLAN_DEPTH: usize = 8;
+
+/// Plans with more conditions and payments than this are rejected by `Plan::verify`.
+pub const MAX_PLAN_SIZE: usize = 32;
+
+/// Multi-payments with more payments than this are rejected by `Plan::verify`, so
+/// that the transaction making them fits in a packet. Paying more recipients takes
+/// several transactions.
+pub const MAX_PAYMENTS: usize = 6;
+
+/// A spending plan. Conditions guarding a nested plan are only checked once the
+/// outer conditions have been satisfied and the nested plan is exposed.
 #[repr(C)]
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub enum Plan {
     Pay(Payment),
-    After(Condition, Payment),
-    Race((Condition, Payment), (Condition, Payment)),
+    PayMany(Vec<Payment>),
+    After(Condition, Box<Plan>),
+    And(Vec<Condition>, Box<Plan>),
+    Race(Vec<(Condition, Plan)>),
 }
 
 impl Plan {
@@ -49,14 +112,53 @@ impl Plan {
         Plan::Pay(Payment { tokens, to })
     }
 
+    /// Create a spending plan that makes all the given payments at once.
+    pub fn new_multi_payment(payments: Vec<Payment>) -> Self {
+        Plan::PayMany(payments)
+    }
+
     /// Create a spending plan that pays `tokens` to `to` after being witnessed by `from`.
     pub fn new_authorized_payment(from: PublicKey, tokens: i64, to: PublicKey) -> Self {
-        Plan::After(Condition::Signature(from), Payment { tokens, to })
+        Plan::After(
+            Condition::Signature(from),
+            Box::new(Plan::new_payment(tokens, to)),
+        )
+    }
+
+    /// Create a spending plan that pays `tokens` to `to` once `threshold` of `keys` have signed.
+    pub fn new_multisig_payment(
+        threshold: u64,
+        keys: Vec<PublicKey>,
+        tokens: i64,
+        to: PublicKey,
+    ) -> Self {
+        Plan::After(
+            Condition::MultiSig { threshold, keys },
+            Box::new(Plan::new_payment(tokens, to)),
+        )
     }
 
     /// Create a spending plan that pays `tokens` to `to` after the given DateTime.
     pub fn new_future_payment(dt: DateTime<Utc>, tokens: i64, to: PublicKey) -> Self {
-        Plan::After(Condition::Timestamp(dt), Payment { tokens, to })
+        Plan::After(
+            Condition::Timestamp(dt),
+            Box::new(Plan::new_payment(tokens, to)),
+        )
+    }
+
+    /// Create a hashed timelock contract. The plan pays `tokens` to `to` once
+    /// someone reveals the preimage of `lock`, or refunds `from` after the given DateTime.
+    pub fn new_htlc_payment(
+        lock: Hash,
+        dt: DateTime<Utc>,
+        from: PublicKey,
+        tokens: i64,
+        to: PublicKey,
+    ) -> Self {
+        Plan::Race(vec![
+            (Condition::HashLock(lock), Plan::new_payment(tokens, to)),
+            (Condition::Timestamp(dt), Plan::new_payment(tokens, from)),
+        ])
     }
 
     /// Create a spending plan that pays `tokens` to `to` after the given DateTime
@@ -67,44 +169,118 @@ impl Plan {
         tokens: i64,
         to: PublicKey,
     ) -> Self {
-        Plan::Race(
-            (Condition::Timestamp(dt), Payment { tokens, to }),
-            (Condition::Signature(from), Payment { tokens, to: from }),
-        )
+        Plan::Race(vec![
+            (Condition::Timestamp(dt), Plan::new_payment(tokens, to)),
+            (Condition::Signature(from), Plan::new_payment(tokens, from)),
+        ])
     }
 
-    /// Return Payment if the spending plan requires no additional Witnesses.
-    pub fn final_payment(&self) -> Option<Payment> {
+    /// Return the Payments if the spending plan requires no additional Witnesses.
+    pub fn final_payments(&self) -> Option<Vec<Payment>> {
         match *self {
-            Plan::Pay(ref payment) => Some(payment.clone()),
+            Plan::Pay(ref payment) => Some(vec![payment.clone()]),
+            Plan::PayMany(ref payments) => Some(payments.clone()),
             _ => None,
         }
     }
 
-    /// Return true if the plan spends exactly `spendable_tokens`.
-    pub fn verify(&self, spendable_tokens: i64) -> bool {
+    /// Return every key the plan may pay to, whichever way it is reduced.
+    pub fn payees(&self) -> Vec<PublicKey> {
+        match *self {
+            Plan::Pay(ref payment) => vec![payment.to],
+            Plan::PayMany(ref payments) => payments.iter().map(|x| x.to).collect(),
+            Plan::After(_, ref plan) | Plan::And(_, ref plan) => plan.payees(),
+            Plan::Race(ref arms) => arms.iter().flat_map(|x| x.1.payees()).collect(),
+        }
+    }
+
+    /// Return the number of conditions and payments in the plan, or None if the
+    /// plan is nested deeper than `MAX_PLAN_DEPTH`.
+    fn size(&self, depth: usize) -> Option<usize> {
+        if depth >= MAX_PLAN_DEPTH {
+            return None;
+        }
         match *self {
-            Plan::Pay(ref payment) | Plan::After(_, ref payment) => {
-                payment.tokens == spendable_tokens
+            Plan::Pay(_) => Some(1),
+            Plan::PayMany(ref payments) => Some(payments.len()),
+            Plan::After(_, ref plan) => plan.size(depth + 1).map(|n| n + 1),
+            Plan::And(ref conds, ref plan) => plan.size(depth + 1).map(|n| n + conds.len()),
+            Plan::Race(ref arms) => arms.iter().fold(Some(0), |total, &(_, ref plan)| {
+                Some(total? + plan.size(depth + 1)? + 1)
+            }),
+        }
+    }
+
+    /// Return true if every branch of the plan spends exactly `spendable_tokens`.
+    fn spends(&self, spendable_tokens: i64) -> bool {
+        match *self {
+            Plan::Pay(ref payment) => payment.tokens == spendable_tokens,
+            Plan::PayMany(ref payments) => {
+                // No payment may be negative, otherwise one recipient could fund the others.
+                !payments.is_empty() && payments.len() <= MAX_PAYMENTS
+                    && payments.iter().all(|x| x.tokens >= 0)
+                    && total_tokens(payments) == Some(spendable_tokens)
             }
-            Plan::Race(ref a, ref b) => {
-                a.1.tokens == spendable_tokens && b.1.tokens == spendable_tokens
+            Plan::After(ref cond, ref plan) => cond.verify() && plan.spends(spendable_tokens),
+            Plan::And(ref conds, ref plan) => {
+                !conds.is_empty() && conds.iter().all(|cond| cond.verify())
+                    && plan.spends(spendable_tokens)
+            }
+            Plan::Race(ref arms) => {
+                !arms.is_empty()
+                    && arms.iter()
+                        .all(|&(ref cond, ref plan)| cond.verify() && plan.spends(spendable_tokens))
             }
         }
     }
 
+    /// Return true if the plan is small enough to interpret and spends exactly
+    /// `spendable_tokens` whichever way it is reduced.
+    pub fn verify(&self, spendable_tokens: i64) -> bool {
+        match self.size(0) {
+            Some(size) if size <= MAX_PLAN_SIZE => self.spends(spendable_tokens),
+            _ => false,
+        }
+    }
+
     /// Apply a witness to the spending plan to see if the plan can be reduced.
     /// If so, modify the plan in-place.
     pub fn apply_witness(&mut self, witness: &Witness) {
-        let new_payment = match *self {
-            Plan::After(ref cond, ref payment) if cond.is_satisfied(witness) => Some(payment),
-            Plan::Race((ref cond, ref payment), _) if cond.is_satisfied(witness) => Some(payment),
-            Plan::Race(_, (ref cond, ref payment)) if cond.is_satisfied(witness) => Some(payment),
+        let next_plan = match *self {
+            Plan::After(ref mut cond, ref plan) => {
+                cond.apply_witness(witness);
+                if cond.is_satisfied(witness) {
+                    Some((**plan).clone())
+                } else {
+                    None
+                }
+            }
+            Plan::And(ref mut conds, ref plan) => {
+                for cond in conds.iter_mut() {
+                    cond.apply_witness(witness);
+                }
+                conds.retain(|cond| !cond.is_satisfied(witness));
+                if conds.is_empty() {
+                    Some((**plan).clone())
+                } else {
+                    None
+                }
+            }
+            Plan::Race(ref mut arms) => {
+                for &mut (ref mut cond, _) in arms.iter_mut() {
+                    cond.apply_witness(witness);
+                }
+                arms.iter()
+                    .find(|&&(ref cond, _)| cond.is_satisfied(witness))
+                    .map(|&(_, ref plan)| plan.clone())
+            }
             _ => None,
-        }.cloned();
+        };
 
-        if let Some(payment) = new_payment {
-            mem::replace(self, Plan::Pay(payment));
+        if let Some(plan) = next_plan {
+            *self = plan;
+            // The same witness may satisfy the conditions it just exposed.
+            self.apply_witness(witness);
         }
     }
 }
@@ -128,6 +304,14 @@ mod tests {
         assert!(!Condition::Timestamp(dt2).is_satisfied(&Witness::Timestamp(dt1)));
     }
 
+    #[test]
+    fn test_hash_lock_satisfied() {
+        let lock = hash(b"secret");
+        assert!(Condition::HashLock(lock).is_satisfied(&Witness::Preimage(b"secret".to_vec())));
+        assert!(!Condition::HashLock(lock).is_satisfied(&Witness::Preimage(b"guess".to_vec())));
+        assert!(!Condition::HashLock(lock).is_satisfied(&Witness::Signature(PublicKey::default())));
+    }
+
     #[test]
     fn test_verify_plan() {
         let dt = Utc.ymd(2014, 11, 14).and_hms(8, 9, 10);
@@ -139,6 +323,180 @@ mod tests {
         assert!(Plan::new_cancelable_future_payment(dt, from, 42, to).verify(42));
     }
 
+    #[test]
+    fn test_verify_multisig() {
+        let keys: Vec<_> = (0..3u8)
+            .map(|i| PublicKey::clone_from_slice(&[i; 32]))
+            .collect();
+        let to = PublicKey::default();
+        assert!(Plan::new_multisig_payment(2, keys.clone(), 42, to).verify(42));
+        assert!(Plan::new_multisig_payment(3, keys.clone(), 42, to).verify(42));
+        assert!(!Plan::new_multisig_payment(0, keys.clone(), 42, to).verify(42));
+        assert!(!Plan::new_multisig_payment(4, keys.clone(), 42, to).verify(42));
+
+        // A duplicate key would let one signer count twice.
+        let dup_keys = vec![keys[0], keys[0], keys[1]];
+        assert!(!Plan::new_multisig_payment(2, dup_keys, 42, to).verify(42));
+    }
+
+    #[test]
+    fn test_multisig_payment() {
+        let keys: Vec<_> = (0..3u8)
+            .map(|i| PublicKey::clone_from_slice(&[i; 32]))
+            .collect();
+        let to = PublicKey::default();
+
+        let mut plan = Plan::new_multisig_payment(2, keys.clone(), 42, to);
+        plan.apply_witness(&Witness::Signature(keys[0]));
+        assert_eq!(plan.final_payments(), None);
+
+        // Signing twice doesn't count twice.
+        plan.apply_witness(&Witness::Signature(keys[0]));
+        assert_eq!(plan.final_payments(), None);
+
+        plan.apply_witness(&Witness::Signature(to));
+        assert_eq!(plan.final_payments(), None);
+
+        plan.apply_witness(&Witness::Signature(keys[2]));
+        assert_eq!(plan, Plan::new_payment(42, to));
+    }
+
+    #[test]
+    fn test_verify_nested_plan() {
+        let dt = Utc.ymd(2014, 11, 14).and_hms(8, 9, 10);
+        let from = PublicKey::default();
+        let to = PublicKey::default();
+        let plan = Plan::Race(vec![
+            (
+                Condition::Timestamp(dt),
+                Plan::new_authorized_payment(from, 42, to),
+            ),
+            (Condition::Signature(from), Plan::new_payment(42, from)),
+            (
+                Condition::Signature(to),
+                Plan::new_multi_payment(vec![
+                    Payment { tokens: 21, to },
+                    Payment { tokens: 21, to: from },
+                ]),
+            ),
+        ]);
+        assert!(plan.verify(42));
+
+        // Every branch must spend the same amount.
+        let plan = Plan::Race(vec![
+            (Condition::Timestamp(dt), Plan::new_payment(42, to)),
+            (
+                Condition::Signature(from),
+                Plan::new_authorized_payment(from, 41, from),
+            ),
+        ]);
+        assert!(!plan.verify(42));
+
+        assert!(!Plan::Race(vec![]).verify(42));
+        assert!(!Plan::And(vec![], Box::new(Plan::new_payment(42, to))).verify(42));
+    }
+
+    #[test]
+    fn test_verify_plan_limits() {
+        let from = PublicKey::default();
+        let to = PublicKey::default();
+        let mut plan = Plan::new_payment(42, to);
+        for _ in 0..MAX_PLAN_DEPTH - 1 {
+            plan = Plan::After(Condition::Signature(from), Box::new(plan));
+        }
+        assert!(plan.verify(42));
+        let plan = Plan::After(Condition::Signature(from), Box::new(plan));
+        assert!(!plan.verify(42));
+
+        let arms = (0..MAX_PLAN_SIZE / 2)
+            .map(|_| (Condition::Signature(from), Plan::new_payment(42, to)))
+            .collect();
+        assert!(Plan::Race(arms).verify(42));
+        let arms = (0..MAX_PLAN_SIZE / 2 + 1)
+            .map(|_| (Condition::Signature(from), Plan::new_payment(42, to)))
+            .collect();
+        assert!(!Plan::Race(arms).verify(42));
+    }
+
+    #[test]
+    fn test_and_payment() {
+        let dt = Utc.ymd(2014, 11, 14).and_hms(8, 9, 10);
+        let from = PublicKey::default();
+        let to = PublicKey::default();
+        let conds = vec![Condition::Timestamp(dt), Condition::Signature(from)];
+        let and_plan = Plan::And(conds, Box::new(Plan::new_payment(42, to)));
+
+        let mut plan = and_plan.clone();
+        plan.apply_witness(&Witness::Signature(from));
+        assert_eq!(plan.final_payments(), None);
+        plan.apply_witness(&Witness::Timestamp(dt));
+        assert_eq!(plan, Plan::new_payment(42, to));
+
+        // Order doesn't matter.
+        let mut plan = and_plan.clone();
+        plan.apply_witness(&Witness::Timestamp(dt));
+        assert_eq!(plan.final_payments(), None);
+        plan.apply_witness(&Witness::Signature(from));
+        assert_eq!(plan, Plan::new_payment(42, to));
+    }
+
+    #[test]
+    fn test_nested_payment() {
+        let dt = Utc.ymd(2014, 11, 14).and_hms(8, 9, 10);
+        let from = PublicKey::clone_from_slice(&[1; 32]);
+        let to = PublicKey::default();
+        let mut plan = Plan::Race(vec![
+            (
+                Condition::Signature(to),
+                Plan::new_future_payment(dt, 42, to),
+            ),
+            (Condition::Signature(from), Plan::new_payment(42, from)),
+        ]);
+
+        // Nested conditions aren't checked until they are exposed.
+        plan.apply_witness(&Witness::Timestamp(dt));
+        assert_eq!(plan.final_payments(), None);
+
+        plan.apply_witness(&Witness::Signature(to));
+        assert_eq!(plan, Plan::new_future_payment(dt, 42, to));
+        plan.apply_witness(&Witness::Timestamp(dt));
+        assert_eq!(plan, Plan::new_payment(42, to));
+    }
+
+    #[test]
+    fn test_verify_multi_payment() {
+        let to0 = PublicKey::default();
+        let to1 = PublicKey::default();
+        let plan = Plan::new_multi_payment(vec![
+            Payment { tokens: 40, to: to0 },
+            Payment { tokens: 2, to: to1 },
+        ]);
+        assert!(plan.verify(42));
+        assert!(!plan.verify(41));
+        assert!(!Plan::new_multi_payment(vec![]).verify(0));
+
+        // A negative payment would debit the recipient.
+        let plan = Plan::new_multi_payment(vec![
+            Payment { tokens: 43, to: to0 },
+            Payment { tokens: -1, to: to1 },
+        ]);
+        assert!(!plan.verify(42));
+
+        // Payments that overflow can't add up to what's spent.
+        let plan = Plan::new_multi_payment(vec![
+            Payment { tokens: i64::MAX, to: to0 },
+            Payment { tokens: i64::MAX, to: to1 },
+            Payment { tokens: 3, to: to1 },
+        ]);
+        assert!(!plan.verify(1));
+
+        // Too many payments to fit in a packet.
+        let plan = Plan::new_multi_payment(vec![Payment { tokens: 1, to: to0 }; MAX_PAYMENTS]);
+        assert!(plan.verify(MAX_PAYMENTS as i64));
+        let plan = Plan::new_multi_payment(vec![Payment { tokens: 1, to: to0 }; MAX_PAYMENTS + 1]);
+        assert!(!plan.verify(MAX_PAYMENTS as i64 + 1));
+    }
+
     #[test]
     fn test_authorized_payment() {
         let from = PublicKey::default();
@@ -159,6 +517,25 @@ mod tests {
         assert_eq!(plan, Plan::new_payment(42, to));
     }
 
+    #[test]
+    fn test_htlc_payment() {
+        let dt = Utc.ymd(2014, 11, 14).and_hms(8, 9, 10);
+        let lock = hash(b"secret");
+        let from = PublicKey::clone_from_slice(&[1; 32]);
+        let to = PublicKey::default();
+        assert!(Plan::new_htlc_payment(lock, dt, from, 42, to).verify(42));
+
+        let mut plan = Plan::new_htlc_payment(lock, dt, from, 42, to);
+        plan.apply_witness(&Witness::Preimage(b"guess".to_vec()));
+        assert_eq!(plan.final_payments(), None);
+        plan.apply_witness(&Witness::Preimage(b"secret".to_vec()));
+        assert_eq!(plan, Plan::new_payment(42, to));
+
+        let mut plan = Plan::new_htlc_payment(lock, dt, from, 42, to);
+        plan.apply_witness(&Witness::Timestamp(dt));
+        assert_eq!(plan, Plan::new_payment(42, from));
+    }
+
     #[test]
     fn test_cancelable_future_payment() {
         let dt = Utc.ymd(2014, 11, 14).and_hms(8, 9, 10);
@@ -2,6 +2,7 @@
 
 use accountant;
 use bincode;
+use erasure;
 use serde_json;
 use std;
 use std::any::Any;
@@ -16,6 +17,8 @@ pub enum Error {
     RecvTimeoutError(std::sync::mpsc::RecvTimeoutError),
     Serialize(std::boxed::Box<bincode::ErrorKind>),
     AccountingError(accountant::AccountingError),
+    ErasureError(erasure::ErasureError),
+    InvalidEntries,
     SendError,
     Services,
     GeneralError,
@@ -38,6 +41,11 @@ impl std::convert::From<accountant::AccountingError> for Error {
         Error::AccountingError(e)
     }
 }
+impl std::convert::From<erasure::ErasureError> for Error {
+    fn from(e: erasure::ErasureError) -> Error {
+        Error::ErasureError(e)
+    }
+}
 impl<T> std::convert::From<std::sync::mpsc::SendError<T>> for Error {
     fn from(_e: std::sync::mpsc::SendError<T>) -> Error {
         Error::SendError
@@ -0,0 +1,377 @@
+//! The `rpc` module serves a JSON-RPC 2.0 API over HTTP, for clients that can't
+//! speak the thin client's UDP protocol. Its methods mirror the thin client
+//! requests and are answered by the same `ThinClientService` and
+//! `AccountingStage`:
+//!
+//! * `getBalance [key]` returns the account's balance, or null
+//! * `getLastId []` returns the last Entry ID
+//! * `getTransactionCount []` returns the number of events recorded
+//! * `sendTransaction [transaction]` returns the transaction's signature
+//! * `getSignatureStatus [sig]` returns the transaction's `SignatureStatus`
+//!
+//! Keys, signatures, hashes and transactions are JSON-encoded the way they are
+//! serialized everywhere else, so byte arrays are arrays of numbers.
+
+use accounting_stage::AccountingStage;
+use event::Event;
+use result::Result;
+use serde::de::DeserializeOwned;
+use serde_json::{self, Value};
+use signature::{PublicKey, Signature};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::result;
+use std::time::{Duration, Instant};
+use thin_client_service::{Request, Response, ThinClientService};
+use transaction::Transaction;
+
+/// The largest request body accepted
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// The longest request line and headers accepted, all together
+const MAX_HEADER_SIZE: u64 = 8 * 1024;
+
+/// How long a client has to send its whole request, and to take the response
+const REQUEST_TIMEOUT_MS: u64 = 5_000;
+
+// Error codes defined by JSON-RPC 2.0
+const PARSE_ERROR: i64 = -32_700;
+const INVALID_REQUEST: i64 = -32_600;
+const METHOD_NOT_FOUND: i64 = -32_601;
+const INVALID_PARAMS: i64 = -32_602;
+const INTERNAL_ERROR: i64 = -32_603;
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Serialize, Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+/// Decode the `i`th positional parameter.
+fn param<T: DeserializeOwned>(params: &Value, i: usize) -> result::Result<T, RpcError> {
+    params
+        .get(i)
+        .and_then(|x| serde_json::from_value(x.clone()).ok())
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, "invalid params"))
+}
+
+fn to_value<T: ::serde::Serialize>(x: T) -> result::Result<Value, RpcError> {
+    serde_json::to_value(x).map_err(|_| RpcError::new(INTERNAL_ERROR, "internal error"))
+}
+
+/// Answer the call to `method`. Requests the thin client service already
+/// answers are forwarded to it, so both protocols return the same results.
+fn process_method(
+    thin_client_service: &ThinClientService,
+    accounting_stage: &AccountingStage,
+    method: &str,
+    params: &Value,
+    rsp_addr: SocketAddr,
+) -> result::Result<Value, RpcError> {
+    match method {
+        "getBalance" => {
+            let key: PublicKey = param(params, 0)?;
+            match thin_client_service.process_request(Request::GetBalance { key }, rsp_addr) {
+                Some((Response::Balance { val, .. }, _)) => to_value(val),
+                _ => Err(RpcError::new(INTERNAL_ERROR, "internal error")),
+            }
+        }
+        "getLastId" => to_value(accounting_stage.accountant.last_id()),
+        "getTransactionCount" => to_value(thin_client_service.transaction_count()),
+        "sendTransaction" => {
+            let tr: Transaction = param(params, 0)?;
+            if !tr.verify_sig() || !tr.verify_plan() {
+                return Err(RpcError::new(INVALID_PARAMS, "invalid transaction"));
+            }
+            let sig = tr.sig;
+            accounting_stage
+                .process_events(vec![Event::Transaction(tr)])
+                .map_err(|_| RpcError::new(INTERNAL_ERROR, "internal error"))?;
+            to_value(sig)
+        }
+        "getSignatureStatus" => {
+            let sig: Signature = param(params, 0)?;
+            let req = Request::GetSignatureStatus { sig };
+            match thin_client_service.process_request(req, rsp_addr) {
+                Some((Response::SignatureStatus { status, .. }, _)) => to_value(status),
+                _ => Err(RpcError::new(INTERNAL_ERROR, "internal error")),
+            }
+        }
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, "method not found")),
+    }
+}
+
+fn process_body(
+    thin_client_service: &ThinClientService,
+    accounting_stage: &AccountingStage,
+    body: &[u8],
+    rsp_addr: SocketAddr,
+) -> RpcResponse {
+    let (id, result) = match serde_json::from_slice::<RpcRequest>(body) {
+        Err(_) => (Value::Null, Err(RpcError::new(PARSE_ERROR, "parse error"))),
+        Ok(ref req) if req.jsonrpc != "2.0" => (
+            req.id.clone(),
+            Err(RpcError::new(INVALID_REQUEST, "invalid request")),
+        ),
+        Ok(req) => {
+            let result = process_method(
+                thin_client_service,
+                accounting_stage,
+                &req.method,
+                &req.params,
+                rsp_addr,
+            );
+            (req.id, result)
+        }
+    };
+    let (result, error) = match result {
+        Ok(x) => (Some(x), None),
+        Err(e) => (None, Some(e)),
+    };
+    RpcResponse {
+        jsonrpc: "2.0",
+        id,
+        result,
+        error,
+    }
+}
+
+/// Reads from a TcpStream until `deadline`, so that a client can't hold on to
+/// a connection by sending its request a byte at a time.
+struct DeadlineStream {
+    stream: TcpStream,
+    deadline: Instant,
+}
+
+impl Read for DeadlineStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out"));
+        }
+        self.stream.set_read_timeout(Some(self.deadline - now))?;
+        self.stream.read(buf)
+    }
+}
+
+/// Read an HTTP POST and return its body, or `None` if it isn't one or its
+/// headers are longer than `MAX_HEADER_SIZE`.
+fn read_http_body<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let content_length = {
+        let mut headers = reader.by_ref().take(MAX_HEADER_SIZE);
+        let mut line = String::new();
+        headers.read_line(&mut line)?;
+        if !line.starts_with("POST ") {
+            return Ok(None);
+        }
+        let mut content_length = None;
+        loop {
+            line.clear();
+            // A line cut off by the limit doesn't end the headers.
+            if headers.read_line(&mut line)? == 0 || !line.ends_with('\n') {
+                return Ok(None);
+            }
+            let header = line.trim_right();
+            if header.is_empty() {
+                break;
+            }
+            let mut parts = header.splitn(2, ':');
+            let name = parts.next().unwrap_or("");
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = parts.next().and_then(|x| x.trim().parse::<usize>().ok());
+            }
+        }
+        content_length
+    };
+    match content_length {
+        Some(len) if len <= MAX_BODY_SIZE => {
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body)?;
+            Ok(Some(body))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn write_http_response<W: Write>(writer: &mut W, status: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        body.len()
+    )?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+/// Answer the one JSON-RPC request sent over `stream`.
+pub fn process_connection(
+    thin_client_service: &ThinClientService,
+    accounting_stage: &AccountingStage,
+    mut stream: TcpStream,
+) -> Result<()> {
+    let timeout = Duration::from_millis(REQUEST_TIMEOUT_MS);
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(timeout))?;
+    let rsp_addr = stream.peer_addr()?;
+    let reader = DeadlineStream {
+        stream: stream.try_clone()?,
+        deadline: Instant::now() + timeout,
+    };
+    let body = read_http_body(&mut BufReader::new(reader))?;
+    match body {
+        Some(body) => {
+            let rsp = process_body(thin_client_service, accounting_stage, &body, rsp_addr);
+            let data = serde_json::to_vec(&rsp)?;
+            write_http_response(&mut stream, "200 OK", &data)?;
+        }
+        None => write_http_response(&mut stream, "400 Bad Request", b"")?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use accountant::{Accountant, SignatureStatus};
+    use accounting_stage::AccountingStage;
+    use hash::Hash;
+    use mint::Mint;
+    use rpc::{read_http_body, MAX_HEADER_SIZE, REQUEST_TIMEOUT_MS};
+    use serde_json::{self, Value};
+    use signature::{KeyPair, KeyPairUtil, Signature};
+    use std::io::{Cursor, Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+    use tpu::Tpu;
+    use transaction::Transaction;
+
+    /// Call `method` over HTTP, and return the JSON-RPC response.
+    fn call(addr: SocketAddr, method: &str, params: &str) -> Value {
+        let body = format!(
+            r#"{{"jsonrpc":"2.0","id":1,"method":"{}","params":{}}}"#,
+            method, params
+        );
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ).unwrap();
+        let mut rsp = String::new();
+        stream.read_to_string(&mut rsp).unwrap();
+        assert!(rsp.starts_with("HTTP/1.1 200 OK"));
+        let body = rsp.splitn(2, "\r\n\r\n").nth(1).unwrap();
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn test_read_http_body() {
+        let req = "POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\n{}";
+        let body = read_http_body(&mut Cursor::new(req)).unwrap();
+        assert_eq!(body, Some(b"{}".to_vec()));
+
+        let req = "GET / HTTP/1.1\r\n\r\n";
+        assert_eq!(read_http_body(&mut Cursor::new(req)).unwrap(), None);
+
+        // Headers that go on and on are refused without reading them all.
+        let long_header = format!("X: {}\r\n", "a".repeat(MAX_HEADER_SIZE as usize));
+        let req = format!("POST / HTTP/1.1\r\n{}Content-Length: 2\r\n\r\n{{}}", long_header);
+        assert_eq!(read_http_body(&mut Cursor::new(req)).unwrap(), None);
+    }
+
+    #[test]
+    fn test_rpc() {
+        let mint = Mint::new(10_000);
+        let accountant = Accountant::new(&mint);
+        let accounting_stage = AccountingStage::new(accountant, &mint.last_id(), None);
+        let tpu = Arc::new(Tpu::new(accounting_stage));
+        let exit = Arc::new(AtomicBool::new(false));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut threads = vec![Tpu::sync_no_broadcast_service(tpu.clone(), exit.clone())];
+        threads.extend(Tpu::rpc_service(&tpu, listener, exit.clone()).unwrap());
+
+        // A client that doesn't send its request doesn't hold up the others.
+        let idle = TcpStream::connect(addr).unwrap();
+        let now = Instant::now();
+        let mint_key = serde_json::to_string(&[mint.pubkey()]).unwrap();
+        assert_eq!(call(addr, "getBalance", &mint_key)["result"].as_i64(), Some(10_000));
+        assert!(now.elapsed() < Duration::from_millis(REQUEST_TIMEOUT_MS));
+        drop(idle);
+
+        let bob_pubkey = KeyPair::new().pubkey();
+        let tr = Transaction::new(&mint.keypair(), bob_pubkey, 500, mint.last_id());
+        let params = serde_json::to_string(&[&tr]).unwrap();
+        let result = call(addr, "sendTransaction", &params)["result"].clone();
+        let sig: Signature = serde_json::from_value(result).unwrap();
+        assert_eq!(sig, tr.sig);
+
+        let params = serde_json::to_string(&[sig]).unwrap();
+        let result = call(addr, "getSignatureStatus", &params)["result"].clone();
+        let status: SignatureStatus = serde_json::from_value(result).unwrap();
+        assert_eq!(status, SignatureStatus::Processed);
+
+        let params = serde_json::to_string(&[bob_pubkey]).unwrap();
+        assert_eq!(call(addr, "getBalance", &params)["result"].as_i64(), Some(500));
+
+        let result = call(addr, "getLastId", "[]")["result"].clone();
+        let last_id: Hash = serde_json::from_value(result).unwrap();
+        assert_ne!(last_id, mint.last_id());
+
+        // The count is updated as the entry is written out.
+        let now = Instant::now();
+        let mut count = None;
+        while count != Some(1) && now.elapsed() < Duration::new(5, 0) {
+            count = call(addr, "getTransactionCount", "[]")["result"].as_u64();
+            sleep(Duration::from_millis(10));
+        }
+        assert_eq!(count, Some(1));
+
+        // A tampered transaction is refused.
+        let mut tr = Transaction::new(&mint.keypair(), bob_pubkey, 1, mint.last_id());
+        tr.data.tokens = 2;
+        let params = serde_json::to_string(&[&tr]).unwrap();
+        assert!(call(addr, "sendTransaction", &params)["result"].is_null());
+
+        let rsp = call(addr, "getNothing", "[]");
+        assert_eq!(rsp["error"]["code"].as_i64(), Some(-32_601));
+
+        exit.store(true, Ordering::Relaxed);
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+}
@@ -7,7 +7,10 @@ use ring::error::Unspecified;
 use ring::rand::SecureRandom;
 use ring::signature::Ed25519KeyPair;
 use ring::{rand, signature};
+use serde_json;
 use std::cell::RefCell;
+use std::fs::File;
+use std::io;
 use std::mem;
 use untrusted;
 
@@ -36,6 +39,22 @@ impl KeyPairUtil for Ed25519KeyPair {
     }
 }
 
+/// Read a keypair from `path`, where it's stored as a JSON array of its PKCS#8 bytes.
+pub fn read_keypair(path: &str) -> io::Result<KeyPair> {
+    let pkcs8: Vec<u8> = serde_json::from_reader(File::open(path)?)?;
+    KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid pkcs8 keypair"))
+}
+
+/// Generate a keypair and store it in `path`, to be read with `read_keypair`.
+pub fn write_new_keypair(path: &str) -> io::Result<KeyPair> {
+    let rng = rand::SystemRandom::new();
+    let pkcs8 = signature::Ed25519KeyPair::generate_pkcs8(&rng)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "generate_pkcs8 failed"))?;
+    serde_json::to_writer(File::create(path)?, &pkcs8.to_vec())?;
+    read_keypair(path)
+}
+
 pub trait SignatureUtil {
     fn verify(&self, peer_public_key_bytes: &[u8], msg_bytes: &[u8]) -> bool;
 }
@@ -92,6 +111,8 @@ impl SecureRandom for GenKeys {
 mod tests {
     use super::*;
     use std::collections::HashSet;
+    use std::env;
+    use std::fs::remove_file;
     use std::iter::FromIterator;
 
     #[test]
@@ -118,4 +139,14 @@ mod tests {
         let users2_set: HashSet<(Vec<u8>, i64)> = HashSet::from_iter(users2.iter().cloned());
         assert_eq!(users1_set, users2_set);
     }
+
+    #[test]
+    fn test_keypair_file() {
+        let path = env::temp_dir().join(format!("keypair-{}.json", KeyPair::new().pubkey()[0]));
+        let path = path.to_str().unwrap();
+        let keypair = write_new_keypair(path).unwrap();
+        assert_eq!(read_keypair(path).unwrap().pubkey(), keypair.pubkey());
+        remove_file(path).unwrap();
+        assert!(read_keypair(path).is_err());
+    }
 }
@@ -1,21 +1,36 @@
 //! The `streamer` module defines a set of services for effecently pulling data from udp sockets.
 use crdt::Crdt;
+use ecdsa;
 #[cfg(feature = "erasure")]
 use erasure;
+use erasure::ErasureConfig;
 use packet::{Blob, BlobRecycler, PacketRecycler, SharedBlob, SharedPackets, NUM_BLOBS};
-use result::Result;
+use rayon::prelude::*;
+use result::{Error, Result};
+use std::cmp::max;
 use std::collections::VecDeque;
 use std::net::UdpSocket;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::{Arc, RwLock};
 use std::thread::{spawn, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use timing::timestamp;
 
 pub type PacketReceiver = mpsc::Receiver<SharedPackets>;
 pub type PacketSender = mpsc::Sender<SharedPackets>;
 pub type BlobSender = mpsc::Sender<VecDeque<SharedBlob>>;
 pub type BlobReceiver = mpsc::Receiver<VecDeque<SharedBlob>>;
+/// The most recent blobs, each at its index modulo the window length
+pub type Window = Arc<RwLock<Vec<Option<SharedBlob>>>>;
+
+/// How long the window waits without progress before asking for missing blobs
+const REPAIR_INTERVAL_MS: u64 = 200;
+
+pub fn default_window() -> Window {
+    Arc::new(RwLock::new(vec![None; NUM_BLOBS]))
+}
 
 fn recv_loop(
     sock: &UdpSocket,
@@ -128,55 +143,121 @@ pub fn blob_receiver(
     Ok(t)
 }
 
-fn recv_window(
+/// Copy a blob, so the copy can be recycled independently of the original.
+fn copy_blob(recycler: &BlobRecycler, b: &SharedBlob) -> SharedBlob {
+    let p = b.read().expect("'b' read lock in fn copy_blob");
+    let nv = recycler.allocate();
+    {
+        let mut mnv = nv.write().expect("recycler write lock in fn copy_blob");
+        let sz = p.meta.size;
+        mnv.meta = p.meta.clone();
+        mnv.data[..sz].copy_from_slice(&p.data[..sz]);
+    }
+    nv
+}
+
+/// Whether the blob at `ix` carries entries, as opposed to erasure codes.
+#[cfg(feature = "erasure")]
+fn is_data_index(config: &ErasureConfig, ix: usize) -> bool {
+    !config.is_coding_index(ix as u64)
+}
+
+#[cfg(not(feature = "erasure"))]
+fn is_data_index(_config: &ErasureConfig, _ix: usize) -> bool {
+    true
+}
+
+/// Reconstruct the missing data blobs of the erasure sets the blobs at `ixs` belong to.
+#[cfg(feature = "erasure")]
+fn recover(
+    config: &ErasureConfig,
+    recycler: &BlobRecycler,
     window: &mut Vec<Option<SharedBlob>>,
+    consumed: usize,
+    ixs: &[usize],
+) {
+    let mut starts: Vec<usize> = ixs.iter()
+        .map(|ix| config.set_start(*ix as u64) as usize)
+        .collect();
+    starts.sort();
+    starts.dedup();
+    for start in starts {
+        // the end of the set must not wrap around onto blobs that aren't consumed yet
+        if start + config.num_coded() > consumed + NUM_BLOBS {
+            continue;
+        }
+        if let Err(e) = erasure::recover(config, recycler, window, start) {
+            trace!("erasure set at {} not recovered: {:?}", start, e);
+        }
+    }
+}
+
+#[cfg(not(feature = "erasure"))]
+fn recover(
+    _config: &ErasureConfig,
+    _recycler: &BlobRecycler,
+    _window: &mut Vec<Option<SharedBlob>>,
+    _consumed: usize,
+    _ixs: &[usize],
+) {
+}
+
+fn recv_window(
+    window: &Window,
     crdt: &Arc<RwLock<Crdt>>,
     recycler: &BlobRecycler,
     consumed: &mut usize,
+    received: &mut usize,
     r: &BlobReceiver,
     s: &BlobSender,
     retransmit: &BlobSender,
 ) -> Result<()> {
     let timer = Duration::new(1, 0);
     let mut dq = r.recv_timeout(timer)?;
-    let leader_id = crdt.read()
-        .expect("'crdt' read lock in fn recv_window")
-        .leader_data()
-        .id;
+    let (leader_id, config) = {
+        let robj = crdt.read().expect("'crdt' read lock in fn recv_window");
+        (robj.leader_data().id, robj.erasure)
+    };
     while let Ok(mut nq) = r.try_recv() {
         dq.append(&mut nq)
     }
+    // only the blobs signed by the leader are retransmitted or enter the window
+    let verified = ecdsa::ed25519_verify_blobs(&dq);
+    let mut dq: VecDeque<_> = dq.into_iter()
+        .zip(verified)
+        .filter_map(|(b, v)| {
+            let from_leader = {
+                let p = b.read().expect("'b' read lock in fn recv_window");
+                trace!(
+                    "idx: {} addr: {:?} id: {:?} leader: {:?}",
+                    p.get_index().expect("get_index in fn recv_window"),
+                    p.meta.addr(),
+                    p.get_id().expect("get_id in trace! fn recv_window"),
+                    leader_id
+                );
+                v == 1 && p.get_id().ok() == Some(leader_id)
+            };
+            if from_leader {
+                Some(b)
+            } else {
+                debug!("dropping a blob that isn't signed by the leader");
+                recycler.recycle(b);
+                None
+            }
+        })
+        .collect();
     {
         //retransmit all leader blocks
         let mut retransmitq = VecDeque::new();
         for b in &dq {
-            let p = b.read().expect("'b' read lock in fn recv_window");
-            //TODO this check isn't safe against adverserial packets
-            //we need to maintain a sequence window
-            trace!(
-                "idx: {} addr: {:?} id: {:?} leader: {:?}",
-                p.get_index().expect("get_index in fn recv_window"),
-                p.get_id().expect("get_id in trace! fn recv_window"),
-                p.meta.addr(),
-                leader_id
-            );
-            if p.get_id().expect("get_id in fn recv_window") == leader_id {
-                //TODO
-                //need to copy the retransmited blob
-                //otherwise we get into races with which thread
-                //should do the recycling
-                //
-                //a better absraction would be to recycle when the blob
-                //is dropped via a weakref to the recycler
-                let nv = recycler.allocate();
-                {
-                    let mut mnv = nv.write().expect("recycler write lock in fn recv_window");
-                    let sz = p.meta.size;
-                    mnv.meta.size = sz;
-                    mnv.data[..sz].copy_from_slice(&p.data[..sz]);
-                }
-                retransmitq.push_back(nv);
-            }
+            //TODO
+            //need to copy the retransmited blob
+            //otherwise we get into races with which thread
+            //should do the recycling
+            //
+            //a better absraction would be to recycle when the blob
+            //is dropped via a weakref to the recycler
+            retransmitq.push_back(copy_blob(recycler, b));
         }
         if !retransmitq.is_empty() {
             retransmit.send(retransmitq)?;
@@ -184,32 +265,60 @@ fn recv_window(
     }
     //send a contiguous set of blocks
     let mut contq = VecDeque::new();
+    let mut window = window.write().expect("'window' write lock in fn recv_window");
+    let mut ixs = Vec::new();
     while let Some(b) = dq.pop_front() {
-        let b_ = b.clone();
-        let p = b.write().expect("'b' write lock in fn recv_window");
-        let pix = p.get_index()? as usize;
+        let pix = b.read().expect("'b' read lock in fn recv_window").get_index()? as usize;
+        if pix < *consumed || pix >= *consumed + NUM_BLOBS {
+            debug!("blob {} is outside the window at {}", pix, *consumed);
+            recycler.recycle(b);
+            continue;
+        }
         let w = pix % NUM_BLOBS;
-        //TODO, after the block are authenticated
-        //if we get different blocks at the same index
-        //that is a network failure/attack
-        trace!("window w: {} size: {}", w, p.meta.size);
-        {
-            if window[w].is_none() {
-                window[w] = Some(b_);
-            } else {
-                debug!("duplicate blob at index {:}", w);
+        //TODO, the blocks are authenticated
+        //so if we get different blocks at the same index
+        //that is a leader failure/attack
+        trace!("window w: {} index: {}", w, pix);
+        if let Some(old) = window[w].take() {
+            let oix = old.read().expect("'old' read lock in fn recv_window").get_index()?;
+            if oix as usize == pix {
+                debug!("duplicate blob at index {:}", pix);
+                window[w] = Some(old);
+                recycler.recycle(b);
+                continue;
             }
-            loop {
-                let k = *consumed % NUM_BLOBS;
-                trace!("k: {} consumed: {}", k, *consumed);
-                if window[k].is_none() {
-                    break;
-                }
-                contq.push_back(window[k].clone().expect("clone in fn recv_window"));
-                window[k] = None;
-                *consumed += 1;
+            // an already consumed blob, retained until now to answer repair requests
+            recycler.recycle(old);
+        }
+        window[w] = Some(b);
+        ixs.push(pix);
+        *received = max(*received, pix);
+    }
+    recover(&config, recycler, &mut window, *consumed, &ixs);
+    loop {
+        // erasure codes aren't needed once the data blobs are in
+        if !is_data_index(&config, *consumed) {
+            *consumed += 1;
+            continue;
+        }
+        let k = *consumed % NUM_BLOBS;
+        trace!("k: {} consumed: {}", k, *consumed);
+        let next = match window[k] {
+            Some(ref b) => {
+                let p = b.read().expect("'b' read lock in fn recv_window");
+                p.get_index()? as usize == *consumed
             }
+            None => false,
+        };
+        if !next {
+            break;
         }
+        // send a copy downstream and keep the blob to answer repair requests
+        contq.push_back(copy_blob(
+            recycler,
+            window[k].as_ref().expect("window[k] in fn recv_window"),
+        ));
+        *consumed += 1;
     }
     trace!("sending contq.len: {}", contq.len());
     if !contq.is_empty() {
@@ -218,36 +327,140 @@ fn recv_window(
     Ok(())
 }
 
+/// Ask the network for the blobs missing between `consumed` and the highest index received,
+/// or else for the blob at `consumed`, in case the rest of the stream went missing with it.
+fn repair_window(
+    window: &Window,
+    crdt: &Arc<RwLock<Crdt>>,
+    consumed: usize,
+    received: usize,
+) -> Result<()> {
+    let config = crdt.read()
+        .expect("'crdt' read lock in fn repair_window")
+        .erasure;
+    let missing: Vec<usize> = {
+        let window = window.read().expect("'window' read lock in fn repair_window");
+        (consumed..max(received, consumed + 1))
+            .filter(|ix| is_data_index(&config, *ix))
+            .filter(|ix| match window[ix % NUM_BLOBS] {
+                Some(ref b) => {
+                    let p = b.read().expect("'b' read lock in fn repair_window");
+                    p.get_index().ok() != Some(*ix as u64)
+                }
+                None => true,
+            })
+            .collect()
+    };
+    if missing.is_empty() {
+        return Ok(());
+    }
+    debug!("repairing {} missing blobs from {}", missing.len(), consumed);
+    let reqs: Vec<_> = {
+        let robj = crdt.read().expect("'crdt' read lock in fn repair_window");
+        missing
+            .into_iter()
+            .map(|ix| robj.window_index_request(ix as u64))
+            .collect::<Result<_>>()?
+    };
+    let sock = UdpSocket::bind("0.0.0.0:0")?;
+    for (addr, req) in reqs {
+        sock.send_to(&req, addr)?;
+    }
+    Ok(())
+}
+
+/// Service to order the blobs from the leader into a contiguous stream, and
+/// to ask the network for the ones that went missing. Blobs that aren't signed
+/// by the leader are dropped.
+/// # Arguments
+/// * `exit` - Boolean to signal system exit.
+/// * `crdt` - CRDT structure
+/// * `window` - The window, shared with `Crdt::listen` to answer repair requests.
+/// * `recycler` - Blob recycler.
+/// * `r` - Receive channel for blobs from the network.
+/// * `s` - Send channel for the contiguous blobs.
+/// * `retransmit` - Send channel for the blobs to be retransmitted.
+/// * `consumed` - Index of the first blob to send downstream.
 pub fn window(
     exit: Arc<AtomicBool>,
     crdt: Arc<RwLock<Crdt>>,
+    window: Window,
     recycler: BlobRecycler,
     r: BlobReceiver,
     s: BlobSender,
     retransmit: BlobSender,
+    consumed: u64,
 ) -> JoinHandle<()> {
     spawn(move || {
-        let mut window = vec![None; NUM_BLOBS];
-        let mut consumed = 0;
+        let mut consumed = consumed as usize;
+        let mut received = consumed;
+        let mut last_consumed = consumed;
+        let mut last_repair = Instant::now();
+        let repair_interval = Duration::from_millis(REPAIR_INTERVAL_MS);
         loop {
             if exit.load(Ordering::Relaxed) {
                 break;
             }
             let _ = recv_window(
-                &mut window,
+                &window,
                 &crdt,
                 &recycler,
                 &mut consumed,
+                &mut received,
                 &r,
                 &s,
                 &retransmit,
             );
+            if last_repair.elapsed() >= repair_interval {
+                if consumed == last_consumed {
+                    let _ = repair_window(&window, &crdt, consumed, received);
+                }
+                last_consumed = consumed;
+                last_repair = Instant::now();
+            }
         }
     })
 }
 
+/// Generate the coding blobs for the erasure sets completed by the data blobs at `ixs`,
+/// and retain them in the window.
+#[cfg(feature = "erasure")]
+fn generate_coding(
+    config: &ErasureConfig,
+    recycler: &BlobRecycler,
+    window: &mut Vec<Option<SharedBlob>>,
+    ixs: &[usize],
+) -> Result<Vec<SharedBlob>> {
+    let mut coding = Vec::new();
+    for ix in ixs {
+        if !config.is_coding_index(*ix as u64 + 1) {
+            continue;
+        }
+        let start = config.set_start(*ix as u64) as usize;
+        erasure::generate_coding(config, recycler, window, start)?;
+        for i in start + config.num_data()..start + config.num_coded() {
+            let b = window[i % NUM_BLOBS]
+                .clone()
+                .expect("coding blob in fn generate_coding");
+            coding.push(b);
+        }
+    }
+    Ok(coding)
+}
+
+#[cfg(not(feature = "erasure"))]
+fn generate_coding(
+    _config: &ErasureConfig,
+    _recycler: &BlobRecycler,
+    _window: &mut Vec<Option<SharedBlob>>,
+    _ixs: &[usize],
+) -> Result<Vec<SharedBlob>> {
+    Ok(Vec::new())
+}
+
 fn broadcast(
     crdt: &Arc<RwLock<Crdt>>,
+    window: &Window,
     recycler: &BlobRecycler,
     r: &BlobReceiver,
     sock: &UdpSocket,
@@ -258,39 +471,84 @@ fn broadcast(
     while let Ok(mut nq) = r.try_recv() {
         dq.append(&mut nq);
     }
-    let mut blobs = dq.into_iter().collect();
-    /// appends codes to the list of blobs allowing us to reconstruct the stream
-    #[cfg(feature = "erasure")]
-    erasure::generate_codes(blobs);
-    Crdt::broadcast(crdt, &blobs, &sock, transmit_index)?;
-    while let Some(b) = blobs.pop() {
-        recycler.recycle(b);
+    let (me_id, keypair, config, no_peers) = {
+        let robj = crdt.read().expect("'crdt' read lock in fn broadcast");
+        // every blob goes to some layer 1 node if any of them is live
+        let no_peers = robj.broadcast_peers(0, timestamp()).is_empty();
+        (robj.my_data().id, robj.keypair(), robj.erasure, no_peers)
+    };
+    let mut blobs: Vec<_> = dq.into_iter().collect();
+    {
+        // retain the blobs to answer repair requests, which can be served as soon
+        // as a blob is in the window, so it's signed before it goes in
+        let mut window = window.write().expect("'window' write lock in fn broadcast");
+        let mut ixs = Vec::new();
+        for b in &blobs {
+            while !is_data_index(&config, *transmit_index as usize) {
+                *transmit_index += 1;
+            }
+            let ix = *transmit_index;
+            {
+                let mut blob = b.write().expect("'b' write lock in fn broadcast");
+                blob.set_id(me_id)?;
+                blob.set_index(ix)?;
+                blob.set_flags(0)?;
+                let size = blob.meta.size as u64;
+                blob.set_data_size(size)?;
+                blob.sign(&keypair);
+            }
+            if let Some(old) = window[ix as usize % NUM_BLOBS].take() {
+                recycler.recycle(old);
+            }
+            window[ix as usize % NUM_BLOBS] = Some(b.clone());
+            ixs.push(ix as usize);
+            *transmit_index += 1;
+        }
+        // appends codes to the list of blobs allowing us to reconstruct the stream,
+        // signed before the window is released
+        let mut coding = generate_coding(&config, recycler, &mut window, &ixs)?;
+        coding.par_iter().for_each(|b| {
+            b.write()
+                .expect("'b' write lock in fn broadcast")
+                .sign(&keypair)
+        });
+        blobs.append(&mut coding);
+    }
+    // the entries are already in the ledger, so the blobs are indexed and kept in
+    // the window even if nobody is live, for the replicas to repair once they are
+    if no_peers {
+        trace!("nobody to broadcast to");
+        return Ok(());
     }
+    Crdt::broadcast(crdt, &blobs, &sock)?;
     Ok(())
 }
 
-/// Service to broadcast messages from the leader to layer 1 nodes.
-/// See `crdt` for network layer definitions.
+/// Service to broadcast messages from the leader to layer 1 nodes, signed by the leader.
+/// See `crdt` for network layer definitions. The service exits once the sending half of
+/// `r` is dropped and every blob sent before that is broadcast.
 /// # Arguments
 /// * `sock` - Socket to send from.
-/// * `exit` - Boolean to signal system exit.
 /// * `crdt` - CRDT structure
+/// * `window` - Retains the sent blobs, shared with `Crdt::listen` to answer repair requests.
 /// * `recycler` - Blob recycler.
 /// * `r` - Receive channel for blobs to be retransmitted to all the layer 1 nodes.
+/// * `transmit_index` - Index of the first blob to send, where the previous leader stopped.
 pub fn broadcaster(
     sock: UdpSocket,
-    exit: Arc<AtomicBool>,
     crdt: Arc<RwLock<Crdt>>,
+    window: Window,
     recycler: BlobRecycler,
     r: BlobReceiver,
+    transmit_index: u64,
 ) -> JoinHandle<()> {
     spawn(move || {
-        let mut transmit_index = 0;
+        let mut transmit_index = transmit_index;
         loop {
-            if exit.load(Ordering::Relaxed) {
+            let e = broadcast(&crdt, &window, &recycler, &r, &sock, &mut transmit_index);
+            if let Err(Error::RecvTimeoutError(RecvTimeoutError::Disconnected)) = e {
                 break;
             }
-            let _ = broadcast(&crdt, &recycler, &r, &sock, &mut transmit_index);
         }
     })
 }
@@ -317,14 +575,14 @@ fn retransmit(
     Ok(())
 }
 
-/// Service to retransmit messages from the leader to layer 1 nodes.
-/// See `crdt` for network layer definitions.
+/// Service to retransmit messages from the leader to this node's layer 2 neighborhood.
+/// See `crdt` for network layer definitions and how the neighborhoods are computed.
 /// # Arguments
 /// * `sock` - Socket to read from.  Read timeout is set to 1.
 /// * `exit` - Boolean to signal system exit.
 /// * `crdt` - This structure needs to be updated and populated by the accountant and via gossip.
 /// * `recycler` - Blob recycler.
-/// * `r` - Receive channel for blobs to be retransmitted to all the layer 1 nodes.
+/// * `r` - Receive channel for blobs to be retransmitted to the neighborhood.
 pub fn retransmitter(
     sock: UdpSocket,
     exit: Arc<AtomicBool>,
@@ -450,6 +708,11 @@ mod bench {
 #[cfg(test)]
 mod test {
     use crdt::{Crdt, ReplicatedData};
+    use erasure::{ErasureConfig, NUM_CODED};
+    #[cfg(feature = "erasure")]
+    use hash::Hash;
+    #[cfg(feature = "erasure")]
+    use ledger::{next_entries, process_entry_list_into_blobs, reconstruct_entries_from_blobs};
     use logger;
     use packet::{Blob, BlobRecycler, Packet, PacketRecycler, Packets, PACKET_DATA_SIZE};
     use signature::KeyPair;
@@ -463,8 +726,11 @@ mod test {
     use std::sync::{Arc, RwLock};
     use std::thread::sleep;
     use std::time::Duration;
+    #[cfg(feature = "erasure")]
+    use std::time::Instant;
     use streamer::{BlobReceiver, PacketReceiver};
-    use streamer::{blob_receiver, receiver, responder, retransmitter, window};
+    use streamer::{blob_receiver, broadcaster, default_window, receiver, responder, retransmitter,
+                   window};
 
     fn get_msgs(r: PacketReceiver, num: &mut usize) {
         for _t in 0..5 {
@@ -535,21 +801,23 @@ mod test {
 
     #[test]
     pub fn window_send_test() {
-        let pubkey_me = KeyPair::new().pubkey();
+        let keypair_me = Arc::new(KeyPair::new());
         let read = UdpSocket::bind("127.0.0.1:0").expect("bind");
         let addr = read.local_addr().unwrap();
         let send = UdpSocket::bind("127.0.0.1:0").expect("bind");
         let serve = UdpSocket::bind("127.0.0.1:0").expect("bind");
         let exit = Arc::new(AtomicBool::new(false));
         let rep_data = ReplicatedData::new(
-            pubkey_me,
+            keypair_me.pubkey(),
             read.local_addr().unwrap(),
             send.local_addr().unwrap(),
             serve.local_addr().unwrap(),
         );
-        let mut crdt_me = Crdt::new(rep_data);
+        let mut crdt_me = Crdt::new(rep_data, keypair_me.clone());
         let me_id = crdt_me.my_data().id;
         crdt_me.set_leader(me_id);
+        // all the blobs carry data
+        crdt_me.erasure = ErasureConfig::new(NUM_CODED, 0);
         let subs = Arc::new(RwLock::new(crdt_me));
 
         let resp_recycler = BlobRecycler::default();
@@ -561,14 +829,27 @@ mod test {
         let t_window = window(
             exit.clone(),
             subs,
+            default_window(),
             resp_recycler.clone(),
             r_reader,
             s_window,
             s_retransmit,
+            0,
         );
         let (s_responder, r_responder) = channel();
         let t_responder = responder(send, exit.clone(), resp_recycler.clone(), r_responder);
         let mut msgs = VecDeque::new();
+        // a blob that claims to be from the leader, and is dropped
+        let forged = resp_recycler.allocate();
+        {
+            let mut w = forged.write().unwrap();
+            w.set_index(0).unwrap();
+            w.set_id(me_id).unwrap();
+            w.meta.size = PACKET_DATA_SIZE;
+            w.sign(&KeyPair::new());
+            w.meta.set_addr(&addr);
+        }
+        msgs.push_back(forged);
         for v in 0..10 {
             let i = 9 - v;
             let b = resp_recycler.allocate();
@@ -578,6 +859,7 @@ mod test {
             w.set_id(me_id).unwrap();
             assert_eq!(i, w.get_index().unwrap());
             w.meta.size = PACKET_DATA_SIZE;
+            w.sign(&keypair_me);
             w.meta.set_addr(&addr);
             msgs.push_back(b_);
         }
@@ -596,18 +878,217 @@ mod test {
         t_window.join().expect("join");
     }
 
+    /// Test that the window asks the leader for a lost blob
+    #[test]
+    pub fn window_repair_test() {
+        let exit = Arc::new(AtomicBool::new(false));
+        let (crdt_leader, sock_gossip_leader, _, _) = test_node();
+        let (crdt_target, _, sock_replicate_target, _) = test_node();
+        let leader_data = crdt_leader.read().unwrap().my_data().clone();
+        let leader_keypair = crdt_leader.read().unwrap().keypair();
+        crdt_leader.write().unwrap().set_leader(leader_data.id);
+        // repairs only go to nodes the leader knows
+        let target_data = crdt_target.read().unwrap().my_data().clone();
+        crdt_leader.write().unwrap().insert(target_data);
+        crdt_target.write().unwrap().insert(leader_data.clone());
+        crdt_target.write().unwrap().set_leader(leader_data.id);
+        crdt_target.write().unwrap().erasure = ErasureConfig::new(NUM_CODED, 0);
+        let target_addr = sock_replicate_target.local_addr().unwrap();
+
+        // the leader retains the blobs it broadcast
+        let recycler = BlobRecycler::default();
+        let leader_window = default_window();
+        let mut msgs = VecDeque::new();
+        for i in 0..10 {
+            let b = recycler.allocate();
+            {
+                let mut w = b.write().unwrap();
+                w.set_index(i).unwrap();
+                w.set_id(leader_data.id).unwrap();
+                w.meta.size = PACKET_DATA_SIZE;
+                w.sign(&leader_keypair);
+                w.meta.set_addr(&target_addr);
+            }
+            leader_window.write().unwrap()[i as usize] = Some(b.clone());
+            // blob 0 is lost in transit
+            if i != 0 {
+                let c = recycler.allocate();
+                *c.write().unwrap() = b.read().unwrap().clone();
+                msgs.push_back(c);
+            }
+        }
+        let t_listen = Crdt::listen(
+            crdt_leader.clone(),
+            leader_window,
+            sock_gossip_leader,
+            exit.clone(),
+        );
+
+        let (s_reader, r_reader) = channel();
+        let t_receiver =
+            blob_receiver(exit.clone(), recycler.clone(), sock_replicate_target, s_reader).unwrap();
+        let (s_window, r_window) = channel();
+        let (s_retransmit, _r_retransmit) = channel();
+        let t_window = window(
+            exit.clone(),
+            crdt_target,
+            default_window(),
+            recycler.clone(),
+            r_reader,
+            s_window,
+            s_retransmit,
+            0,
+        );
+        let (s_responder, r_responder) = channel();
+        let send = UdpSocket::bind("127.0.0.1:0").expect("bind");
+        let t_responder = responder(send, exit.clone(), recycler.clone(), r_responder);
+        s_responder.send(msgs).expect("send");
+
+        let mut num = 0;
+        get_blobs(r_window, &mut num);
+        assert_eq!(num, 10);
+        exit.store(true, Ordering::Relaxed);
+        t_receiver.join().expect("join");
+        t_responder.join().expect("join");
+        t_window.join().expect("join");
+        t_listen.join().expect("join");
+    }
+
+    /// Test that the window reconstructs the entries when blobs are lost in transit
+    #[cfg(feature = "erasure")]
+    #[test]
+    pub fn window_erasure_test() {
+        let exit = Arc::new(AtomicBool::new(false));
+        let (crdt_leader, _, _, sock_leader) = test_node();
+        // the target's replicate socket is a proxy that drops some of the blobs
+        let (crdt_target, _, sock_proxy, _) = test_node();
+        let leader_data = crdt_leader.read().unwrap().my_data().clone();
+        let target_data = crdt_target.read().unwrap().my_data().clone();
+        crdt_leader.write().unwrap().set_leader(leader_data.id);
+        crdt_leader.write().unwrap().insert(target_data);
+        crdt_target.write().unwrap().insert(leader_data.clone());
+        crdt_target.write().unwrap().set_leader(leader_data.id);
+
+        let recycler = BlobRecycler::default();
+        let (s_broadcast, r_broadcast) = channel();
+        let t_broadcast = broadcaster(
+            sock_leader,
+            crdt_leader,
+            default_window(),
+            recycler.clone(),
+            r_broadcast,
+            0,
+        );
+        let (s_reader, r_reader) = channel();
+        let (s_window, r_window) = channel();
+        let (s_retransmit, _r_retransmit) = channel();
+        let t_window = window(
+            exit.clone(),
+            crdt_target,
+            default_window(),
+            recycler.clone(),
+            r_reader,
+            s_window,
+            s_retransmit,
+            0,
+        );
+
+        // two full erasure sets and part of a third
+        let config = ErasureConfig::default();
+        let num_data = 2 * config.num_data() + 4;
+        let entries = next_entries(&Hash::default(), 1, vec![vec![]; num_data]);
+        let mut msgs = VecDeque::new();
+        for entry in &entries {
+            process_entry_list_into_blobs(&vec![entry.clone()], &recycler, &mut msgs);
+        }
+        assert_eq!(msgs.len(), num_data);
+        s_broadcast.send(msgs).unwrap();
+
+        let num_coding = 2 * config.max_missing();
+        let mut num_received = 0;
+        sock_proxy
+            .set_read_timeout(Some(Duration::new(1, 0)))
+            .unwrap();
+        let start = Instant::now();
+        while num_received < num_data + num_coding && start.elapsed() < Duration::new(5, 0) {
+            let mut blobs = match Blob::recv_from(&recycler, &sock_proxy) {
+                Ok(blobs) => blobs,
+                Err(_) => continue,
+            };
+            num_received += blobs.len();
+            // lose as many blobs as each set can afford
+            blobs.retain(|b| match b.read().unwrap().get_index().unwrap() {
+                1 | 5 | 10 | 12 => false,
+                _ => true,
+            });
+            s_reader.send(blobs).unwrap();
+        }
+        assert_eq!(num_received, num_data + num_coding);
+
+        let mut blobs = VecDeque::new();
+        let start = Instant::now();
+        while blobs.len() < num_data && start.elapsed() < Duration::new(5, 0) {
+            if let Ok(mut q) = r_window.recv_timeout(Duration::new(1, 0)) {
+                blobs.append(&mut q);
+            }
+        }
+        assert_eq!(reconstruct_entries_from_blobs(&blobs), entries);
+        exit.store(true, Ordering::Relaxed);
+        drop(s_broadcast);
+        t_broadcast.join().expect("join");
+        t_window.join().expect("join");
+    }
+
+    /// Test that the leader keeps its blobs for repair even with nobody to send them to
+    #[test]
+    pub fn broadcast_no_peers_test() {
+        let (crdt_leader, _, _, sock_leader) = test_node();
+        let leader_data = crdt_leader.read().unwrap().my_data().clone();
+        crdt_leader.write().unwrap().set_leader(leader_data.id);
+
+        let recycler = BlobRecycler::default();
+        let leader_window = default_window();
+        let (s_broadcast, r_broadcast) = channel();
+        let t_broadcast = broadcaster(
+            sock_leader,
+            crdt_leader,
+            leader_window.clone(),
+            recycler.clone(),
+            r_broadcast,
+            0,
+        );
+        let mut msgs = VecDeque::new();
+        for _ in 0..3 {
+            let b = recycler.allocate();
+            b.write().unwrap().meta.size = PACKET_DATA_SIZE;
+            msgs.push_back(b);
+        }
+        s_broadcast.send(msgs).unwrap();
+        drop(s_broadcast);
+        t_broadcast.join().expect("join");
+
+        let window = leader_window.read().unwrap();
+        for i in 0..3 {
+            let b = window[i].as_ref().expect("blob in the window");
+            let b = b.read().unwrap();
+            assert_eq!(b.get_index().unwrap(), i as u64);
+            assert_eq!(b.get_id().unwrap(), leader_data.id);
+            assert!(b.verify());
+        }
+    }
+
     fn test_node() -> (Arc<RwLock<Crdt>>, UdpSocket, UdpSocket, UdpSocket) {
         let gossip = UdpSocket::bind("127.0.0.1:0").unwrap();
         let replicate = UdpSocket::bind("127.0.0.1:0").unwrap();
         let serve = UdpSocket::bind("127.0.0.1:0").unwrap();
-        let pubkey = KeyPair::new().pubkey();
+        let keypair = KeyPair::new();
         let d = ReplicatedData::new(
-            pubkey,
+            keypair.pubkey(),
             gossip.local_addr().unwrap(),
             replicate.local_addr().unwrap(),
             serve.local_addr().unwrap(),
         );
-        let crdt = Crdt::new(d);
+        let crdt = Crdt::new(d, Arc::new(keypair));
         trace!(
             "id: {} gossip: {} replicate: {} serve: {}",
             crdt.my_data().id[0],
@@ -631,12 +1112,22 @@ mod test {
         crdt_leader.write().unwrap().insert(leader_data.clone());
         crdt_leader.write().unwrap().set_leader(leader_data.id);
         let t_crdt_leader_g = Crdt::gossip(crdt_leader.clone(), exit.clone());
-        let t_crdt_leader_l = Crdt::listen(crdt_leader.clone(), sock_gossip_leader, exit.clone());
+        let t_crdt_leader_l = Crdt::listen(
+            crdt_leader.clone(),
+            default_window(),
+            sock_gossip_leader,
+            exit.clone(),
+        );
 
         crdt_target.write().unwrap().insert(leader_data.clone());
         crdt_target.write().unwrap().set_leader(leader_data.id);
         let t_crdt_target_g = Crdt::gossip(crdt_target.clone(), exit.clone());
-        let t_crdt_target_l = Crdt::listen(crdt_target.clone(), sock_gossip_target, exit.clone());
+        let t_crdt_target_l = Crdt::listen(
+            crdt_target.clone(),
+            default_window(),
+            sock_gossip_target,
+            exit.clone(),
+        );
         //leader retransmitter
         let (s_retransmit, r_retransmit) = channel();
         let blob_recycler = BlobRecycler::default();
@@ -3,6 +3,8 @@
 //! messages to the network directly. The binary encoding of its messages are
 //! unstable and may change in future releases.
 
+use account_history::HistoryItem;
+use accountant::SignatureStatus;
 use bincode::{deserialize, serialize};
 use futures::future::{ok, FutureResult};
 use hash::Hash;
@@ -10,15 +12,27 @@ use signature::{KeyPair, PublicKey, Signature};
 use std::collections::HashMap;
 use std::io;
 use std::net::{SocketAddr, UdpSocket};
-use thin_client_service::{Request, Response, Subscription};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use thin_client_service::{Request, Response, Subscription, SUBSCRIPTION_TIMEOUT_MS};
 use transaction::Transaction;
 
+/// Subscriptions sent per request, so that each request fits in a packet
+const SUBSCRIPTIONS_PER_REQUEST: usize = 4;
+
 pub struct ThinClient {
     pub addr: SocketAddr,
     pub socket: UdpSocket,
     last_id: Option<Hash>,
     num_events: u64,
     balances: HashMap<PublicKey, Option<i64>>,
+    signature_status: HashMap<Signature, SignatureStatus>,
+    finalized_id: Option<Hash>,
+    account_history: HashMap<PublicKey, Vec<HistoryItem>>,
+    subscriptions: Vec<Subscription>,
+    subscribed_at: Instant,
+    /// The cookie the server sent this client to subscribe with
+    cookie: Option<Hash>,
 }
 
 impl ThinClient {
@@ -26,23 +40,108 @@ impl ThinClient {
     /// over `socket`. To receive responses, the caller must bind `socket`
     /// to a public address before invoking ThinClient methods.
     pub fn new(addr: SocketAddr, socket: UdpSocket) -> Self {
-        let client = ThinClient {
+        let mut client = ThinClient {
             addr: addr,
             socket,
             last_id: None,
             num_events: 0,
             balances: HashMap::new(),
+            signature_status: HashMap::new(),
+            finalized_id: None,
+            account_history: HashMap::new(),
+            subscriptions: vec![],
+            subscribed_at: Instant::now(),
+            cookie: None,
         };
         client.init();
         client
     }
 
-    pub fn init(&self) {
-        let subscriptions = vec![Subscription::EntryInfo];
-        let req = Request::Subscribe { subscriptions };
-        let data = serialize(&req).expect("serialize Subscribe in thin_client");
+    pub fn init(&mut self) {
+        let _res = self.subscribe(vec![Subscription::EntryInfo]);
+    }
+
+    /// Ask the server to push updates for `subscriptions`. Consume them with
+    /// `notifications`. The server drops subscriptions that aren't renewed, so
+    /// the client renews them as it receives responses. Until the client has
+    /// the server's cookie, the server answers with the cookie instead, and the
+    /// client subscribes again with it.
+    pub fn subscribe(&mut self, subscriptions: Vec<Subscription>) -> io::Result<()> {
+        for subscription in &subscriptions {
+            if !self.subscriptions.contains(subscription) {
+                self.subscriptions.push(subscription.clone());
+            }
+        }
         trace!("subscribing to {}", self.addr);
-        let _res = self.socket.send_to(&data, &self.addr);
+        let cookie = self.cookie;
+        self.send_subscriptions(&subscriptions, |subscriptions| {
+            Request::Subscribe {
+                subscriptions,
+                cookie,
+            }
+        })
+    }
+
+    /// Ask the server to stop pushing updates for `subscriptions`.
+    pub fn unsubscribe(&mut self, subscriptions: Vec<Subscription>) -> io::Result<()> {
+        self.subscriptions.retain(|x| !subscriptions.contains(x));
+        // Without a cookie, the server hasn't taken any subscriptions.
+        let cookie = match self.cookie {
+            Some(cookie) => cookie,
+            None => return Ok(()),
+        };
+        self.send_subscriptions(&subscriptions, |subscriptions| {
+            Request::Unsubscribe {
+                subscriptions,
+                cookie,
+            }
+        })
+    }
+
+    fn send_subscriptions<F>(&self, subscriptions: &[Subscription], new_req: F) -> io::Result<()>
+    where
+        F: Fn(Vec<Subscription>) -> Request,
+    {
+        for chunk in subscriptions.chunks(SUBSCRIPTIONS_PER_REQUEST) {
+            let req = new_req(chunk.to_vec());
+            let data = serialize(&req).expect("serialize subscriptions in thin_client");
+            self.socket.send_to(&data, &self.addr)?;
+        }
+        Ok(())
+    }
+
+    /// Send all the subscriptions again, to renew them or to take effect
+    /// with a new cookie.
+    fn resubscribe(&mut self) {
+        self.subscribed_at = Instant::now();
+        let subscriptions = self.subscriptions.clone();
+        let cookie = self.cookie;
+        let res = self.send_subscriptions(&subscriptions, |subscriptions| {
+            Request::Subscribe {
+                subscriptions,
+                cookie,
+            }
+        });
+        if let Err(e) = res {
+            warn!("couldn't renew subscriptions: {:?}", e);
+        }
+    }
+
+    /// Renew the subscriptions once half of their timeout has passed.
+    fn renew_subscriptions(&mut self) {
+        if self.subscribed_at.elapsed() > Duration::from_millis(SUBSCRIPTION_TIMEOUT_MS / 2) {
+            self.resubscribe();
+        }
+    }
+
+    /// Return an iterator over the responses the server pushes for this
+    /// client's subscriptions. Entry info and cookies only update the client,
+    /// as they do for the other methods. The iterator blocks for each item and
+    /// ends when the socket's read timeout elapses. Keep the timeout under
+    /// `SUBSCRIPTION_TIMEOUT_MS` so that the subscriptions are renewed while
+    /// the server is quiet.
+    pub fn notifications<'a>(&'a mut self) -> Notifications<'a> {
+        Notifications { client: self }
     }
 
     pub fn recv_response(&self) -> io::Result<Response> {
@@ -55,6 +154,7 @@ impl ThinClient {
     }
 
     pub fn process_response(&mut self, resp: Response) {
+        self.renew_subscriptions();
         match resp {
             Response::Balance { key, val } => {
                 info!("Response balance {:?} {:?}", key, val);
@@ -65,6 +165,25 @@ impl ThinClient {
                 self.last_id = Some(entry_info.id);
                 self.num_events += entry_info.num_events;
             }
+            Response::SignatureStatus { sig, status } => {
+                info!("Response signature status {:?} {:?}", sig, status);
+                self.signature_status.insert(sig, status);
+            }
+            Response::FinalizedId { id } => {
+                info!("Response finalized id {:?}", id);
+                self.finalized_id = id;
+            }
+            Response::AccountHistory { key, items } => {
+                info!("Response account history {:?} {}", key, items.len());
+                self.account_history.insert(key, items);
+            }
+            Response::SubscribeCookie { cookie } => {
+                info!("Response subscribe cookie {:?}", cookie);
+                if self.cookie != Some(cookie) {
+                    self.cookie = Some(cookie);
+                    self.resubscribe();
+                }
+            }
         }
     }
 
@@ -111,6 +230,88 @@ impl ThinClient {
         self.balances[pubkey].ok_or(io::Error::new(io::ErrorKind::Other, "nokey"))
     }
 
+    /// Request the status of the transaction signed by `sig`. This method blocks
+    /// until the server sends a response.
+    pub fn get_signature_status(&mut self, sig: &Signature) -> io::Result<SignatureStatus> {
+        let req = Request::GetSignatureStatus { sig: *sig };
+        let data = serialize(&req).expect("serialize GetSignatureStatus in thin_client");
+        self.socket.send_to(&data, &self.addr)?;
+        let mut done = false;
+        while !done {
+            let resp = self.recv_response()?;
+            if let &Response::SignatureStatus { sig: ref x, .. } = &resp {
+                done = x == sig;
+            }
+            self.process_response(resp);
+        }
+        Ok(self.signature_status[sig].clone())
+    }
+
+    /// Request the latest Entry ID that a supermajority of stake voted for, or
+    /// `None` if no entry is final yet. This method blocks until the server
+    /// sends a response.
+    pub fn get_finalized_id(&mut self) -> io::Result<Option<Hash>> {
+        let req = Request::GetFinalizedId;
+        let data = serialize(&req).expect("serialize GetFinalizedId in thin_client");
+        self.socket.send_to(&data, &self.addr)?;
+        let mut done = false;
+        while !done {
+            let resp = self.recv_response()?;
+            if let &Response::FinalizedId { .. } = &resp {
+                done = true;
+            }
+            self.process_response(resp);
+        }
+        Ok(self.finalized_id)
+    }
+
+    /// Request up to `limit` of the events that touched `pubkey`, newest first,
+    /// starting before the one signed by `before`. The server returns at most
+    /// `MAX_HISTORY_ITEMS` at a time. This method blocks until the server sends
+    /// a response.
+    pub fn get_account_history(
+        &mut self,
+        pubkey: &PublicKey,
+        before: Option<Signature>,
+        limit: u64,
+    ) -> io::Result<Vec<HistoryItem>> {
+        let req = Request::GetAccountHistory {
+            key: *pubkey,
+            before,
+            limit,
+        };
+        let data = serialize(&req).expect("serialize GetAccountHistory in thin_client");
+        self.socket.send_to(&data, &self.addr)?;
+        let mut done = false;
+        while !done {
+            let resp = self.recv_response()?;
+            if let &Response::AccountHistory { ref key, .. } = &resp {
+                done = key == pubkey;
+            }
+            self.process_response(resp);
+        }
+        Ok(self.account_history.remove(pubkey).unwrap_or_default())
+    }
+
+    /// Poll the server until the transaction signed by `sig` is processed or
+    /// rejected. If `timeout` elapses first, return the last status seen.
+    pub fn poll_for_signature(
+        &mut self,
+        sig: &Signature,
+        timeout: Duration,
+    ) -> io::Result<SignatureStatus> {
+        let now = Instant::now();
+        loop {
+            let status = self.get_signature_status(sig)?;
+            match status {
+                SignatureStatus::NotFound | SignatureStatus::Pending if now.elapsed() < timeout => {
+                    sleep(Duration::from_millis(100))
+                }
+                _ => return Ok(status),
+            }
+        }
+    }
+
     /// Request the last Entry ID from the server. This method blocks
     /// until the server sends a response.
     pub fn get_last_id(&mut self) -> FutureResult<Hash, ()> {
@@ -149,10 +350,31 @@ impl ThinClient {
     }
 }
 
+pub struct Notifications<'a> {
+    client: &'a mut ThinClient,
+}
+
+impl<'a> Iterator for Notifications<'a> {
+    type Item = Response;
+
+    fn next(&mut self) -> Option<Response> {
+        loop {
+            self.client.renew_subscriptions();
+            let resp = self.client.recv_response().ok()?;
+            match resp {
+                Response::EntryInfo(_) | Response::SubscribeCookie { .. } => {
+                    self.client.process_response(resp);
+                }
+                _ => return Some(resp),
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use accountant::Accountant;
+    use accountant::{Accountant, AccountingError};
     use accounting_stage::AccountingStage;
     use crdt::{Crdt, ReplicatedData};
     use futures::Future;
@@ -163,9 +385,8 @@ mod tests {
     use std::io::sink;
     use std::sync::atomic::{AtomicBool, Ordering};
     use std::sync::{Arc, RwLock};
-    use std::thread::sleep;
-    use std::time::Duration;
-    use std::time::Instant;
+    use streamer::default_window;
+    use thin_client_service::SignedStateHash;
     use tpu::{self, Tpu};
 
     #[test]
@@ -175,9 +396,9 @@ mod tests {
         let serve = UdpSocket::bind("0.0.0.0:0").unwrap();
         let events_socket = UdpSocket::bind("0.0.0.0:0").unwrap();
         let addr = serve.local_addr().unwrap();
-        let pubkey = KeyPair::new().pubkey();
+        let keypair = KeyPair::new();
         let d = ReplicatedData::new(
-            pubkey,
+            keypair.pubkey(),
             gossip.local_addr().unwrap(),
             "0.0.0.0:0".parse().unwrap(),
             serve.local_addr().unwrap(),
@@ -191,6 +412,7 @@ mod tests {
         let accountant = Arc::new(Tpu::new(accounting_stage));
         let threads = Tpu::serve(
             &accountant,
+            keypair,
             d,
             serve,
             events_socket,
@@ -219,6 +441,186 @@ mod tests {
             }
         }
         assert_eq!(balance.unwrap(), 500);
+
+        // The transfer is indexed once its entry is written, which can come after
+        // the balance is updated.
+        let mut history;
+        let now = Instant::now();
+        loop {
+            history = accountant
+                .get_account_history(&bob_pubkey, None, 10)
+                .unwrap();
+            if !history.is_empty() || now.elapsed().as_secs() > 0 {
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].change, 500);
+        exit.store(true, Ordering::Relaxed);
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_poll_for_signature() {
+        let (leader_data, leader_gossip, _, leader_serve, leader_events, leader_keypair) =
+            tpu::test_node();
+        let alice = Mint::new(10_000);
+        let accountant = Accountant::new(&alice);
+        let bob_pubkey = KeyPair::new().pubkey();
+        let exit = Arc::new(AtomicBool::new(false));
+        let accounting_stage = AccountingStage::new(accountant, &alice.last_id(), Some(30));
+        let tpu = Arc::new(Tpu::new(accounting_stage));
+        let serve_addr = leader_serve.local_addr().unwrap();
+        let threads = Tpu::serve(
+            &tpu,
+            leader_keypair,
+            leader_data,
+            leader_serve,
+            leader_events,
+            leader_gossip,
+            exit.clone(),
+            sink(),
+        ).unwrap();
+        sleep(Duration::from_millis(300));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        let mut client = ThinClient::new(serve_addr, socket);
+        let last_id = client.get_last_id().wait().unwrap();
+        let timeout = Duration::new(5, 0);
+
+        let sig = client
+            .transfer(500, &alice.keypair(), bob_pubkey, &last_id)
+            .unwrap();
+        assert_eq!(
+            client.poll_for_signature(&sig, timeout).unwrap(),
+            SignatureStatus::Processed
+        );
+
+        let sig = client
+            .transfer(10_000, &alice.keypair(), bob_pubkey, &last_id)
+            .unwrap();
+        assert_eq!(
+            client.poll_for_signature(&sig, timeout).unwrap(),
+            SignatureStatus::Rejected(AccountingError::InsufficientFunds)
+        );
+
+        exit.store(true, Ordering::Relaxed);
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_finalized_id() {
+        let (leader_data, leader_gossip, _, leader_serve, leader_events, leader_keypair) =
+            tpu::test_node();
+        let alice = Mint::new(10_000);
+        let accountant = Accountant::new(&alice);
+        let exit = Arc::new(AtomicBool::new(false));
+        let accounting_stage = AccountingStage::new(accountant, &alice.last_id(), Some(30));
+        let tpu = Arc::new(Tpu::new(accounting_stage));
+        let serve_addr = leader_serve.local_addr().unwrap();
+        let threads = Tpu::serve(
+            &tpu,
+            leader_keypair,
+            leader_data,
+            leader_serve,
+            leader_events,
+            leader_gossip,
+            exit.clone(),
+            sink(),
+        ).unwrap();
+        sleep(Duration::from_millis(300));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        let mut client = ThinClient::new(serve_addr, socket);
+        let last_id = client.get_last_id().wait().unwrap();
+        assert_eq!(client.get_finalized_id().unwrap(), None);
+
+        // Nothing has been spent since the mint, so a fresh Accountant agrees
+        // on the state. Alice holds all the stake, so her vote alone finalizes
+        // the entry.
+        let state_hash = Accountant::new(&alice).state_hash();
+        let vote = SignedStateHash::new(&alice.keypair(), last_id, state_hash);
+        let data = serialize(&Request::StateHash(vote)).unwrap();
+        client.socket.send_to(&data, &serve_addr).unwrap();
+
+        let now = Instant::now();
+        let mut finalized_id = None;
+        while finalized_id.is_none() && now.elapsed() < Duration::new(5, 0) {
+            finalized_id = client.get_finalized_id().unwrap();
+        }
+        assert_eq!(finalized_id, Some(last_id));
+
+        exit.store(true, Ordering::Relaxed);
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        let (leader_data, leader_gossip, _, leader_serve, leader_events, leader_keypair) =
+            tpu::test_node();
+        let alice = Mint::new(10_000);
+        let accountant = Accountant::new(&alice);
+        let bob_pubkey = KeyPair::new().pubkey();
+        let exit = Arc::new(AtomicBool::new(false));
+        let accounting_stage = AccountingStage::new(accountant, &alice.last_id(), Some(30));
+        let tpu = Arc::new(Tpu::new(accounting_stage));
+        let serve_addr = leader_serve.local_addr().unwrap();
+        let threads = Tpu::serve(
+            &tpu,
+            leader_keypair,
+            leader_data,
+            leader_serve,
+            leader_events,
+            leader_gossip,
+            exit.clone(),
+            sink(),
+        ).unwrap();
+        sleep(Duration::from_millis(300));
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::new(5, 0))).unwrap();
+        let mut client = ThinClient::new(serve_addr, socket);
+        let last_id = client.get_last_id().wait().unwrap();
+        let tr = Transaction::new(&alice.keypair(), bob_pubkey, 500, last_id);
+        let sig = tr.sig;
+        let subscriptions = vec![
+            Subscription::Balance(bob_pubkey),
+            Subscription::Signature(sig),
+        ];
+        client.subscribe(subscriptions).unwrap();
+        sleep(Duration::from_millis(100));
+        client.transfer_signed(tr).unwrap();
+
+        let mut balance = None;
+        let mut status = None;
+        for resp in client.notifications() {
+            match resp {
+                Response::Balance { key, val } => {
+                    assert_eq!(key, bob_pubkey);
+                    balance = val;
+                }
+                Response::SignatureStatus { sig: x, status: y } => {
+                    assert_eq!(x, sig);
+                    status = Some(y);
+                }
+                _ => panic!("unexpected notification"),
+            }
+            if balance.is_some() && status.is_some() {
+                break;
+            }
+        }
+        assert_eq!(balance, Some(500));
+        assert_eq!(status, Some(SignatureStatus::Processed));
+
         exit.store(true, Ordering::Relaxed);
         for t in threads {
             t.join().unwrap();
@@ -227,7 +629,8 @@ mod tests {
 
     #[test]
     fn test_bad_sig() {
-        let (leader_data, leader_gossip, _, leader_serve, leader_events) = tpu::test_node();
+        let (leader_data, leader_gossip, _, leader_serve, leader_events, leader_keypair) =
+            tpu::test_node();
         let alice = Mint::new(10_000);
         let accountant = Accountant::new(&alice);
         let bob_pubkey = KeyPair::new().pubkey();
@@ -237,6 +640,7 @@ mod tests {
         let serve_addr = leader_serve.local_addr().unwrap();
         let threads = Tpu::serve(
             &tpu,
+            leader_keypair,
             leader_data,
             leader_serve,
             leader_events,
@@ -273,19 +677,27 @@ mod tests {
         }
     }
 
-    fn test_node() -> (ReplicatedData, UdpSocket, UdpSocket, UdpSocket, UdpSocket) {
+    fn test_node() -> (
+        ReplicatedData,
+        UdpSocket,
+        UdpSocket,
+        UdpSocket,
+        UdpSocket,
+        KeyPair,
+    ) {
         let gossip = UdpSocket::bind("0.0.0.0:0").unwrap();
         let serve = UdpSocket::bind("0.0.0.0:0").unwrap();
         let events_socket = UdpSocket::bind("0.0.0.0:0").unwrap();
         let replicate = UdpSocket::bind("0.0.0.0:0").unwrap();
-        let pubkey = KeyPair::new().pubkey();
-        let leader = ReplicatedData::new(
-            pubkey,
+        let keypair = KeyPair::new();
+        let mut leader = ReplicatedData::new(
+            keypair.pubkey(),
             gossip.local_addr().unwrap(),
             replicate.local_addr().unwrap(),
             serve.local_addr().unwrap(),
         );
-        (leader, gossip, serve, replicate, events_socket)
+        leader.sign(&keypair);
+        (leader, gossip, serve, replicate, events_socket, keypair)
     }
 
     #[test]
@@ -313,6 +725,7 @@ mod tests {
 
         let leader_threads = Tpu::serve(
             &leader_acc,
+            leader.5,
             leader.0.clone(),
             leader.2,
             leader.4,
@@ -322,6 +735,7 @@ mod tests {
         ).unwrap();
         let replicant_threads = Tpu::replicate(
             &replicant_acc,
+            replicant.5,
             replicant.0.clone(),
             replicant.1,
             replicant.2,
@@ -331,16 +745,17 @@ mod tests {
         ).unwrap();
 
         //lets spy on the network
-        let (mut spy, spy_gossip, _, _, _) = test_node();
+        let (mut spy, spy_gossip, _, _, _, spy_keypair) = test_node();
         let daddr = "0.0.0.0:0".parse().unwrap();
         spy.replicate_addr = daddr;
         spy.serve_addr = daddr;
-        let mut spy_crdt = Crdt::new(spy);
+        let mut spy_crdt = Crdt::new(spy, Arc::new(spy_keypair));
         spy_crdt.insert(leader.0.clone());
         spy_crdt.set_leader(leader.0.id);
 
         let spy_ref = Arc::new(RwLock::new(spy_crdt));
-        let t_spy_listen = Crdt::listen(spy_ref.clone(), spy_gossip, exit.clone());
+        let t_spy_listen =
+            Crdt::listen(spy_ref.clone(), default_window(), spy_gossip, exit.clone());
         let t_spy_gossip = Crdt::gossip(spy_ref.clone(), exit.clone());
         //wait for the network to converge
         for _ in 0..20 {
@@ -1,23 +1,27 @@
 //! The `thin_client_service` sits alongside the TPU and queries it for information
 //! on behalf of thing clients.
 
-use accountant::Accountant;
+use account_history::{AccountHistory, HistoryItem, MAX_HISTORY_ITEMS};
+use accountant::{Accountant, SignatureStatus};
 use accounting_stage::AccountingStage;
 use bincode::{deserialize, serialize};
 use entry::Entry;
 use event::Event;
-use hash::Hash;
+use hash::{extend_and_hash, hash, Hash};
 use packet;
 use packet::SharedPackets;
+use rand::{thread_rng, Rng};
 use rayon::prelude::*;
 use result::Result;
-use signature::PublicKey;
-use std::collections::VecDeque;
-use std::net::{SocketAddr, UdpSocket};
+use signature::{KeyPair, KeyPairUtil, PublicKey, Signature, SignatureUtil};
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
 use transaction::Transaction;
 //use std::io::{Cursor, Write};
 //use std::sync::atomic::{AtomicBool, Ordering};
 //use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
 //use std::thread::{spawn, JoinHandle};
@@ -31,12 +35,85 @@ use timing;
 pub enum Request {
     Transaction(Transaction),
     GetBalance { key: PublicKey },
-    Subscribe { subscriptions: Vec<Subscription> },
+    GetSignatureStatus { sig: Signature },
+    /// Subscribe to updates, or renew the subscriptions before they expire.
+    /// They only take effect with the `cookie` the server sends back to this
+    /// address in a `Response::SubscribeCookie`, so a spoofed address can't be
+    /// subscribed.
+    Subscribe {
+        subscriptions: Vec<Subscription>,
+        cookie: Option<Hash>,
+    },
+    Unsubscribe {
+        subscriptions: Vec<Subscription>,
+        cookie: Hash,
+    },
+    StateHash(SignedStateHash),
+    GetFinalizedId,
+    /// Page through the events that touched `key`, newest first. Pass the
+    /// signature of the last item received as `before` to get the next page.
+    GetAccountHistory {
+        key: PublicKey,
+        before: Option<Signature>,
+        limit: u64,
+    },
 }
 
+/// A replica's claim of what the accountant state hash was after it
+/// processed the Entry `entry_id`. The leader records it in the ledger as
+/// the replica's vote.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedStateHash {
+    pub from: PublicKey,
+    pub entry_id: Hash,
+    pub state_hash: Hash,
+    pub sig: Signature,
+}
+
+impl SignedStateHash {
+    pub fn new(keypair: &KeyPair, entry_id: Hash, state_hash: Hash) -> Self {
+        let sign_data = serialize(&(&entry_id, &state_hash)).expect("serialize in fn new");
+        SignedStateHash {
+            from: keypair.pubkey(),
+            entry_id,
+            state_hash,
+            sig: Signature::clone_from_slice(keypair.sign(&sign_data).as_ref()),
+        }
+    }
+
+    pub fn verify(&self) -> bool {
+        let sign_data =
+            serialize(&(&self.entry_id, &self.state_hash)).expect("serialize in fn verify");
+        self.sig.verify(&self.from, &sign_data)
+    }
+
+    /// Return the Vote this claim stands for. Both are signed over the same
+    /// data, so the signature carries over.
+    pub fn into_vote(self) -> Event {
+        Event::Vote {
+            from: self.from,
+            entry_id: self.entry_id,
+            state_hash: self.state_hash,
+            sig: self.sig,
+        }
+    }
+}
+
+/// Subscriptions expire unless renewed within this many milliseconds
+pub const SUBSCRIPTION_TIMEOUT_MS: u64 = 60_000;
+
+/// The most subscriptions a single address may hold
+pub const MAX_SUBSCRIPTIONS_PER_ADDR: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Subscription {
+    /// Push every Entry's `EntryInfo`, starting with the last one recorded
     EntryInfo,
+    /// Push a `Response::Balance` whenever the account's balance changes
+    Balance(PublicKey),
+    /// Push a `Response::SignatureStatus` whenever the transaction's status
+    /// changes, until it is `Processed`
+    Signature(Signature),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -46,11 +123,121 @@ pub struct EntryInfo {
     pub num_events: u64,
 }
 
+/// Release one of the subscriptions held by `addr`.
+fn release(counts: &mut HashMap<SocketAddr, usize>, addr: &SocketAddr) {
+    let is_last = match counts.get_mut(addr) {
+        Some(count) => {
+            *count -= 1;
+            *count == 0
+        }
+        None => false,
+    };
+    if is_last {
+        counts.remove(addr);
+    }
+}
+
+/// The addresses subscribed to each update. Each address is only notified once
+/// per update, however many times it subscribes.
+#[derive(Default)]
+struct Subscribers {
+    /// The addresses subscribed to each update, and when each last subscribed
+    addrs: HashMap<Subscription, HashMap<SocketAddr, Instant>>,
+    /// The number of subscriptions each address holds
+    counts: HashMap<SocketAddr, usize>,
+    /// The balance last pushed for each subscribed account
+    balances: HashMap<PublicKey, Option<i64>>,
+    /// The status last pushed for each subscribed transaction
+    statuses: HashMap<Signature, SignatureStatus>,
+    /// The info of the last Entry recorded, for new `EntryInfo` subscribers
+    last_entry_info: Option<EntryInfo>,
+}
+
+impl Subscribers {
+    /// Add or renew a subscription. Returns false if `addr` already holds
+    /// `MAX_SUBSCRIPTIONS_PER_ADDR` others.
+    fn subscribe(&mut self, subscription: Subscription, addr: SocketAddr, now: Instant) -> bool {
+        if !self.is_subscribed(&subscription, &addr) {
+            let count = self.counts.entry(addr).or_insert(0);
+            if *count >= MAX_SUBSCRIPTIONS_PER_ADDR {
+                return false;
+            }
+            *count += 1;
+        }
+        self.addrs
+            .entry(subscription)
+            .or_insert_with(HashMap::new)
+            .insert(addr, now);
+        true
+    }
+
+    fn is_subscribed(&self, subscription: &Subscription, addr: &SocketAddr) -> bool {
+        self.addrs
+            .get(subscription)
+            .map_or(false, |addrs| addrs.contains_key(addr))
+    }
+
+    fn unsubscribe(&mut self, subscription: &Subscription, addr: &SocketAddr) {
+        let removed = match self.addrs.get_mut(subscription) {
+            Some(addrs) => addrs.remove(addr).is_some(),
+            None => false,
+        };
+        if removed {
+            release(&mut self.counts, addr);
+            self.prune();
+        }
+    }
+
+    /// Drop every address subscribed to `subscription`.
+    fn forget(&mut self, subscription: &Subscription) {
+        if let Some(addrs) = self.addrs.remove(subscription) {
+            for addr in addrs.keys() {
+                release(&mut self.counts, addr);
+            }
+            self.prune();
+        }
+    }
+
+    /// Drop the subscriptions that weren't renewed in time.
+    fn expire(&mut self, now: Instant) {
+        let timeout = Duration::from_millis(SUBSCRIPTION_TIMEOUT_MS);
+        let counts = &mut self.counts;
+        for addrs in self.addrs.values_mut() {
+            addrs.retain(|addr, renewed| {
+                let is_live = now.duration_since(*renewed) < timeout;
+                if !is_live {
+                    release(counts, addr);
+                }
+                is_live
+            });
+        }
+        self.prune();
+    }
+
+    /// Forget the updates nobody is subscribed to anymore.
+    fn prune(&mut self) {
+        self.addrs.retain(|_, addrs| !addrs.is_empty());
+        let addrs = &self.addrs;
+        self.balances
+            .retain(|key, _| addrs.contains_key(&Subscription::Balance(*key)));
+        self.statuses
+            .retain(|sig, _| addrs.contains_key(&Subscription::Signature(*sig)));
+    }
+
+    fn get_addrs(&self, subscription: &Subscription) -> Vec<SocketAddr> {
+        self.addrs
+            .get(subscription)
+            .map(|addrs| addrs.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
 impl Request {
     /// Verify the request is valid.
     pub fn verify(&self) -> bool {
         match *self {
             Request::Transaction(ref tr) => tr.verify_plan(),
+            Request::StateHash(ref state_hash) => state_hash.verify(),
             _ => true,
         }
     }
@@ -60,29 +247,54 @@ impl Request {
 pub enum Response {
     Balance { key: PublicKey, val: Option<i64> },
     EntryInfo(EntryInfo),
+    SignatureStatus { sig: Signature, status: SignatureStatus },
+    FinalizedId { id: Option<Hash> },
+    /// The cookie to send with subscriptions from the address this is sent to
+    SubscribeCookie { cookie: Hash },
+    AccountHistory {
+        key: PublicKey,
+        items: Vec<HistoryItem>,
+    },
 }
 
 pub struct ThinClientService {
     //pub output: Mutex<Receiver<Response>>,
     //response_sender: Mutex<Sender<Response>>,
     accountant: Arc<Accountant>,
-    entry_info_subscribers: Mutex<Vec<SocketAddr>>,
+    subscribers: Mutex<Subscribers>,
+    /// Where notifications are queued for their responder, once it's running
+    notifier: Mutex<Option<(streamer::BlobSender, packet::BlobRecycler)>>,
+    account_history: AccountHistory,
+    /// A secret that keys the subscription cookies
+    cookie_secret: Hash,
+    /// The number of events recorded
+    transaction_count: AtomicUsize,
 }
 
 impl ThinClientService {
     /// Create a new Tpu that wraps the given Accountant.
     pub fn new(accountant: Arc<Accountant>) -> Self {
+        Self::new_with_history(accountant, AccountHistory::default())
+    }
+
+    /// Create a new ThinClientService that serves `account_history`, typically
+    /// rebuilt while replaying the ledger into `accountant`.
+    pub fn new_with_history(accountant: Arc<Accountant>, account_history: AccountHistory) -> Self {
         //let (response_sender, output) = channel();
         ThinClientService {
             //output: Mutex::new(output),
             //response_sender: Mutex::new(response_sender),
             accountant,
-            entry_info_subscribers: Mutex::new(vec![]),
+            subscribers: Mutex::new(Subscribers::default()),
+            notifier: Mutex::new(None),
+            account_history,
+            cookie_secret: hash(&thread_rng().gen::<[u8; 32]>()),
+            transaction_count: AtomicUsize::new(0),
         }
     }
 
     /// Process Request items sent by clients.
-    fn process_request(
+    pub fn process_request(
         &self,
         msg: Request,
         rsp_addr: SocketAddr,
@@ -94,20 +306,107 @@ impl ThinClientService {
                 info!("Response::Balance {:?}", rsp);
                 Some(rsp)
             }
-            Request::Transaction(_) => unreachable!(),
-            Request::Subscribe { subscriptions } => {
-                for subscription in subscriptions {
-                    match subscription {
-                        Subscription::EntryInfo => {
-                            self.entry_info_subscribers.lock().unwrap().push(rsp_addr)
+            Request::GetSignatureStatus { sig } => {
+                let status = self.accountant.get_signature_status(&sig);
+                let rsp = (Response::SignatureStatus { sig, status }, rsp_addr);
+                info!("Response::SignatureStatus {:?}", rsp);
+                Some(rsp)
+            }
+            Request::GetFinalizedId => {
+                let id = self.accountant.finalized_id();
+                let rsp = (Response::FinalizedId { id }, rsp_addr);
+                info!("Response::FinalizedId {:?}", rsp);
+                Some(rsp)
+            }
+            Request::GetAccountHistory { key, before, limit } => {
+                let limit = min(limit as usize, MAX_HISTORY_ITEMS);
+                let items = self.account_history.get_history(&key, before, limit);
+                let rsp = (Response::AccountHistory { key, items }, rsp_addr);
+                info!("Response::AccountHistory {:?}", rsp);
+                Some(rsp)
+            }
+            Request::Transaction(_) | Request::StateHash(_) => unreachable!(),
+            Request::Subscribe {
+                subscriptions,
+                cookie,
+            } => {
+                let expected = self.subscription_cookie(&rsp_addr);
+                if cookie != Some(expected) {
+                    let rsp = (Response::SubscribeCookie { cookie: expected }, rsp_addr);
+                    info!("Response::SubscribeCookie {:?}", rsp);
+                    return Some(rsp);
+                }
+                let mut rsps = vec![];
+                {
+                    let now = Instant::now();
+                    let mut subscribers = self.subscribers.lock().unwrap();
+                    for subscription in subscriptions {
+                        let is_new = !subscribers.is_subscribed(&subscription, &rsp_addr);
+                        match subscription {
+                            Subscription::EntryInfo => if is_new {
+                                // The next entry may be a while, so start with the last.
+                                if let Some(ref entry_info) = subscribers.last_entry_info {
+                                    rsps.push((Response::EntryInfo(entry_info.clone()), rsp_addr));
+                                }
+                            },
+                            Subscription::Balance(key) => {
+                                // Only changes from here on are pushed.
+                                if !subscribers.balances.contains_key(&key) {
+                                    let val = self.accountant.get_balance(&key);
+                                    subscribers.balances.insert(key, val);
+                                }
+                            }
+                            Subscription::Signature(sig) => {
+                                // The transaction may have been processed already,
+                                // and no entry may come along to push it.
+                                let status = match subscribers.statuses.get(&sig) {
+                                    Some(status) => status.clone(),
+                                    None => self.accountant.get_signature_status(&sig),
+                                };
+                                if status != SignatureStatus::NotFound {
+                                    let rsp = Response::SignatureStatus {
+                                        sig,
+                                        status: status.clone(),
+                                    };
+                                    rsps.push((rsp, rsp_addr));
+                                }
+                                if status == SignatureStatus::Processed {
+                                    continue;
+                                }
+                                subscribers.statuses.insert(sig, status);
+                            }
+                        }
+                        if !subscribers.subscribe(subscription, rsp_addr, now) {
+                            warn!("too many subscriptions from {}", rsp_addr);
                         }
                     }
+                    subscribers.prune();
+                }
+                self.notify(rsps);
+                None
+            }
+            Request::Unsubscribe {
+                subscriptions,
+                cookie,
+            } => {
+                if cookie != self.subscription_cookie(&rsp_addr) {
+                    return None;
+                }
+                let mut subscribers = self.subscribers.lock().unwrap();
+                for subscription in &subscriptions {
+                    subscribers.unsubscribe(subscription, &rsp_addr);
                 }
                 None
             }
         }
     }
 
+    /// Return the cookie that `addr` must send to subscribe. Only `addr` is sent
+    /// its cookie, and it can't be guessed without `cookie_secret`.
+    fn subscription_cookie(&self, addr: &SocketAddr) -> Hash {
+        extend_and_hash(&self.cookie_secret, addr.to_string().as_bytes())
+    }
+
     pub fn process_requests(
         &self,
         reqs: Vec<(Request, SocketAddr)>,
@@ -117,25 +416,102 @@ impl ThinClientService {
             .collect()
     }
 
-    pub fn notify_entry_info_subscribers(&self, entry: &Entry) {
-        // TODO: No need to bind().
-        let socket = UdpSocket::bind("0.0.0.0:0").expect("bind");
+    /// Index the events in `entry` for account history requests, once the
+    /// accountant has processed it and paid its fees to the leader.
+    pub fn record_history(&self, entry: &Entry) {
+        self.account_history
+            .record_entry(entry, self.accountant.leader());
+        self.transaction_count
+            .fetch_add(entry.events.len(), Ordering::Relaxed);
+    }
+
+    /// Return the number of events recorded.
+    pub fn transaction_count(&self) -> usize {
+        self.transaction_count.load(Ordering::Relaxed)
+    }
 
-        // copy subscribers to avoid taking lock while doing io
-        let addrs = self.entry_info_subscribers.lock().unwrap().clone();
+    /// Start queueing notifications to `sender`, to be sent by its responder.
+    pub fn set_notifier(&self, sender: streamer::BlobSender, blob_recycler: packet::BlobRecycler) {
+        *self.notifier.lock().unwrap() = Some((sender, blob_recycler));
+    }
+
+    pub fn notify_entry_info_subscribers(&self, entry: &Entry) {
+        let entry_info = EntryInfo {
+            id: entry.id,
+            num_hashes: entry.num_hashes,
+            num_events: entry.events.len() as u64,
+        };
+        let addrs = {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            subscribers.expire(Instant::now());
+            subscribers.last_entry_info = Some(entry_info.clone());
+            subscribers.get_addrs(&Subscription::EntryInfo)
+        };
         trace!("Sending to {} addrs", addrs.len());
-        for addr in addrs {
-            let entry_info = EntryInfo {
-                id: entry.id,
-                num_hashes: entry.num_hashes,
-                num_events: entry.events.len() as u64,
-            };
-            let data = serialize(&Response::EntryInfo(entry_info)).expect("serialize EntryInfo");
-            trace!("sending {} to {}", data.len(), addr);
-            //TODO dont do IO here, this needs to be on a separate channel
-            let res = socket.send_to(&data, addr);
-            if res.is_err() {
-                eprintln!("couldn't send response: {:?}", res);
+        let rsps = addrs
+            .into_iter()
+            .map(|addr| (Response::EntryInfo(entry_info.clone()), addr))
+            .collect();
+        self.notify(rsps);
+    }
+
+    /// Push the balances that changed since they were last pushed, and the
+    /// statuses of the transactions that moved on. Call it after the
+    /// accountant processes an Entry.
+    pub fn notify_subscribers(&self) {
+        let mut rsps = vec![];
+        {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            subscribers.expire(Instant::now());
+            let subscribers = &mut *subscribers;
+            for (key, last_val) in &mut subscribers.balances {
+                let val = self.accountant.get_balance(key);
+                if val != *last_val {
+                    *last_val = val;
+                    if let Some(addrs) = subscribers.addrs.get(&Subscription::Balance(*key)) {
+                        for addr in addrs.keys() {
+                            rsps.push((Response::Balance { key: *key, val }, *addr));
+                        }
+                    }
+                }
+            }
+            let mut processed = vec![];
+            for (sig, last_status) in &mut subscribers.statuses {
+                let status = self.accountant.get_signature_status(sig);
+                if status != *last_status {
+                    *last_status = status.clone();
+                    if let Some(addrs) = subscribers.addrs.get(&Subscription::Signature(*sig)) {
+                        for addr in addrs.keys() {
+                            let rsp = Response::SignatureStatus {
+                                sig: *sig,
+                                status: status.clone(),
+                            };
+                            rsps.push((rsp, *addr));
+                        }
+                    }
+                    if status == SignatureStatus::Processed {
+                        processed.push(*sig);
+                    }
+                }
+            }
+            for sig in processed {
+                subscribers.forget(&Subscription::Signature(sig));
+            }
+        }
+        self.notify(rsps);
+    }
+
+    /// Queue notifications for their responder. They're dropped if it isn't
+    /// running.
+    fn notify(&self, rsps: Vec<(Response, SocketAddr)>) {
+        if rsps.is_empty() {
+            return;
+        }
+        if let Some((ref sender, ref blob_recycler)) = *self.notifier.lock().unwrap() {
+            let res = Self::serialize_responses(rsps, blob_recycler)
+                .and_then(|blobs| sender.send(blobs).map_err(From::from));
+            if let Err(e) = res {
+                warn!("couldn't queue notifications: {:?}", e);
             }
         }
     }
@@ -164,7 +540,7 @@ impl ThinClientService {
             .collect()
     }
 
-    /// Split Request list into verified transactions and the rest
+    /// Split Request list into verified transactions and votes, and the rest
     fn partition_requests(
         req_vers: Vec<(Request, SocketAddr, u8)>,
     ) -> (Vec<Event>, Vec<(Request, SocketAddr)>) {
@@ -177,6 +553,7 @@ impl ThinClientService {
                         events.push(Event::Transaction(tr));
                     }
                 }
+                Request::StateHash(msg) => events.push(msg.into_vote()),
                 _ => reqs.push((msg, rsp_addr)),
             }
         }
@@ -299,10 +676,19 @@ pub fn to_request_packets(r: &packet::PacketRecycler, reqs: Vec<Request>) -> Vec
 
 #[cfg(test)]
 mod tests {
+    use accountant::Accountant;
     use bincode::serialize;
     use ecdsa;
+    use hash::{hash, Hash};
+    use mint::Mint;
     use packet::{PacketRecycler, NUM_PACKETS};
-    use thin_client_service::{to_request_packets, Request};
+    use signature::{KeyPair, KeyPairUtil};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use thin_client_service::{to_request_packets, Request, Response, SignedStateHash,
+                              Subscribers, Subscription, ThinClientService,
+                              MAX_SUBSCRIPTIONS_PER_ADDR, SUBSCRIPTION_TIMEOUT_MS};
     use transaction::{memfind, test_tx};
 
     #[test]
@@ -314,6 +700,103 @@ mod tests {
         assert_matches!(memfind(&packet, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]), None);
     }
 
+    #[test]
+    fn test_state_hash_verify() {
+        let keypair = KeyPair::new();
+        let zero = Hash::default();
+        let msg = SignedStateHash::new(&keypair, zero, hash(&zero));
+        assert!(Request::StateHash(msg.clone()).verify());
+
+        let mut bad_msg = msg.clone();
+        bad_msg.state_hash = zero; // <-- attack!
+        assert!(!Request::StateHash(bad_msg).verify());
+
+        let mut bad_msg = msg;
+        bad_msg.from = KeyPair::new().pubkey(); // <-- attack!
+        assert!(!Request::StateHash(bad_msg).verify());
+    }
+
+    #[test]
+    fn test_subscribe_cookie() {
+        let mint = Mint::new(1);
+        let service = ThinClientService::new(Arc::new(Accountant::new(&mint)));
+        let addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let other_addr: SocketAddr = "127.0.0.1:1235".parse().unwrap();
+        let subscribe = |cookie| Request::Subscribe {
+            subscriptions: vec![Subscription::EntryInfo],
+            cookie,
+        };
+        let is_subscribed = || {
+            let subscribers = service.subscribers.lock().unwrap();
+            !subscribers.get_addrs(&Subscription::EntryInfo).is_empty()
+        };
+
+        // Without a cookie, the address is only sent its cookie.
+        let cookie = match service.process_request(subscribe(None), addr) {
+            Some((Response::SubscribeCookie { cookie }, rsp_addr)) => {
+                assert_eq!(rsp_addr, addr);
+                cookie
+            }
+            rsp => panic!("unexpected response {:?}", rsp),
+        };
+        assert!(!is_subscribed());
+
+        // Another address's cookie doesn't work.
+        assert!(service.process_request(subscribe(Some(cookie)), other_addr).is_some());
+        assert!(!is_subscribed());
+
+        assert!(service.process_request(subscribe(Some(cookie)), addr).is_none());
+        assert!(is_subscribed());
+
+        // Nor can another address unsubscribe it.
+        let unsubscribe = Request::Unsubscribe {
+            subscriptions: vec![Subscription::EntryInfo],
+            cookie: Hash::default(),
+        };
+        service.process_request(unsubscribe, addr);
+        assert!(is_subscribed());
+        let unsubscribe = Request::Unsubscribe {
+            subscriptions: vec![Subscription::EntryInfo],
+            cookie,
+        };
+        service.process_request(unsubscribe, addr);
+        assert!(!is_subscribed());
+    }
+
+    #[test]
+    fn test_subscribers() {
+        let mut subscribers = Subscribers::default();
+        let addr: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let now = Instant::now();
+
+        // Subscribing twice only notifies once.
+        assert!(subscribers.subscribe(Subscription::EntryInfo, addr, now));
+        assert!(subscribers.subscribe(Subscription::EntryInfo, addr, now));
+        assert_eq!(subscribers.get_addrs(&Subscription::EntryInfo), vec![addr]);
+        assert_eq!(subscribers.counts[&addr], 1);
+
+        subscribers.unsubscribe(&Subscription::EntryInfo, &addr);
+        assert!(subscribers.get_addrs(&Subscription::EntryInfo).is_empty());
+        assert!(subscribers.counts.is_empty());
+
+        // Each address may only hold so many.
+        for _ in 0..MAX_SUBSCRIPTIONS_PER_ADDR {
+            let subscription = Subscription::Balance(KeyPair::new().pubkey());
+            assert!(subscribers.subscribe(subscription, addr, now));
+        }
+        assert!(!subscribers.subscribe(Subscription::EntryInfo, addr, now));
+        let other: SocketAddr = "127.0.0.1:1235".parse().unwrap();
+        assert!(subscribers.subscribe(Subscription::EntryInfo, other, now));
+
+        // Subscriptions that aren't renewed expire.
+        let later = now + Duration::from_millis(SUBSCRIPTION_TIMEOUT_MS);
+        assert!(subscribers.subscribe(Subscription::EntryInfo, other, later));
+        subscribers.expire(later);
+        assert_eq!(subscribers.get_addrs(&Subscription::EntryInfo), vec![other]);
+        assert_eq!(subscribers.addrs.len(), 1);
+        assert_eq!(subscribers.counts.len(), 1);
+    }
+
     #[test]
     fn test_to_packets() {
         let tr = Request::Transaction(test_tx());
@@ -3,7 +3,7 @@
 use bincode::serialize;
 use chrono::prelude::*;
 use hash::Hash;
-use plan::{Condition, Payment, Plan};
+use plan::{total_tokens, Payment, Plan, MAX_PAYMENTS};
 use rayon::prelude::*;
 use signature::{KeyPair, KeyPairUtil, PublicKey, Signature, SignatureUtil};
 
@@ -14,6 +14,7 @@ pub const PUB_KEY_OFFSET: usize = 80;
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct TransactionData {
     pub tokens: i64,
+    pub fee: i64,
     pub last_id: Hash,
     pub plan: Plan,
 }
@@ -26,15 +27,22 @@ pub struct Transaction {
 }
 
 impl Transaction {
-    /// Create and sign a new Transaction. Used for unit-testing.
-    pub fn new(from_keypair: &KeyPair, to: PublicKey, tokens: i64, last_id: Hash) -> Self {
+    /// Create and sign a new Transaction that debits `tokens` from the signer,
+    /// pays `fee` to the leader and leaves the rest to `plan`.
+    pub fn new_with_plan(
+        from_keypair: &KeyPair,
+        plan: Plan,
+        tokens: i64,
+        fee: i64,
+        last_id: Hash,
+    ) -> Self {
         let from = from_keypair.pubkey();
-        let plan = Plan::Pay(Payment { tokens, to });
         let mut tr = Transaction {
             sig: Signature::default(),
             data: TransactionData {
                 plan,
                 tokens,
+                fee,
                 last_id,
             },
             from: from,
@@ -43,6 +51,42 @@ impl Transaction {
         tr
     }
 
+    /// Create and sign a new Transaction that pays `fee` tokens to the leader
+    /// and the remaining `tokens - fee` to `to`.
+    pub fn new_taxed(
+        from_keypair: &KeyPair,
+        to: PublicKey,
+        tokens: i64,
+        fee: i64,
+        last_id: Hash,
+    ) -> Self {
+        let plan = Plan::new_payment(tokens - fee, to);
+        Self::new_with_plan(from_keypair, plan, tokens, fee, last_id)
+    }
+
+    /// Create and sign a Transaction that makes all `payments` or none of them.
+    /// The sender is debited the sum of the payments plus `fee`. Returns None if
+    /// that sum overflows, or if there are more than `MAX_PAYMENTS` payments to
+    /// fit in a packet.
+    pub fn new_multi_payment(
+        from_keypair: &KeyPair,
+        payments: Vec<Payment>,
+        fee: i64,
+        last_id: Hash,
+    ) -> Option<Self> {
+        if payments.len() > MAX_PAYMENTS {
+            return None;
+        }
+        let tokens = total_tokens(&payments)?.checked_add(fee)?;
+        let plan = Plan::new_multi_payment(payments);
+        Some(Self::new_with_plan(from_keypair, plan, tokens, fee, last_id))
+    }
+
+    /// Create and sign a new Transaction. Used for unit-testing.
+    pub fn new(from_keypair: &KeyPair, to: PublicKey, tokens: i64, last_id: Hash) -> Self {
+        Self::new_taxed(from_keypair, to, tokens, 0, last_id)
+    }
+
     /// Create and sign a postdated Transaction. Used for unit-testing.
     pub fn new_on_date(
         from_keypair: &KeyPair,
@@ -52,14 +96,12 @@ impl Transaction {
         last_id: Hash,
     ) -> Self {
         let from = from_keypair.pubkey();
-        let plan = Plan::Race(
-            (Condition::Timestamp(dt), Payment { tokens, to }),
-            (Condition::Signature(from), Payment { tokens, to: from }),
-        );
+        let plan = Plan::new_cancelable_future_payment(dt, from, tokens, to);
         let mut tr = Transaction {
             data: TransactionData {
                 plan,
                 tokens,
+                fee: 0,
                 last_id,
             },
             from: from,
@@ -83,8 +125,11 @@ impl Transaction {
         self.sig.verify(&self.from, &self.get_sign_data())
     }
 
+    /// Verify the fee is within the transferred tokens and that the plan
+    /// spends whatever is left over.
     pub fn verify_plan(&self) -> bool {
-        self.data.plan.verify(self.data.tokens)
+        self.data.fee >= 0 && self.data.fee <= self.data.tokens
+            && self.data.plan.verify(self.data.tokens - self.data.fee)
     }
 }
 
@@ -156,6 +201,7 @@ mod tests {
             data: TransactionData {
                 plan,
                 tokens: 0,
+                fee: 0,
                 last_id: Default::default(),
             },
             from: Default::default(),
@@ -204,6 +250,87 @@ mod tests {
         assert_matches!(memfind(&tx, &tr.from), Some(PUB_KEY_OFFSET));
     }
 
+    #[test]
+    fn test_multi_payment_layout() {
+        let keypair = KeyPair::new();
+        let payments = (0..4)
+            .map(|i| Payment {
+                tokens: i,
+                to: KeyPair::new().pubkey(),
+            })
+            .collect();
+        let tr = Transaction::new_multi_payment(&keypair, payments, 1, Hash::default()).unwrap();
+        assert!(tr.verify_plan());
+        let sign_data = tr.get_sign_data();
+        let tx = serialize(&tr).unwrap();
+        assert_matches!(memfind(&tx, &sign_data), Some(SIGNED_DATA_OFFSET));
+        assert_matches!(memfind(&tx, &tr.sig), Some(SIG_OFFSET));
+        assert_matches!(memfind(&tx, &tr.from), Some(PUB_KEY_OFFSET));
+    }
+
+    #[test]
+    fn test_multi_payment_overflow() {
+        let keypair = KeyPair::new();
+        let payments: Vec<_> = vec![i64::MAX, i64::MAX, 3]
+            .into_iter()
+            .map(|tokens| Payment {
+                tokens,
+                to: KeyPair::new().pubkey(),
+            })
+            .collect();
+        assert!(
+            Transaction::new_multi_payment(&keypair, payments.clone(), 0, Hash::default())
+                .is_none()
+        );
+
+        // The payments wrap around to 1, which mustn't pass for spending 1 token.
+        let plan = Plan::new_multi_payment(payments);
+        let tr = Transaction::new_with_plan(&keypair, plan, 1, 0, Hash::default());
+        assert!(!tr.verify_plan());
+    }
+
+    #[test]
+    fn test_multi_payment_too_many() {
+        let keypair = KeyPair::new();
+        let payments: Vec<_> = (0..MAX_PAYMENTS + 1)
+            .map(|_| Payment {
+                tokens: 1,
+                to: KeyPair::new().pubkey(),
+            })
+            .collect();
+        assert!(
+            Transaction::new_multi_payment(&keypair, payments.clone(), 0, Hash::default())
+                .is_none()
+        );
+        assert!(
+            Transaction::new_multi_payment(&keypair, payments[1..].to_vec(), 0, Hash::default())
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn test_multi_payment_attack() {
+        let keypair = KeyPair::new();
+        let thief_keypair = KeyPair::new();
+        let payments = vec![
+            Payment {
+                tokens: 1,
+                to: KeyPair::new().pubkey(),
+            },
+            Payment {
+                tokens: 1,
+                to: KeyPair::new().pubkey(),
+            },
+        ];
+        let mut tr =
+            Transaction::new_multi_payment(&keypair, payments, 0, Hash::default()).unwrap();
+        if let Plan::PayMany(ref mut payments) = tr.data.plan {
+            payments[1].to = thief_keypair.pubkey(); // <-- attack!
+        }
+        assert!(tr.verify_plan());
+        assert!(!tr.verify_sig());
+    }
+
     #[test]
     fn test_overspend_attack() {
         let keypair0 = KeyPair::new();
@@ -222,6 +349,38 @@ mod tests {
         assert!(!tr.verify_plan());
     }
 
+    #[test]
+    fn test_fee() {
+        let keypair = KeyPair::new();
+        let zero = Hash::default();
+        let tr = Transaction::new_taxed(&keypair, keypair.pubkey(), 42, 2, zero);
+        assert!(tr.verify_plan());
+        if let Plan::Pay(ref payment) = tr.data.plan {
+            assert_eq!(payment.tokens, 40);
+        }
+    }
+
+    #[test]
+    fn test_fee_attack() {
+        let keypair = KeyPair::new();
+        let zero = Hash::default();
+        let mut tr = Transaction::new_taxed(&keypair, keypair.pubkey(), 42, 2, zero);
+        tr.data.fee = 0; // <-- attack, skip the fee but keep the payment!
+        assert!(!tr.verify_plan());
+
+        // A negative fee would let the sender mint tokens.
+        let mut tr = Transaction::new_taxed(&keypair, keypair.pubkey(), 42, 0, zero);
+        tr.data.fee = -1; // <-- attack!
+        if let Plan::Pay(ref mut payment) = tr.data.plan {
+            payment.tokens = 43;
+        }
+        assert!(!tr.verify_plan());
+
+        // The fee can't exceed the tokens being transferred.
+        let tr = Transaction::new_taxed(&keypair, keypair.pubkey(), 42, 43, zero);
+        assert!(!tr.verify_plan());
+    }
+
     #[test]
     fn test_verify_transactions() {
         let alice_keypair = KeyPair::new();